@@ -0,0 +1,362 @@
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{header, Body, Method, Request, Response, Server, StatusCode};
+use poise::serenity_prelude::{GuildId, Http, UserId};
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::commands::voice_commands::{resolve_input, set_paused, skip_track, stop_session};
+use crate::player::PlayerState;
+use crate::pot::{PlaylistItem, PotPlayInputType};
+use crate::now_playing;
+use crate::shutdown;
+
+/// Environment variable with the address the control API listens on, like 127.0.0.1:8080
+pub const API_ADDR_ENV: &str = "API_ADDR";
+
+/// Environment variable with the token clients send as `Authorization: Bearer <token>`
+pub const API_TOKEN_ENV: &str = "API_TOKEN";
+
+/// Comment sent on idle event streams so proxies do not close them
+const KEEPALIVE: Duration = Duration::from_secs(15);
+
+struct Api {
+    data: crate::Data,
+    http: Arc<Http>,
+    token: String,
+}
+
+struct ApiError {
+    status: StatusCode,
+    message: String,
+}
+
+impl ApiError {
+    fn new(status: StatusCode, message: impl Into<String>) -> Self {
+        Self { status, message: message.into() }
+    }
+}
+
+type ApiResult = Result<Response<Body>, ApiError>;
+
+#[derive(Serialize)]
+struct GuildStatus {
+    guild_id: u64,
+    connected: bool,
+    state: &'static str,
+    current: Option<PlaylistItem>,
+    queue: Vec<PlaylistItem>,
+}
+
+#[derive(Deserialize)]
+struct EnqueueRequest {
+    /// Anything /play accepts, an url, a search or library:<query>
+    song: String,
+}
+
+#[derive(Deserialize)]
+struct MoveRequest {
+    from: usize,
+    to: usize,
+}
+
+fn json(status: StatusCode, value: &impl Serialize) -> ApiResult {
+    let body = serde_json::to_vec(value).map_err(|err| ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+
+    let mut response = Response::new(Body::from(body));
+    *response.status_mut() = status;
+    response.headers_mut().insert(header::CONTENT_TYPE, header::HeaderValue::from_static("application/json"));
+    Ok(response)
+}
+
+fn message(message: impl Into<String>) -> ApiResult {
+    json(StatusCode::OK, &serde_json::json!({ "message": message.into() }))
+}
+
+/// Compare every byte so the time taken does not tell how much of the token was right
+fn same_token(given: &str, token: &str) -> bool {
+    given.len() == token.len() && given.bytes().zip(token.bytes()).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+/// Token of the request, from the Authorization header or the `token` query parameter that EventSource has to use
+fn request_token<'a>(authorization: Option<&'a str>, query: Option<&'a str>) -> Option<std::borrow::Cow<'a, str>> {
+    if let Some(token) = authorization.and_then(|value| value.strip_prefix("Bearer ")) {
+        return Some(token.trim().into());
+    }
+
+    url::form_urlencoded::parse(query?.as_bytes()).find(|(key, _)| key == "token").map(|(_, value)| value)
+}
+
+fn query_param(request: &Request<Body>, name: &str) -> Option<String> {
+    url::form_urlencoded::parse(request.uri().query()?.as_bytes()).find(|(key, _)| key == name).map(|(_, value)| value.into_owned())
+}
+
+fn parse_guild(id: &str) -> Result<GuildId, ApiError> {
+    id.parse().map(GuildId).map_err(|_| ApiError::new(StatusCode::BAD_REQUEST, "Invalid guild id"))
+}
+
+/// Positions start at 1 like in /queue and /remove
+fn position_index(position: usize) -> Result<usize, ApiError> {
+    position.checked_sub(1).ok_or_else(|| ApiError::new(StatusCode::BAD_REQUEST, "Positions start at 1"))
+}
+
+fn parse_position(position: &str) -> Result<usize, ApiError> {
+    position.parse().map_err(|_| ApiError::new(StatusCode::BAD_REQUEST, "Invalid position")).and_then(position_index)
+}
+
+async fn read_json<T: for<'de> Deserialize<'de>>(request: Request<Body>) -> Result<T, ApiError> {
+    let body = hyper::body::to_bytes(request.into_body()).await.map_err(|err| ApiError::new(StatusCode::BAD_REQUEST, err.to_string()))?;
+    serde_json::from_slice(&body).map_err(|err| ApiError::new(StatusCode::BAD_REQUEST, err.to_string()))
+}
+
+impl Api {
+    async fn status(&self, guild_id: GuildId) -> GuildStatus {
+        let mut playlist = self.data.system_playlist.write().await;
+        let queue = playlist.items(guild_id).to_vec();
+        let player = playlist.player(guild_id);
+
+        GuildStatus {
+            guild_id: guild_id.0,
+            connected: self.data.songbird.get(guild_id).is_some(),
+            state: player.state.name(),
+            current: player.current.clone(),
+            queue,
+        }
+    }
+
+    /// Guilds with the bot in a voice channel
+    async fn guilds(&self) -> ApiResult {
+        let guilds = self.data.system_playlist.read().await.guilds();
+
+        let mut statuses = Vec::new();
+        for guild_id in guilds {
+            if self.data.songbird.get(guild_id).is_some() {
+                statuses.push(self.status(guild_id).await);
+            }
+        }
+
+        json(StatusCode::OK, &statuses)
+    }
+
+    async fn enqueue(&self, guild_id: GuildId, request: EnqueueRequest) -> ApiResult {
+        // Queued items only play in a running session, starting one needs a member in a voice channel
+        if !self.data.system_playlist.read().await.is_playing(guild_id) {
+            return Err(ApiError::new(StatusCode::CONFLICT, "Nothing is playing, start the session from Discord"));
+        }
+
+        let items = resolve_input(&self.data, PotPlayInputType::parse(request.song), UserId(0)).await
+            .map_err(|err| ApiError::new(StatusCode::UNPROCESSABLE_ENTITY, err.to_string()))?;
+        let items = items.into_iter().map(|mut item| {
+            item.requester = None;
+            item.autoplay = false;
+            item
+        }).collect();
+
+        let settings = self.data.guild_settings.read().await.get(guild_id);
+        let mut playlist = self.data.system_playlist.write().await;
+        let outcome = playlist.add(guild_id, items, &settings);
+        now_playing::refresh(&self.http, &mut playlist, guild_id).await;
+
+        json(StatusCode::OK, &serde_json::json!({
+            "added": outcome.added,
+            "rejected": outcome.rejection_summary(),
+        }))
+    }
+
+    async fn remove(&self, guild_id: GuildId, index: usize) -> ApiResult {
+        let mut playlist = self.data.system_playlist.write().await;
+        let item = playlist.remove(guild_id, index).ok_or_else(|| ApiError::new(StatusCode::NOT_FOUND, format!("There is no song at position {}", index + 1)))?;
+        now_playing::refresh(&self.http, &mut playlist, guild_id).await;
+
+        message(format!("Removed {}", item.title))
+    }
+
+    async fn move_item(&self, guild_id: GuildId, request: MoveRequest) -> ApiResult {
+        let (from, to) = (position_index(request.from)?, position_index(request.to)?);
+
+        let mut playlist = self.data.system_playlist.write().await;
+        if !playlist.move_item(guild_id, from, to) {
+            return Err(ApiError::new(StatusCode::NOT_FOUND, "Position out of the queue"));
+        }
+        now_playing::refresh(&self.http, &mut playlist, guild_id).await;
+
+        message(format!("Moved song {} to {}", request.from, request.to))
+    }
+
+    async fn clear(&self, guild_id: GuildId) -> ApiResult {
+        let mut playlist = self.data.system_playlist.write().await;
+        playlist.clear(guild_id);
+        now_playing::refresh(&self.http, &mut playlist, guild_id).await;
+
+        message("Queue cleared")
+    }
+
+    async fn shuffle(&self, guild_id: GuildId) -> ApiResult {
+        let mut playlist = self.data.system_playlist.write().await;
+        let shuffled = playlist.shuffle(guild_id);
+        now_playing::refresh(&self.http, &mut playlist, guild_id).await;
+
+        message(format!("{} songs shuffled", shuffled))
+    }
+
+    async fn skip(&self, guild_id: GuildId) -> ApiResult {
+        let call_lock = self.data.songbird.get(guild_id).ok_or_else(|| ApiError::new(StatusCode::CONFLICT, "Not in a voice channel"))?;
+        let settings = self.data.guild_settings.read().await.get(guild_id);
+        let mut playlist = self.data.system_playlist.write().await;
+
+        if !matches!(playlist.state(guild_id), PlayerState::Playing | PlayerState::Paused) {
+            return Err(ApiError::new(StatusCode::CONFLICT, "Nothing to play"));
+        }
+        let player = playlist.player(guild_id);
+        let channel_id = player.text_channel.or(player.now_playing.map(|(channel_id, _)| channel_id))
+            .ok_or_else(|| ApiError::new(StatusCode::CONFLICT, "The session has no text channel"))?;
+
+        if skip_track(&self.http, &self.data.songbird, &call_lock, channel_id, &mut playlist, guild_id, &settings).await {
+            message("Song skipped")
+        } else {
+            message("Queue ended")
+        }
+    }
+
+    async fn pause(&self, guild_id: GuildId, pause: bool) -> ApiResult {
+        let mut playlist = self.data.system_playlist.write().await;
+        set_paused(&self.http, &mut playlist, guild_id, pause).await
+            .map_err(|err| ApiError::new(StatusCode::CONFLICT, err.to_string()))
+            .and_then(message)
+    }
+
+    async fn stop(&self, guild_id: GuildId) -> ApiResult {
+        if self.data.songbird.get(guild_id).is_none() {
+            return Err(ApiError::new(StatusCode::CONFLICT, "Not in a voice channel"));
+        }
+
+        stop_session(&self.http, &self.data, guild_id).await
+            .map_err(|err| ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+        message("Left voice channel")
+    }
+
+    /// Stream the now playing events as server sent events, of one guild with `?guild=<id>`
+    async fn events(&self, guild: Option<GuildId>) -> ApiResult {
+        let mut receiver = self.data.system_playlist.read().await.events.subscribe();
+        let (mut sender, body) = Body::channel();
+
+        tokio::spawn(async move {
+            loop {
+                let chunk = match tokio::time::timeout(KEEPALIVE, receiver.recv()).await {
+                    Ok(Ok(event)) => {
+                        if guild.is_some_and(|guild| guild.0 != event.guild_id()) {
+                            continue;
+                        }
+                        match serde_json::to_string(&event) {
+                            Ok(event) => format!("data: {}\n\n", event),
+                            Err(_) => continue,
+                        }
+                    },
+                    Ok(Err(RecvError::Lagged(missed))) => format!(": {} events missed\n\n", missed),
+                    Ok(Err(RecvError::Closed)) => break,
+                    Err(_) => ": keepalive\n\n".to_string(),
+                };
+
+                // The client went away
+                if sender.send_data(chunk.into()).await.is_err() {
+                    break;
+                }
+            }
+        });
+
+        let mut response = Response::new(body);
+        response.headers_mut().insert(header::CONTENT_TYPE, header::HeaderValue::from_static("text/event-stream"));
+        response.headers_mut().insert(header::CACHE_CONTROL, header::HeaderValue::from_static("no-cache"));
+        Ok(response)
+    }
+
+    async fn route(&self, request: Request<Body>) -> ApiResult {
+        let authorization = request.headers().get(header::AUTHORIZATION).and_then(|value| value.to_str().ok());
+        match request_token(authorization, request.uri().query()) {
+            Some(token) if same_token(&token, &self.token) => (),
+            _ => return Err(ApiError::new(StatusCode::UNAUTHORIZED, "Missing or wrong token")),
+        }
+        if shutdown::is_shutting_down() {
+            return Err(ApiError::new(StatusCode::SERVICE_UNAVAILABLE, "The bot is shutting down"));
+        }
+
+        let path = request.uri().path().to_owned();
+        let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+
+        match (request.method().clone(), segments.as_slice()) {
+            (Method::GET, ["guilds"]) => self.guilds().await,
+            (Method::GET, ["guilds", id]) => json(StatusCode::OK, &self.status(parse_guild(id)?).await),
+            (Method::POST, ["guilds", id, "queue"]) => self.enqueue(parse_guild(id)?, read_json(request).await?).await,
+            (Method::DELETE, ["guilds", id, "queue"]) => self.clear(parse_guild(id)?).await,
+            (Method::POST, ["guilds", id, "queue", "move"]) => self.move_item(parse_guild(id)?, read_json(request).await?).await,
+            (Method::POST, ["guilds", id, "queue", "shuffle"]) => self.shuffle(parse_guild(id)?).await,
+            (Method::DELETE, ["guilds", id, "queue", position]) => self.remove(parse_guild(id)?, parse_position(position)?).await,
+            (Method::POST, ["guilds", id, "skip"]) => self.skip(parse_guild(id)?).await,
+            (Method::POST, ["guilds", id, "pause"]) => self.pause(parse_guild(id)?, true).await,
+            (Method::POST, ["guilds", id, "resume"]) => self.pause(parse_guild(id)?, false).await,
+            (Method::POST, ["guilds", id, "stop"]) => self.stop(parse_guild(id)?).await,
+            (Method::GET, ["events"]) => {
+                let guild = query_param(&request, "guild").map(|id| parse_guild(&id)).transpose()?;
+                self.events(guild).await
+            },
+            _ => Err(ApiError::new(StatusCode::NOT_FOUND, "Not found")),
+        }
+    }
+}
+
+async fn handle(request: Request<Body>, api: Arc<Api>) -> Result<Response<Body>, Infallible> {
+    let method = request.method().clone();
+    let path = request.uri().path().to_owned();
+
+    match api.route(request).await {
+        Ok(response) => Ok(response),
+        Err(err) => {
+            tracing::debug!(%method, %path, status = err.status.as_u16(), "API request failed: {}", err.message);
+            let body = serde_json::json!({ "error": err.message }).to_string();
+            let mut response = Response::new(Body::from(body));
+            *response.status_mut() = err.status;
+            response.headers_mut().insert(header::CONTENT_TYPE, header::HeaderValue::from_static("application/json"));
+            Ok(response)
+        },
+    }
+}
+
+/// Serve the control API until the bot stops, it acts on the same playlist and calls as the slash commands
+pub async fn serve(addr: SocketAddr, token: String, data: crate::Data, http: Arc<Http>) -> hyper::Result<()> {
+    let api = Arc::new(Api { data, http, token });
+
+    let make_service = make_service_fn(move |_connection| {
+        let api = api.clone();
+
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request| handle(request, api.clone())))
+        }
+    });
+
+    Server::try_bind(&addr)?.serve(make_service).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tokens_come_from_the_header_or_the_query() {
+        assert_eq!(request_token(Some("Bearer secret"), None).as_deref(), Some("secret"));
+        assert_eq!(request_token(None, Some("guild=1&token=secret")).as_deref(), Some("secret"));
+        assert_eq!(request_token(Some("Basic secret"), None), None);
+        assert_eq!(request_token(None, Some("guild=1")), None);
+    }
+
+    #[test]
+    fn tokens_must_match_exactly() {
+        assert!(same_token("secret", "secret"));
+        assert!(!same_token("secreT", "secret"));
+        assert!(!same_token("secret2", "secret"));
+        assert!(!same_token("", "secret"));
+    }
+}
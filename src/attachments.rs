@@ -0,0 +1,127 @@
+use std::fs;
+
+use poise::serenity_prelude::Attachment;
+use tokio::task;
+
+use crate::cache;
+use crate::helpers;
+use crate::library;
+use crate::metrics;
+use crate::pot::PlaylistItem;
+
+/// Extractor of the items that come from files attached to Discord messages
+pub const ATTACHMENT_EXTRACTOR: &str = "attachment";
+
+/// Largest file that is downloaded, the limit for uploads without boosts
+pub const MAX_ATTACHMENT_SIZE: u64 = 25 * 1024 * 1024;
+
+const AUDIO_EXTENSIONS: [&str; 8] = ["mp3", "ogg", "oga", "opus", "flac", "wav", "m4a", "aac"];
+
+#[derive(Debug)]
+pub enum AttachmentError {
+    NotAudio(String),
+    TooBig(String),
+    Download(String),
+}
+
+impl std::fmt::Display for AttachmentError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            AttachmentError::NotAudio(name) => write!(f, "{} is not an audio file", name),
+            AttachmentError::TooBig(name) => write!(f, "{} is bigger than {}MB", name, MAX_ATTACHMENT_SIZE / 1024 / 1024),
+            AttachmentError::Download(name) => write!(f, "Cannot download {}", name),
+        }
+    }
+}
+
+impl std::error::Error for AttachmentError {}
+
+/// Discord sets the content type from the extension, files without one are checked by their name
+fn is_audio(content_type: Option<&str>, filename: &str) -> bool {
+    match content_type {
+        Some(content_type) => content_type.starts_with("audio/") || content_type == "application/ogg",
+        None => filename.rsplit_once('.')
+            .map(|(_, extension)| AUDIO_EXTENSIONS.contains(&extension.to_lowercase().as_str()))
+            .unwrap_or(false),
+    }
+}
+
+/// Check the attachment, download it to the media cache and build its item
+///
+/// The duration and tags are read with ffprobe, the title falls back to the file name
+pub async fn download(attachment: &Attachment, requester: u64) -> Result<PlaylistItem, AttachmentError> {
+    if !is_audio(attachment.content_type.as_deref(), &attachment.filename) {
+        return Err(AttachmentError::NotAudio(attachment.filename.to_owned()));
+    }
+    if attachment.size > MAX_ATTACHMENT_SIZE {
+        return Err(AttachmentError::TooBig(attachment.filename.to_owned()));
+    }
+
+    let mut item = PlaylistItem {
+        id: attachment.id.to_string(),
+        title: attachment.filename.to_owned(),
+        original_url: attachment.url.to_owned(),
+        extractor: ATTACHMENT_EXTRACTOR.to_string(),
+        thumbnail: None,
+        duration: None,
+        playlist_id: None,
+        webpage_url: None,
+        is_live: Some(false),
+        was_live: None,
+        requester: Some(requester),
+        autoplay: false,
+    };
+
+    let path = cache::media_path(&item);
+    if !path.is_file() {
+        let bytes = attachment.download().await.map_err(|err| {
+            tracing::warn!(attachment = %attachment.filename, "Cannot download the attachment: {:?}", err);
+            AttachmentError::Download(attachment.filename.to_owned())
+        })?;
+
+        let target = path.clone();
+        let written = task::spawn_blocking(move || {
+            if let Some(dir) = target.parent() {
+                fs::create_dir_all(dir)?;
+            }
+            let len = bytes.len() as u64;
+            helpers::write_atomic(&target, &bytes).map(|_| metrics::CACHE_BYTES.inc_by(len))
+        }).await;
+
+        if !matches!(written, Ok(Ok(_))) {
+            tracing::error!(path = %path.display(), "Cannot write the attachment: {:?}", written);
+            return Err(AttachmentError::Download(attachment.filename.to_owned()));
+        }
+    }
+
+    let probed = task::spawn_blocking({
+        let path = path.clone();
+        move || library::probe(&path)
+    }).await.map_err(|_| AttachmentError::Download(attachment.filename.to_owned()))?;
+    // ffprobe is optional, the attachment still plays without tags and duration
+    let entry = probed.unwrap_or_else(|err| {
+        tracing::warn!(attachment = %attachment.filename, "Cannot read the tags: {:?}", err);
+        library::untagged(&path)
+    });
+    // Untagged files keep their name, the cached copy is named after the attachment id
+    if entry.title != item.id {
+        item.title = entry.display_title();
+    }
+    item.duration = entry.duration;
+
+    Ok(item)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn audio_is_detected_by_content_type_or_name() {
+        assert!(is_audio(Some("audio/mpeg"), "song.mp3"));
+        assert!(is_audio(Some("application/ogg"), "song.ogg"));
+        assert!(!is_audio(Some("image/png"), "cover.mp3"));
+        assert!(is_audio(None, "Song.FLAC"));
+        assert!(!is_audio(None, "notes.txt"));
+    }
+}
@@ -0,0 +1,77 @@
+use std::collections::HashSet;
+
+use poise::serenity_prelude::GuildId;
+use rand::seq::SliceRandom;
+
+use crate::history::PlayOutcome;
+use crate::pot::{PlaylistItem, SystemPlaylist};
+use crate::settings::GuildSettings;
+
+/// Last played items autoplay does not pick again
+const RECENT_LIMIT: usize = 50;
+
+/// Videos read from the mix of the last song
+const MIX_LENGTH: usize = 25;
+
+/// First candidate that was not played recently and that the guild admits
+fn pick<'a>(candidates: impl IntoIterator<Item = &'a PlaylistItem>, recent: &HashSet<String>, settings: &GuildSettings) -> Option<&'a PlaylistItem> {
+    candidates.into_iter().find(|item| !recent.contains(&item.key()) && settings.admission.check(item).is_ok())
+}
+
+/// Pick a song to keep the session going when the queue runs out
+///
+/// Songs of the YouTube mix of the last song come first, then songs of the guild history that were played to the end
+///
+/// The last song is the one playing when asked ahead of time, like by a crossfade
+pub async fn next_item(playlist: &mut SystemPlaylist, guild_id: GuildId, settings: &GuildSettings) -> Option<PlaylistItem> {
+    let current = playlist.player(guild_id).current.clone();
+    let queued: Vec<String> = playlist.items(guild_id).iter().chain(current.as_ref()).map(PlaylistItem::key).collect();
+    let entries = playlist.history.entries(guild_id);
+    let seed = match current {
+        Some(current) => current,
+        None => entries.last()?.item.clone(),
+    };
+
+    let recent: HashSet<String> = entries.iter().rev().take(RECENT_LIMIT).map(|entry| entry.item.key()).chain(queued).collect();
+
+    let mut finished: Vec<PlaylistItem> = entries.iter()
+        .filter(|entry| entry.outcome == PlayOutcome::Finished)
+        .map(|entry| entry.item.clone())
+        .collect();
+    finished.shuffle(&mut rand::thread_rng());
+
+    let mix = if seed.extractor.eq_ignore_ascii_case("youtube") {
+        SystemPlaylist::youtube_mix(&seed.id, MIX_LENGTH).await.unwrap_or_else(|err| {
+            tracing::warn!(video_id = %seed.id, "Cannot get the mix: {:?}", err);
+            Vec::new()
+        })
+    } else {
+        Vec::new()
+    };
+
+    let mut item = pick(mix.iter().chain(finished.iter()), &recent, settings)?.clone();
+    item.requester = None;
+    item.autoplay = true;
+
+    Some(item)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn item(id: &str, title: &str) -> PlaylistItem {
+        PlaylistItem::for_test("youtube", id, title)
+    }
+
+    #[test]
+    fn recent_and_blocked_items_are_not_picked() {
+        let candidates = vec![item("a", "played"), item("b", "blocked song"), item("c", "new")];
+        let recent: HashSet<String> = ["youtube:a".to_string()].into();
+        let mut settings = GuildSettings::default();
+        settings.admission.blocked_keywords.push("blocked".to_string());
+
+        assert_eq!(pick(&candidates, &recent, &settings).map(|item| item.id.as_str()), Some("c"));
+        assert!(pick(&candidates[..2], &recent, &settings).is_none());
+    }
+}
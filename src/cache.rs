@@ -0,0 +1,283 @@
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{LazyLock, Mutex};
+use std::time::{Duration, SystemTime};
+
+use tokio::task;
+
+use crate::helpers;
+use crate::layout::layout;
+use crate::library::{self, LOCAL_EXTRACTOR};
+use crate::loudness::{self, LoudnessMeasurement};
+use crate::metrics;
+use crate::pot::{PlaylistItem, SystemPlaylist};
+
+/// Environment variable that turns on downloading played songs to the cache, `on` or `off` (default)
+pub const MEDIA_CACHE_ENV: &str = "MEDIA_CACHE";
+
+static ENABLED: LazyLock<bool> = LazyLock::new(|| env::var(MEDIA_CACHE_ENV).map(|value| value == "on").unwrap_or(false));
+
+/// Items being downloaded or measured, so the same item is not processed twice at once
+static IN_PROGRESS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+/// What is known about a cached media file, stored as meta/{extractor}/{id}.json in the cache directory
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MediaMeta {
+    pub item: PlaylistItem,
+    #[serde(default)]
+    pub loudness: Option<LoudnessMeasurement>,
+}
+
+/// Ids can be urls, keep only what is safe in a file name
+fn file_name(id: &str) -> String {
+    id.chars().map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '_' { c } else { '_' }).collect()
+}
+
+pub fn media_path(item: &PlaylistItem) -> PathBuf {
+    layout().media().join(file_name(&item.extractor)).join(file_name(&item.id))
+}
+
+fn meta_path(item: &PlaylistItem) -> PathBuf {
+    layout().meta().join(file_name(&item.extractor)).join(format!("{}.json", file_name(&item.id)))
+}
+
+/// Path of the cached media of the item, None if it was not downloaded yet
+///
+/// Library items are never downloaded, their path is the file in the library
+pub fn cached_media(item: &PlaylistItem) -> Option<PathBuf> {
+    let path = if item.extractor == LOCAL_EXTRACTOR {
+        PathBuf::from(&item.original_url)
+    } else {
+        media_path(item)
+    };
+    match fs::metadata(&path) {
+        Ok(attributes) if attributes.is_file() => Some(path),
+        _ => None,
+    }
+}
+
+pub fn load_meta(item: &PlaylistItem) -> Option<MediaMeta> {
+    let json = fs::read_to_string(meta_path(item)).ok()?;
+    serde_json::from_str(&json).ok()
+}
+
+fn save_meta(meta: &MediaMeta) -> std::io::Result<()> {
+    let path = meta_path(&meta.item);
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+
+    let json = serde_json::to_string_pretty(meta)?;
+    helpers::write_json(&path, json)
+}
+
+/// Mark the item as in progress, false if it already was
+fn start(key: &str) -> bool {
+    let mut in_progress = IN_PROGRESS.lock().unwrap_or_else(|err| err.into_inner());
+    if in_progress.iter().any(|existing| existing == key) {
+        return false;
+    }
+    in_progress.push(key.to_string());
+    true
+}
+
+fn finish(key: &str) {
+    IN_PROGRESS.lock().unwrap_or_else(|err| err.into_inner()).retain(|existing| existing != key);
+}
+
+/// Measure the loudness of the cached media and save it with the item
+fn measure(item: PlaylistItem, path: PathBuf) {
+    let loudness = match loudness::measure(&path.to_string_lossy()) {
+        Ok(loudness) => Some(loudness),
+        Err(err) => {
+            tracing::warn!(path = %path.display(), "Cannot measure the loudness: {:?}", err);
+            None
+        },
+    };
+
+    if let Err(err) = save_meta(&MediaMeta { item, loudness }) {
+        tracing::error!(path = %path.display(), "Cannot save the metadata: {}", err);
+    }
+}
+
+/// Played songs are only downloaded when the operator turned it on, the first play fetches the audio a second time
+pub fn is_enabled() -> bool {
+    *ENABLED
+}
+
+/// Download the item and measure its loudness without waiting for it, later plays use the cached file
+pub fn store_in_background(item: &PlaylistItem) {
+    // Items of unknown length may be streams that never end
+    if !is_enabled() || item.extractor == LOCAL_EXTRACTOR || item.is_live() || item.duration.is_none() {
+        return;
+    }
+
+    let key = media_path(item).to_string_lossy().into_owned();
+    if !start(&key) {
+        return;
+    }
+
+    let item = item.clone();
+    task::spawn_blocking(move || {
+        let path = media_path(&item);
+        if let Err(err) = SystemPlaylist::ytdlp_download(&path, &item.original_url) {
+            tracing::warn!(track = %item.title, "Cannot download: {:?}", err);
+        }
+
+        if let Ok(attributes) = fs::metadata(&path) {
+            metrics::CACHE_BYTES.inc_by(attributes.len());
+        }
+        if path.is_file() {
+            measure(item, path);
+        }
+        finish(&key);
+    });
+}
+
+/// Measure a cached file that has no measurement yet, like files cached before normalization existed
+pub fn measure_in_background(item: &PlaylistItem) {
+    let path = match cached_media(item) {
+        Some(path) => path,
+        None => return,
+    };
+    let key = path.to_string_lossy().into_owned();
+    if !start(&key) {
+        return;
+    }
+
+    let item = item.clone();
+    task::spawn_blocking(move || {
+        measure(item, path);
+        finish(&key);
+    });
+}
+
+/// A file of the media cache, as seen by the `cache` subcommands
+pub struct CachedFile {
+    pub media: PathBuf,
+    pub meta: PathBuf,
+    pub size: u64,
+    /// Last time the file was written or read, as far as the file system tracks reads
+    pub last_used: SystemTime,
+}
+
+impl CachedFile {
+    /// yt-dlp and interrupted writes leave these behind
+    pub fn is_partial(&self) -> bool {
+        matches!(self.media.extension().and_then(|extension| extension.to_str()), Some("part" | "ytdl" | helpers::TEMP_EXTENSION))
+    }
+
+    /// The metadata parses and ffprobe can read the media
+    pub fn verify(&self) -> Result<(), String> {
+        if self.is_partial() {
+            return Err("unfinished download".into());
+        }
+        if library::probe(&self.media).map_err(|err| err.to_string())?.duration.is_none() {
+            return Err("ffprobe cannot read the media".into());
+        }
+        match fs::read_to_string(&self.meta) {
+            Ok(json) => serde_json::from_str::<MediaMeta>(&json).map(|_| ()).map_err(|err| format!("invalid metadata, {}", err)),
+            Err(_) => Ok(()),
+        }
+    }
+
+    /// Remove the media and its metadata
+    pub fn remove(&self) -> std::io::Result<()> {
+        fs::remove_file(&self.media)?;
+        match fs::remove_file(&self.meta) {
+            Err(err) if err.kind() != std::io::ErrorKind::NotFound => Err(err),
+            _ => Ok(()),
+        }
+    }
+}
+
+fn files_in(dir: &Path) -> Vec<PathBuf> {
+    let mut files = Vec::new();
+    for extractor in fs::read_dir(dir).into_iter().flatten().flatten() {
+        for entry in fs::read_dir(extractor.path()).into_iter().flatten().flatten() {
+            if entry.path().is_file() {
+                files.push(entry.path());
+            }
+        }
+    }
+    files.sort();
+    files
+}
+
+/// Every file of the media cache
+pub fn cached_files() -> Vec<CachedFile> {
+    files_in(&layout().media()).into_iter().filter_map(|media| {
+        let attributes = fs::metadata(&media).ok()?;
+        let modified = attributes.modified().ok()?;
+        let extractor = media.parent()?.file_name()?;
+        let meta = layout().meta().join(extractor).join(format!("{}.json", media.file_name()?.to_string_lossy()));
+
+        Some(CachedFile {
+            size: attributes.len(),
+            last_used: attributes.accessed().map(|accessed| accessed.max(modified)).unwrap_or(modified),
+            media,
+            meta,
+        })
+    }).collect()
+}
+
+/// Metadata files whose media is not in the cache anymore
+pub fn orphan_meta(files: &[CachedFile]) -> Vec<PathBuf> {
+    files_in(&layout().meta()).into_iter()
+        .filter(|meta| !files.iter().any(|file| &file.meta == meta))
+        .collect()
+}
+
+/// Indexes of the files to remove, unused for longer than `max_age` and then the least recently used until the cache fits in `max_bytes`
+pub fn prune_selection(files: &[CachedFile], now: SystemTime, max_age: Option<Duration>, max_bytes: Option<u64>) -> Vec<usize> {
+    let mut by_age: Vec<usize> = (0..files.len()).collect();
+    by_age.sort_by_key(|&index| files[index].last_used);
+
+    let mut selected = Vec::new();
+    let mut total: u64 = files.iter().map(|file| file.size).sum();
+    for index in by_age {
+        let file = &files[index];
+        let too_old = max_age.is_some_and(|max_age| now.duration_since(file.last_used).unwrap_or_default() > max_age);
+        let too_big = max_bytes.is_some_and(|max_bytes| total > max_bytes);
+
+        if too_old || too_big {
+            total -= file.size;
+            selected.push(index);
+        }
+    }
+
+    selected
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file(name: &str, size: u64, days_ago: u64) -> CachedFile {
+        CachedFile {
+            media: PathBuf::from(name),
+            meta: PathBuf::from(format!("{}.json", name)),
+            size,
+            last_used: SystemTime::UNIX_EPOCH + Duration::from_secs((100 - days_ago) * 86400),
+        }
+    }
+
+    #[test]
+    fn prune_removes_old_files_then_least_recently_used() {
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(100 * 86400);
+        let files = vec![file("new", 10, 1), file("old", 10, 40), file("mid", 10, 10)];
+
+        assert_eq!(prune_selection(&files, now, Some(Duration::from_secs(30 * 86400)), None), [1]);
+        assert_eq!(prune_selection(&files, now, None, Some(15)), [1, 2]);
+        assert_eq!(prune_selection(&files, now, Some(Duration::from_secs(5 * 86400)), Some(100)), [1, 2]);
+        assert!(prune_selection(&files, now, None, None).is_empty());
+    }
+
+    #[test]
+    fn url_ids_are_safe_file_names() {
+        assert_eq!(file_name("https://radio.example.com/a b"), "https___radio_example_com_a_b");
+        assert_eq!(file_name("dQw4w9WgXcQ"), "dQw4w9WgXcQ");
+    }
+}
@@ -0,0 +1,361 @@
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+use clap::{Parser, Subcommand};
+use poise::serenity_prelude::UserId;
+use serde::de::DeserializeOwned;
+
+use crate::cache;
+use crate::dependencies;
+use crate::history::HistoryEntry;
+use crate::layout::{self, layout};
+use crate::library::{Library, LIBRARY_DIR_ENV};
+use crate::logging;
+use crate::metrics;
+use crate::pot::{PotPlayInputType, SystemPlaylist};
+use crate::saved_playlists::SavedPlaylist;
+use crate::settings::GuildSettings;
+use crate::shutdown::SavedQueue;
+
+#[derive(Parser)]
+#[command(about = "A private discord music bot, runs the bot when no command is given")]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<CliCommand>,
+}
+
+#[derive(Subcommand)]
+pub enum CliCommand {
+    /// Check that yt-dlp and ffmpeg are installed, the data directories and the configuration
+    Check,
+    /// Manage the media cache
+    Cache {
+        #[command(subcommand)]
+        command: CacheCommand,
+    },
+    /// Print the items /play would queue for a url or a search, as JSON
+    Resolve {
+        song: String,
+    },
+    /// Print the settings, history, saved playlists and saved queue of every guild as JSON
+    ExportQueues,
+}
+
+#[derive(Subcommand)]
+pub enum CacheCommand {
+    /// Size of the cache and files without metadata
+    Stats,
+    /// Remove files that were not used for a while or until the cache fits in a size
+    Prune {
+        /// Remove files not played for this many days
+        #[arg(long)]
+        older_than: Option<u64>,
+        /// Remove the least recently played files until the cache is smaller than this many MB
+        #[arg(long)]
+        max_size: Option<u64>,
+        /// Only list the files that would be removed
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Read every cached file with ffprobe and list the broken ones
+    Verify {
+        /// Remove the broken files
+        #[arg(long)]
+        fix: bool,
+    },
+}
+
+/// Run the command and return the exit code of the process
+pub async fn run(command: CliCommand) -> i32 {
+    match command {
+        CliCommand::Check => check(),
+        CliCommand::Cache { command: CacheCommand::Stats } => cache_stats(),
+        CliCommand::Cache { command: CacheCommand::Prune { older_than, max_size, dry_run } } => cache_prune(older_than, max_size, dry_run),
+        CliCommand::Cache { command: CacheCommand::Verify { fix } } => cache_verify(fix),
+        CliCommand::Resolve { song } => resolve(song).await,
+        CliCommand::ExportQueues => export_queues(),
+    }
+}
+
+fn format_size(bytes: u64) -> String {
+    format!("{:.1} MB", bytes as f64 / 1_000_000.0)
+}
+
+/// Guild state files of a directory, named {guild_id}.json
+fn guild_files(dir: &Path) -> Vec<(u64, std::path::PathBuf)> {
+    let mut files: Vec<(u64, std::path::PathBuf)> = fs::read_dir(dir).into_iter().flatten().flatten()
+        .filter_map(|entry| {
+            let path = entry.path();
+            let guild_id = path.file_stem()?.to_str()?.parse().ok()?;
+            (path.extension()? == "json").then_some((guild_id, path))
+        })
+        .collect();
+    files.sort();
+    files
+}
+
+/// Problems of the guild state files of a directory, parsed as the bot would
+fn check_guild_files<T: DeserializeOwned>(dir: &Path) -> Vec<String> {
+    guild_files(dir).into_iter().filter_map(|(_, path)| {
+        let json = match fs::read_to_string(&path) {
+            Ok(json) => json,
+            Err(err) => return Some(format!("{}: {}", path.display(), err)),
+        };
+        serde_json::from_str::<T>(&json).err().map(|err| format!("{}: {}", path.display(), err))
+    }).collect()
+}
+
+fn check() -> i32 {
+    let mut problems = Vec::new();
+
+    println!("Programs");
+    for report in dependencies::probe() {
+        println!("  {}", report);
+        if !report.is_usable() {
+            problems.push(if report.required {
+                format!("{} is required", report)
+            } else {
+                format!("{}, local files and attachments will not have tags and durations", report)
+            });
+        }
+    }
+
+    println!("Data directories");
+    for dir in layout().directories() {
+        let status = match fs::metadata(&dir) {
+            Ok(attributes) if !attributes.is_dir() => "not a directory",
+            Ok(attributes) if attributes.permissions().readonly() => "not writable",
+            Ok(_) => "OK",
+            // Created when the bot starts
+            Err(_) => "missing",
+        };
+        println!("  {}: {}", dir.display(), status);
+        if status != "OK" && status != "missing" {
+            problems.push(format!("{} is {}", dir.display(), status));
+        }
+    }
+
+    println!("Configuration");
+    println!("  DISCORD_TOKEN: {}", if option_env!("DISCORD_TOKEN").is_some() { "built in" } else { "missing" });
+    println!("  YOUTUBE_TOKEN: {}", if option_env!("YOUTUBE_TOKEN").is_some() { "built in" } else { "missing" });
+    if option_env!("DISCORD_TOKEN").is_none() || option_env!("YOUTUBE_TOKEN").is_none() {
+        problems.push("the binary was built without DISCORD_TOKEN or YOUTUBE_TOKEN".to_string());
+    }
+
+    for name in [layout::DATA_DIR_ENV, layout::CACHE_DIR_ENV] {
+        if let Ok(dir) = env::var(name) {
+            println!("  {}: {}", name, dir);
+        }
+    }
+    if let Ok(mode) = env::var(cache::MEDIA_CACHE_ENV) {
+        println!("  {}: {}", cache::MEDIA_CACHE_ENV, mode);
+        if mode != "on" && mode != "off" {
+            problems.push(format!("{} {} is not on or off", cache::MEDIA_CACHE_ENV, mode));
+        }
+    }
+    if let Ok(dir) = env::var(LIBRARY_DIR_ENV) {
+        println!("  {}: {}", LIBRARY_DIR_ENV, dir);
+        if !Path::new(&dir).is_dir() {
+            problems.push(format!("{} {} is not a directory", LIBRARY_DIR_ENV, dir));
+        }
+    }
+    if let Ok(addr) = env::var(metrics::METRICS_ADDR_ENV) {
+        println!("  {}: {}", metrics::METRICS_ADDR_ENV, addr);
+        if addr.parse::<std::net::SocketAddr>().is_err() {
+            problems.push(format!("{} {} is not an address like 127.0.0.1:9100", metrics::METRICS_ADDR_ENV, addr));
+        }
+    }
+    if let Ok(filter) = env::var(logging::LOG_LEVEL_ENV) {
+        println!("  {}: {}", logging::LOG_LEVEL_ENV, filter);
+        if let Err(err) = tracing_subscriber::EnvFilter::try_new(&filter) {
+            problems.push(format!("{} {} is not a valid filter, {}", logging::LOG_LEVEL_ENV, filter, err));
+        }
+    }
+    if let Ok(mode) = env::var(dependencies::DEPENDENCY_CHECK_ENV) {
+        println!("  {}: {}", dependencies::DEPENDENCY_CHECK_ENV, mode);
+        if mode != "strict" && mode != "degrade" {
+            problems.push(format!("{} {} is not strict or degrade", dependencies::DEPENDENCY_CHECK_ENV, mode));
+        }
+    }
+    if let Ok(format) = env::var(logging::LOG_FORMAT_ENV) {
+        println!("  {}: {}", logging::LOG_FORMAT_ENV, format);
+        if format != "json" && format != "text" {
+            problems.push(format!("{} {} is not json or text", logging::LOG_FORMAT_ENV, format));
+        }
+    }
+    #[cfg(feature = "http-api")]
+    if let Ok(addr) = env::var(crate::api::API_ADDR_ENV) {
+        println!("  {}: {}", crate::api::API_ADDR_ENV, addr);
+        if addr.parse::<std::net::SocketAddr>().is_err() {
+            problems.push(format!("{} {} is not an address like 127.0.0.1:8080", crate::api::API_ADDR_ENV, addr));
+        }
+        if env::var(crate::api::API_TOKEN_ENV).map(|token| token.is_empty()).unwrap_or(true) {
+            problems.push(format!("{} is set without {}", crate::api::API_ADDR_ENV, crate::api::API_TOKEN_ENV));
+        }
+    }
+
+    problems.append(&mut check_guild_files::<GuildSettings>(&layout().settings()));
+    problems.append(&mut check_guild_files::<Vec<HistoryEntry>>(&layout().history()));
+    problems.append(&mut check_guild_files::<Vec<SavedPlaylist>>(&layout().playlists()));
+    problems.append(&mut check_guild_files::<SavedQueue>(&layout().queues()));
+
+    if problems.is_empty() {
+        println!("Everything is ready");
+        0
+    } else {
+        println!("Problems");
+        for problem in &problems {
+            println!("  {}", problem);
+        }
+        1
+    }
+}
+
+fn cache_stats() -> i32 {
+    let files = cache::cached_files();
+
+    let mut extractors: BTreeMap<String, (usize, u64)> = BTreeMap::new();
+    for file in &files {
+        let extractor = file.media.parent().and_then(|dir| dir.file_name()).map(|name| name.to_string_lossy().into_owned()).unwrap_or_default();
+        let (count, size) = extractors.entry(extractor).or_default();
+        *count += 1;
+        *size += file.size;
+    }
+
+    for (extractor, (count, size)) in &extractors {
+        println!("{}: {} files, {}", extractor, count, format_size(*size));
+    }
+    println!("Total: {} files, {}", files.len(), format_size(files.iter().map(|file| file.size).sum()));
+    println!("Without metadata: {}", files.iter().filter(|file| !file.meta.is_file()).count());
+    println!("Unfinished downloads: {}", files.iter().filter(|file| file.is_partial()).count());
+    println!("Metadata without media: {}", cache::orphan_meta(&files).len());
+
+    0
+}
+
+fn cache_prune(older_than: Option<u64>, max_size: Option<u64>, dry_run: bool) -> i32 {
+    if older_than.is_none() && max_size.is_none() {
+        println!("Nothing to do, use --older-than <days> or --max-size <MB>");
+        return 2;
+    }
+
+    let files = cache::cached_files();
+    let max_age = older_than.map(|days| Duration::from_secs(days * 24 * 60 * 60));
+    let max_bytes = max_size.map(|megabytes| megabytes * 1_000_000);
+    let selected = cache::prune_selection(&files, SystemTime::now(), max_age, max_bytes);
+
+    let mut freed = 0;
+    let mut failed = false;
+    for file in selected.into_iter().map(|index| &files[index]) {
+        if dry_run {
+            println!("Would remove {} ({})", file.media.display(), format_size(file.size));
+        } else if let Err(err) = file.remove() {
+            println!("Cannot remove {}: {}", file.media.display(), err);
+            failed = true;
+            continue;
+        } else {
+            println!("Removed {} ({})", file.media.display(), format_size(file.size));
+        }
+        freed += file.size;
+    }
+
+    // Metadata of media removed by hand is of no use
+    for meta in cache::orphan_meta(&files) {
+        if dry_run {
+            println!("Would remove {}", meta.display());
+        } else if let Err(err) = fs::remove_file(&meta) {
+            println!("Cannot remove {}: {}", meta.display(), err);
+            failed = true;
+        }
+    }
+
+    println!("{} {}", if dry_run { "Would free" } else { "Freed" }, format_size(freed));
+    if failed { 1 } else { 0 }
+}
+
+fn cache_verify(fix: bool) -> i32 {
+    // Every file would look broken, and --fix would empty the cache
+    if let Some(report) = dependencies::probe().into_iter().find(|report| report.name == dependencies::FFPROBE && !report.is_usable()) {
+        println!("Cannot verify the cache, {}", report);
+        return 1;
+    }
+
+    let files = cache::cached_files();
+
+    let mut broken = 0;
+    for file in &files {
+        if let Err(reason) = file.verify() {
+            broken += 1;
+            println!("{}: {}", file.media.display(), reason);
+
+            if fix {
+                if let Err(err) = file.remove() {
+                    println!("Cannot remove {}: {}", file.media.display(), err);
+                }
+            }
+        }
+    }
+
+    println!("{} of {} files are broken", broken, files.len());
+    if broken > 0 && !fix { 1 } else { 0 }
+}
+
+async fn resolve(song: String) -> i32 {
+    let items = match PotPlayInputType::parse(song) {
+        PotPlayInputType::Library(query) => Library::load().resolve(&query, 0),
+        input => SystemPlaylist::resolve(input, UserId(0)).await,
+    };
+
+    match items {
+        Ok(mut items) => {
+            for item in items.iter_mut() {
+                item.requester = None;
+            }
+            match serde_json::to_string_pretty(&items) {
+                Ok(json) => println!("{}", json),
+                Err(err) => {
+                    println!("{}", err);
+                    return 1;
+                },
+            }
+            0
+        },
+        Err(err) => {
+            println!("Cannot resolve: {:?}", err);
+            1
+        },
+    }
+}
+
+/// Everything that survives a restart, queues are the ones saved when the bot was last stopped
+fn export_queues() -> i32 {
+    let mut guilds: BTreeMap<u64, serde_json::Map<String, serde_json::Value>> = BTreeMap::new();
+    let mut failed = false;
+
+    for (key, dir) in [("settings", layout().settings()), ("history", layout().history()), ("playlists", layout().playlists()), ("queue", layout().queues())] {
+        for (guild_id, path) in guild_files(&dir) {
+            let value = fs::read_to_string(&path).map_err(|err| err.to_string())
+                .and_then(|json| serde_json::from_str::<serde_json::Value>(&json).map_err(|err| err.to_string()));
+
+            match value {
+                Ok(value) => { guilds.entry(guild_id).or_default().insert(key.to_string(), value); },
+                Err(err) => {
+                    eprintln!("Skipped {}: {}", path.display(), err);
+                    failed = true;
+                },
+            }
+        }
+    }
+
+    match serde_json::to_string_pretty(&guilds) {
+        Ok(json) => println!("{}", json),
+        Err(err) => {
+            eprintln!("{}", err);
+            return 1;
+        },
+    }
+    if failed { 1 } else { 0 }
+}
@@ -1,2 +1,6 @@
 pub mod shitpost_reactions;
-pub mod voice_commands;
\ No newline at end of file
+pub mod voice_commands;
+pub mod settings_commands;
+pub mod history_commands;
+pub mod playlist_commands;
+pub mod library_commands;
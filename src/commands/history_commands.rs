@@ -0,0 +1,226 @@
+use poise::serenity_prelude::{self as serenity, ButtonStyle, CreateComponents, CreateEmbed, InteractionResponseType, MessageComponentInteraction};
+
+use crate::{
+    history::HistoryEntry,
+    permissions::Action,
+    commands::voice_commands::{Control, queue_items},
+};
+
+/// Custom id prefix of every history button
+pub const BUTTON_PREFIX: &str = "history_";
+const BUTTON_PAGE: &str = "history_page:";
+const BUTTON_REQUEUE: &str = "history_requeue:";
+
+const PAGE_SIZE: usize = 10;
+
+/// One page of the guild history, newest entry first
+struct HistoryPage {
+    entries: Vec<HistoryEntry>,
+    /// Requeue key of each entry, see [`requeue_key`]
+    keys: Vec<String>,
+    page: usize,
+    pages: usize,
+}
+
+/// Entries are found again by their timestamp and how many entries before them share it,
+/// so the key keeps pointing at the same entry while new ones are recorded
+fn requeue_key(history: &[HistoryEntry], index: usize) -> String {
+    let played_at = history[index].played_at;
+    let occurrence = history[..index].iter().filter(|entry| entry.played_at == played_at).count();
+    format!("{}:{}", played_at, occurrence)
+}
+
+fn find_entry(history: &[HistoryEntry], key: &str) -> Option<HistoryEntry> {
+    let (played_at, occurrence) = key.split_once(':')?;
+    let (played_at, occurrence) = (played_at.parse::<u64>().ok()?, occurrence.parse::<usize>().ok()?);
+    history.iter().filter(|entry| entry.played_at == played_at).nth(occurrence).cloned()
+}
+
+impl HistoryPage {
+    fn new(history: &[HistoryEntry], page: usize) -> Self {
+        let pages = history.len().div_ceil(PAGE_SIZE).max(1);
+        let page = page.min(pages - 1);
+        let indexes: Vec<usize> = (0..history.len()).rev().skip(page * PAGE_SIZE).take(PAGE_SIZE).collect();
+
+        Self {
+            entries: indexes.iter().map(|index| history[*index].clone()).collect(),
+            keys: indexes.iter().map(|index| requeue_key(history, *index)).collect(),
+            page,
+            pages,
+        }
+    }
+}
+
+fn fill_embed<'b>(embed: &'b mut CreateEmbed, history_page: &HistoryPage) -> &'b mut CreateEmbed {
+    if history_page.entries.is_empty() {
+        return embed.title("History").description("Nothing has been played yet");
+    }
+
+    let lines: Vec<String> = history_page.entries.iter().enumerate().map(|(index, entry)| {
+        let item = &entry.item;
+        let outcome = entry.outcome.label();
+        let requester = match item.requester {
+            _ if item.autoplay => " - autoplay".to_string(),
+            Some(requester) => format!(" - <@{}>", requester),
+            None => String::new(),
+        };
+
+        let title = match item.link() {
+            Some(link) => format!("[{}]({})", item.title, link),
+            None => item.title.to_owned(),
+        };
+
+        format!("{}. {}{} - {} <t:{}:R>",
+            index + 1,
+            title,
+            requester,
+            outcome,
+            entry.played_at)
+    }).collect();
+
+    embed
+        .title("History")
+        .description(lines.join("\n"))
+        .footer(|f| f.text(format!("Page {}/{}", history_page.page + 1, history_page.pages)))
+}
+
+fn fill_components<'b>(components: &'b mut CreateComponents, history_page: &HistoryPage) -> &'b mut CreateComponents {
+    // Requeue buttons in rows of 5, labeled with the position of the entry in the page
+    for (row_index, row_keys) in history_page.keys.chunks(5).enumerate() {
+        components.create_action_row(|row| {
+            for (index, key) in row_keys.iter().enumerate() {
+                row.create_button(|b| b
+                    .custom_id(format!("{}{}", BUTTON_REQUEUE, key))
+                    .label(format!("{}", row_index * 5 + index + 1))
+                    .style(ButtonStyle::Secondary));
+            }
+            row
+        });
+    }
+
+    components.create_action_row(|row| {
+        row
+            .create_button(|b| b
+                .custom_id(format!("{}{}", BUTTON_PAGE, history_page.page.saturating_sub(1)))
+                .label("Previous")
+                .style(ButtonStyle::Primary)
+                .disabled(history_page.page == 0))
+            .create_button(|b| b
+                .custom_id(format!("{}{}", BUTTON_PAGE, history_page.page + 1))
+                .label("Next")
+                .style(ButtonStyle::Primary)
+                .disabled(history_page.page + 1 >= history_page.pages))
+    })
+}
+
+/// Change the page of a history message or requeue one of its entries
+pub async fn handle_history_button(discord: &serenity::Context, data: &crate::Data, component: &MessageComponentInteraction) -> Result<(), crate::Error> {
+    let guild_id = match component.guild_id {
+        Some(guild_id) => guild_id,
+        None => return Ok(()),
+    };
+    let custom_id = component.data.custom_id.as_str();
+
+    if let Some(page) = custom_id.strip_prefix(BUTTON_PAGE) {
+        let page = page.parse::<usize>().unwrap_or(0);
+        let history_page = HistoryPage::new(data.system_playlist.write().await.history.entries(guild_id), page);
+
+        component.create_interaction_response(&discord.http, |r| r
+            .kind(InteractionResponseType::UpdateMessage)
+            .interaction_response_data(|d| d
+                .embed(|e| fill_embed(e, &history_page))
+                .components(|c| fill_components(c, &history_page)))
+        ).await?;
+
+        return Ok(());
+    }
+
+    let key = match custom_id.strip_prefix(BUTTON_REQUEUE) {
+        Some(key) => key,
+        None => return Ok(()),
+    };
+
+    // Requeueing may join the voice channel and start the player, answer later
+    component.create_interaction_response(&discord.http, |r| r
+        .kind(InteractionResponseType::DeferredChannelMessageWithSource)
+        .interaction_response_data(|d| d.ephemeral(true))
+    ).await?;
+
+    let control = Control {
+        discord,
+        data,
+        guild_id,
+        channel_id: component.channel_id,
+        user_id: component.user.id,
+    };
+
+    let entry = find_entry(data.system_playlist.write().await.history.entries(guild_id), key);
+
+    let content = match entry {
+        Some(entry) => match control.check(Action::Play).await {
+            Ok(_) => {
+                let mut item = entry.item;
+                item.requester = Some(*component.user.id.as_u64());
+
+                match queue_items(&control, vec![item]).await {
+                    Ok(lines) => lines.join("\n"),
+                    Err(err) => err.to_string(),
+                }
+            },
+            Err(err) => err.to_string(),
+        },
+        None => "This entry is no longer in the history".to_string(),
+    };
+
+    component.edit_original_interaction_response(&discord.http, |r| r.content(content)).await?;
+
+    Ok(())
+}
+
+/// Show the songs played in this server
+#[poise::command(slash_command, guild_only)]
+pub async fn history(
+    ctx: crate::Context<'_>,
+    #[description = "Page of the history, starting at 1"]
+    page: Option<usize>,
+) -> Result<(), crate::Error> {
+    let guild_id = ctx.guild_id().ok_or_else( || Box::new(crate::CommandError("Cannot get Guild ID".into())))?;
+
+    let page = page.unwrap_or(1).saturating_sub(1);
+    let history_page = HistoryPage::new(ctx.data().system_playlist.write().await.history.entries(guild_id), page);
+
+    let _ = ctx.send(|r| r
+        .embed(|e| fill_embed(e, &history_page))
+        .components(|c| fill_components(c, &history_page))
+    ).await;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::history::PlayOutcome;
+    use crate::pot::PlaylistItem;
+
+    fn entry(id: &str, played_at: u64) -> HistoryEntry {
+        HistoryEntry { item: PlaylistItem::for_test("youtube", id, id), played_at, outcome: PlayOutcome::Finished }
+    }
+
+    #[test]
+    fn requeue_keys_tell_entries_of_the_same_second_apart() {
+        let mut history = vec![entry("a", 10), entry("b", 20), entry("c", 20)];
+        assert_eq!(requeue_key(&history, 1), "20:0");
+        assert_eq!(requeue_key(&history, 2), "20:1");
+
+        // Entries recorded later do not move the key
+        let key = requeue_key(&history, 2);
+        history.push(entry("d", 20));
+        assert_eq!(find_entry(&history, &key).unwrap().item.id, "c");
+        assert_eq!(find_entry(&history, "10:0").unwrap().item.id, "a");
+
+        assert!(find_entry(&history, "20:3").is_none());
+        assert!(find_entry(&history, "20").is_none());
+        assert!(find_entry(&history, "abc:0").is_none());
+    }
+}
@@ -0,0 +1,70 @@
+use crate::{
+    Error, Context,
+    library,
+    now_playing::format_duration,
+};
+
+/// Results shown by /library search
+const SEARCH_LIMIT: usize = 10;
+
+/// Browse the local music library
+#[poise::command(slash_command, guild_only, subcommands("search", "rescan"))]
+pub async fn library(
+    _ctx: Context<'_>
+) -> Result<(), Error> {
+    Ok(())
+}
+
+/// Search the library by title, artist, album or file name
+#[poise::command(slash_command, guild_only)]
+pub async fn search(
+    ctx: Context<'_>,
+    #[description = "Words to search"]
+    query: String,
+) -> Result<(), Error> {
+    let msg = {
+        let library = ctx.data().library.read().await;
+
+        if library.root().is_none() {
+            "The library is not configured".to_string()
+        } else {
+            let lines: Vec<String> = library.search(&query, SEARCH_LIMIT).iter().enumerate().map(|(index, entry)| {
+                let album = match &entry.album {
+                    Some(album) => format!(" ({})", album),
+                    None => String::new(),
+                };
+                let duration = match entry.duration {
+                    Some(duration) => format!(" [{}]", format_duration(duration)),
+                    None => String::new(),
+                };
+                format!("{}. {}{}{}", index + 1, entry.display_title(), album, duration)
+            }).collect();
+
+            if lines.is_empty() {
+                format!("Nothing in the library matches {}", query)
+            } else {
+                format!("{}\nPlay one with /play library:<query>", lines.join("\n"))
+            }
+        }
+    };
+    let _ = ctx.send(|r| r.content(msg)).await;
+
+    Ok(())
+}
+
+/// Scan the library directory again, only the bot owners can use it
+#[poise::command(slash_command, guild_only, owners_only)]
+pub async fn rescan(
+    ctx: Context<'_>,
+) -> Result<(), Error> {
+    // Reading the tags of new files takes a while
+    ctx.defer().await?;
+
+    let msg = match library::rescan(&ctx.data().library).await {
+        Ok(len) => format!("The library has {} songs", len),
+        Err(err) => err.to_string(),
+    };
+    let _ = ctx.send(|r| r.content(msg)).await;
+
+    Ok(())
+}
@@ -0,0 +1,357 @@
+use std::borrow::Cow;
+
+use poise::serenity_prelude::{self as serenity, AttachmentType};
+
+use crate::{
+    Error, Context,
+    PotPlayInputType,
+    pot::PlaylistItem,
+    playlist_formats::{self, PlaylistFormat},
+    permissions::{self, Action},
+    now_playing::format_duration,
+    commands::voice_commands::{Control, queue_items, resolve_input},
+};
+
+/// Items shown by /playlist show
+const SHOW_LIMIT: usize = 20;
+
+async fn autocomplete_name(ctx: Context<'_>, partial: &str) -> Vec<String> {
+    let guild_id = match ctx.guild_id() {
+        Some(guild_id) => guild_id,
+        None => return Vec::new(),
+    };
+    let partial = partial.to_lowercase();
+
+    ctx.data().saved_playlists.write().await.list(guild_id).iter()
+        .filter(|playlist| playlist.name.to_lowercase().contains(&partial))
+        .take(25)
+        .map(|playlist| playlist.name.to_owned())
+        .collect()
+}
+
+/// Save and load named lists of songs
+#[poise::command(slash_command, guild_only, subcommands("save", "load", "add", "list", "show", "delete", "export", "import"))]
+pub async fn playlist(
+    _ctx: Context<'_>
+) -> Result<(), Error> {
+    Ok(())
+}
+
+/// Save the current song and the queue as a playlist, replacing it if it already exists
+#[poise::command(slash_command, guild_only)]
+pub async fn save(
+    ctx: Context<'_>,
+    #[description = "Name of the playlist"]
+    #[autocomplete = "autocomplete_name"]
+    name: String,
+) -> Result<(), Error> {
+    let guild_id = ctx.guild_id().ok_or_else( || Box::new(crate::CommandError("Cannot get Guild ID".into())))?;
+
+    let items: Vec<_> = {
+        let mut playlist = ctx.data().system_playlist.write().await;
+        let current = playlist.player(guild_id).current.clone();
+        current.into_iter().chain(playlist.items(guild_id).iter().cloned()).map(|mut item| {
+            // Whoever loads the playlist requests its items
+            item.requester = None;
+            item.autoplay = false;
+            item
+        }).collect()
+    };
+
+    if items.is_empty() {
+        let _ = ctx.send(|r| r.content("The queue is empty, there is nothing to save")).await;
+        return Ok(());
+    }
+
+    let is_manager = permissions::can_manage_guild(ctx.discord(), guild_id, ctx.author().id).await;
+    let msg = match ctx.data().saved_playlists.write().await.save(guild_id, &name, *ctx.author().id.as_u64(), is_manager, items) {
+        Ok(len) => format!("Saved {} songs as {}", len, name.trim()),
+        Err(err) => err.to_string(),
+    };
+    let _ = ctx.send(|r| r.content(msg)).await;
+
+    Ok(())
+}
+
+/// Add the songs of a playlist to the queue
+#[poise::command(slash_command, guild_only)]
+pub async fn load(
+    ctx: Context<'_>,
+    #[description = "Name of the playlist"]
+    #[autocomplete = "autocomplete_name"]
+    name: String,
+) -> Result<(), Error> {
+    let guild_id = ctx.guild_id().ok_or_else( || Box::new(crate::CommandError("Cannot get Guild ID".into())))?;
+    let control = Control::from_context(ctx)?;
+
+    if let Err(err) = control.check(Action::Play).await {
+        let _ = ctx.send(|r| r.content(err.to_string())).await;
+        return Ok(());
+    }
+
+    let items = match ctx.data().saved_playlists.write().await.get(guild_id, &name) {
+        Some(saved) => saved.items.clone(),
+        None => {
+            let _ = ctx.send(|r| r.content(format!("There is no playlist named {}", name.trim()))).await;
+            return Ok(());
+        },
+    };
+
+    let requester = Some(*ctx.author().id.as_u64());
+    let items = items.into_iter().map(|mut item| {
+        item.requester = requester;
+        item
+    }).collect();
+
+    ctx.defer().await?;
+
+    let content = match queue_items(&control, items).await {
+        Ok(lines) => lines.join("\n"),
+        Err(err) => err.to_string(),
+    };
+    let _ = ctx.send(|r| r.content(content)).await;
+
+    Ok(())
+}
+
+/// Add a song or every song of a playlist url to a saved playlist, creating it if it does not exist
+#[poise::command(slash_command, guild_only)]
+pub async fn add(
+    ctx: Context<'_>,
+    #[description = "Name of the playlist"]
+    #[autocomplete = "autocomplete_name"]
+    name: String,
+    #[description = "Search a song or use a url to a song"]
+    song: String,
+) -> Result<(), Error> {
+    let guild_id = ctx.guild_id().ok_or_else( || Box::new(crate::CommandError("Cannot get Guild ID".into())))?;
+
+    // Resolving can take longer than the interaction timeout
+    ctx.defer().await?;
+
+    let items: Vec<_> = match resolve_input(ctx.data(), PotPlayInputType::parse(song), ctx.author().id).await {
+        Ok(items) => items.into_iter().map(|mut item| {
+            item.requester = None;
+            item
+        }).collect(),
+        Err(err) => {
+            tracing::warn!("Cannot resolve the song: {:?}", err);
+            let _ = ctx.send(|r| r.content("Cannot find the song")).await;
+            return Ok(());
+        },
+    };
+    let added = items.len();
+
+    let is_manager = permissions::can_manage_guild(ctx.discord(), guild_id, ctx.author().id).await;
+    let msg = match ctx.data().saved_playlists.write().await.append(guild_id, &name, *ctx.author().id.as_u64(), is_manager, items) {
+        Ok(len) => format!("Added {} songs to {}, it has {} songs now", added, name.trim(), len),
+        Err(err) => err.to_string(),
+    };
+    let _ = ctx.send(|r| r.content(msg)).await;
+
+    Ok(())
+}
+
+/// Show the saved playlists of this server
+#[poise::command(slash_command, guild_only)]
+pub async fn list(
+    ctx: Context<'_>,
+) -> Result<(), Error> {
+    let guild_id = ctx.guild_id().ok_or_else( || Box::new(crate::CommandError("Cannot get Guild ID".into())))?;
+
+    let lines: Vec<String> = ctx.data().saved_playlists.write().await.list(guild_id).iter().map(|playlist| {
+        format!("**{}** - {} songs - <@{}>", playlist.name, playlist.items.len(), playlist.owner)
+    }).collect();
+
+    if lines.is_empty() {
+        let _ = ctx.send(|r| r.content("There are no saved playlists, create one with /playlist save")).await;
+    } else {
+        let _ = ctx.send(|r| r.embed(|e| e.title("Playlists").description(lines.join("\n")))).await;
+    }
+
+    Ok(())
+}
+
+/// Show the songs of a saved playlist
+#[poise::command(slash_command, guild_only)]
+pub async fn show(
+    ctx: Context<'_>,
+    #[description = "Name of the playlist"]
+    #[autocomplete = "autocomplete_name"]
+    name: String,
+) -> Result<(), Error> {
+    let guild_id = ctx.guild_id().ok_or_else( || Box::new(crate::CommandError("Cannot get Guild ID".into())))?;
+
+    let saved = match ctx.data().saved_playlists.write().await.get(guild_id, &name) {
+        Some(saved) => saved.clone(),
+        None => {
+            let _ = ctx.send(|r| r.content(format!("There is no playlist named {}", name.trim()))).await;
+            return Ok(());
+        },
+    };
+
+    let mut lines: Vec<String> = saved.items.iter().take(SHOW_LIMIT).enumerate().map(|(index, item)| {
+        match item.duration {
+            _ if item.is_live() => format!("{}. {} (LIVE)", index + 1, item.title),
+            Some(duration) => format!("{}. {} ({})", index + 1, item.title, format_duration(duration)),
+            None => format!("{}. {}", index + 1, item.title),
+        }
+    }).collect();
+    if saved.items.len() > SHOW_LIMIT {
+        lines.push(format!("And {} more", saved.items.len() - SHOW_LIMIT));
+    }
+    if lines.is_empty() {
+        lines.push("This playlist is empty".to_string());
+    }
+
+    let total: f32 = saved.items.iter().filter(|item| !item.is_live()).filter_map(|item| item.duration).sum();
+
+    let _ = ctx.send(|r| r.embed(|e| e
+        .title(&saved.name)
+        .description(lines.join("\n"))
+        .field("Owner", format!("<@{}>", saved.owner), true)
+        .field("Songs", saved.items.len(), true)
+        .field("Duration", format_duration(total), true)
+    )).await;
+
+    Ok(())
+}
+
+/// Delete a saved playlist
+#[poise::command(slash_command, guild_only)]
+pub async fn delete(
+    ctx: Context<'_>,
+    #[description = "Name of the playlist"]
+    #[autocomplete = "autocomplete_name"]
+    name: String,
+) -> Result<(), Error> {
+    let guild_id = ctx.guild_id().ok_or_else( || Box::new(crate::CommandError("Cannot get Guild ID".into())))?;
+
+    let is_manager = permissions::can_manage_guild(ctx.discord(), guild_id, ctx.author().id).await;
+    let msg = match ctx.data().saved_playlists.write().await.delete(guild_id, &name, *ctx.author().id.as_u64(), is_manager) {
+        Ok(playlist) => format!("Deleted {}", playlist.name),
+        Err(err) => err.to_string(),
+    };
+    let _ = ctx.send(|r| r.content(msg)).await;
+
+    Ok(())
+}
+
+/// Download the queue or a saved playlist as a file
+#[poise::command(slash_command, guild_only)]
+pub async fn export(
+    ctx: Context<'_>,
+    #[description = "File format"]
+    format: PlaylistFormat,
+    #[description = "Saved playlist to export, the queue is exported when empty"]
+    #[autocomplete = "autocomplete_name"]
+    name: Option<String>,
+) -> Result<(), Error> {
+    let guild_id = ctx.guild_id().ok_or_else( || Box::new(crate::CommandError("Cannot get Guild ID".into())))?;
+
+    let (file_name, items): (String, Vec<PlaylistItem>) = match &name {
+        Some(name) => match ctx.data().saved_playlists.write().await.get(guild_id, name) {
+            Some(saved) => (saved.name.to_owned(), saved.items.clone()),
+            None => {
+                let _ = ctx.send(|r| r.content(format!("There is no playlist named {}", name.trim()))).await;
+                return Ok(());
+            },
+        },
+        None => {
+            let mut playlist = ctx.data().system_playlist.write().await;
+            let current = playlist.player(guild_id).current.clone();
+            ("queue".to_string(), current.into_iter().chain(playlist.items(guild_id).iter().cloned()).collect())
+        },
+    };
+
+    if items.is_empty() {
+        let _ = ctx.send(|r| r.content("There is nothing to export")).await;
+        return Ok(());
+    }
+
+    let content = format.export(&items);
+    let _ = ctx.send(|r| r
+        .content(format!("{} songs", items.len()))
+        .attachment(AttachmentType::Bytes {
+            data: Cow::Owned(content.into_bytes()),
+            filename: format!("{}.{}", file_name, format.extension()),
+        })
+    ).await;
+
+    Ok(())
+}
+
+/// Queue the songs of a M3U, XSPF, JSON or PLS file, or add them to a saved playlist
+#[poise::command(slash_command, guild_only)]
+pub async fn import(
+    ctx: Context<'_>,
+    #[description = "Playlist file"]
+    file: serenity::Attachment,
+    #[description = "Saved playlist to add the songs to, they are queued when empty"]
+    #[autocomplete = "autocomplete_name"]
+    name: Option<String>,
+) -> Result<(), Error> {
+    let guild_id = ctx.guild_id().ok_or_else( || Box::new(crate::CommandError("Cannot get Guild ID".into())))?;
+
+    let format = match PlaylistFormat::from_path(&file.filename) {
+        Some(format) => format,
+        None => {
+            let _ = ctx.send(|r| r.content("Only .m3u, .m3u8, .xspf, .json and .pls files can be imported")).await;
+            return Ok(());
+        },
+    };
+    if file.size > playlist_formats::MAX_FILE_SIZE {
+        let _ = ctx.send(|r| r.content("The file is too big")).await;
+        return Ok(());
+    }
+
+    let control = Control::from_context(ctx)?;
+    if name.is_none() {
+        if let Err(err) = control.check(Action::Play).await {
+            let _ = ctx.send(|r| r.content(err.to_string())).await;
+            return Ok(());
+        }
+    }
+
+    ctx.defer().await?;
+
+    let content = String::from_utf8_lossy(&file.download().await?).into_owned();
+    let mut items = match format.import(&content) {
+        Ok(items) if !items.is_empty() => items,
+        Ok(_) => {
+            let _ = ctx.send(|r| r.content("The file has no songs with a http url")).await;
+            return Ok(());
+        },
+        Err(err) => {
+            let _ = ctx.send(|r| r.content(format!("Cannot read the file: {}", err))).await;
+            return Ok(());
+        },
+    };
+    playlist_formats::mark_streams(&mut items).await;
+
+    let msg = match name {
+        Some(name) => {
+            let added = items.len();
+            let is_manager = permissions::can_manage_guild(ctx.discord(), guild_id, ctx.author().id).await;
+            match ctx.data().saved_playlists.write().await.append(guild_id, &name, *ctx.author().id.as_u64(), is_manager, items) {
+                Ok(len) => format!("Added {} songs to {}, it has {} songs now", added, name.trim(), len),
+                Err(err) => err.to_string(),
+            }
+        },
+        None => {
+            let requester = Some(*ctx.author().id.as_u64());
+            let items = items.into_iter().map(|mut item| {
+                item.requester = requester;
+                item
+            }).collect();
+
+            match queue_items(&control, items).await {
+                Ok(lines) => lines.join("\n"),
+                Err(err) => err.to_string(),
+            }
+        },
+    };
+    let _ = ctx.send(|r| r.content(msg)).await;
+
+    Ok(())
+}
@@ -0,0 +1,297 @@
+use poise::serenity_prelude as serenity;
+use crate::{Error, Context, settings::{AdmissionPolicy, MAX_CROSSFADE}, pot::{track_key, PotPlayInputType, SystemPlaylist}};
+
+/// Change how the bot behaves in this server
+#[poise::command(slash_command, guild_only, required_permissions = "MANAGE_GUILD", subcommands("dj", "voteskip", "queue", "admission", "block", "unblock", "crossfade", "live"))]
+pub async fn settings(
+    _ctx: Context<'_>
+) -> Result<(), Error> {
+    Ok(())
+}
+
+/// Set the role that has full control of the player, leave empty to give everyone control
+#[poise::command(slash_command, guild_only, required_permissions = "MANAGE_GUILD")]
+pub async fn dj(
+    ctx: Context<'_>,
+    #[description = "DJ role"]
+    role: Option<serenity::Role>,
+) -> Result<(), Error> {
+    let guild_id = ctx.guild_id().ok_or_else( || Box::new(crate::CommandError("Cannot get Guild ID".into())))?;
+
+    let result = ctx.data().guild_settings.write().await.update(guild_id, |settings| {
+        settings.dj_role = role.as_ref().map(|role| *role.id.as_u64());
+    });
+
+    let msg = match (result, role) {
+        (Ok(_), Some(role)) => format!("Members with the {} role are now DJs", role.name),
+        (Ok(_), None) => "Everyone can control the player now".to_string(),
+        (Err(err), _) => format!("Cannot save the settings: {}", err),
+    };
+    let _ = ctx.send(|r| r.content(msg)).await;
+
+    Ok(())
+}
+
+/// Let members without skip permission vote to skip the current song
+#[poise::command(slash_command, guild_only, required_permissions = "MANAGE_GUILD")]
+pub async fn voteskip(
+    ctx: Context<'_>,
+    #[description = "Enable vote skip"]
+    enabled: bool,
+    #[description = "Percentage of listeners needed to skip, 50 by default"]
+    #[min = 1]
+    #[max = 100]
+    percentage: Option<u8>,
+) -> Result<(), Error> {
+    let guild_id = ctx.guild_id().ok_or_else( || Box::new(crate::CommandError("Cannot get Guild ID".into())))?;
+
+    let result = ctx.data().guild_settings.write().await.update(guild_id, |settings| {
+        settings.vote_skip = enabled;
+        if let Some(percentage) = percentage {
+            settings.vote_skip_ratio = percentage as f32 / 100.0;
+        }
+    });
+
+    let msg = match result {
+        Ok(settings) if settings.vote_skip => format!("Vote skip enabled, {}% of the listeners are needed to skip", (settings.vote_skip_ratio * 100.0).round()),
+        Ok(_) => "Vote skip disabled".to_string(),
+        Err(err) => format!("Cannot save the settings: {}", err),
+    };
+    let _ = ctx.send(|r| r.content(msg)).await;
+
+    Ok(())
+}
+
+/// Change how songs from different members are ordered and how many each member can queue
+#[poise::command(slash_command, guild_only, required_permissions = "MANAGE_GUILD")]
+pub async fn queue(
+    ctx: Context<'_>,
+    #[description = "Play one song from each member in turn"]
+    fair: Option<bool>,
+    #[description = "Maximum songs a member can have queued, 0 for no limit"]
+    max_songs: Option<u32>,
+    #[description = "Maximum minutes of songs a member can have queued, 0 for no limit"]
+    max_minutes: Option<u32>,
+) -> Result<(), Error> {
+    let guild_id = ctx.guild_id().ok_or_else( || Box::new(crate::CommandError("Cannot get Guild ID".into())))?;
+
+    let result = ctx.data().guild_settings.write().await.update(guild_id, |settings| {
+        if let Some(fair) = fair {
+            settings.fair_queue = fair;
+        }
+        if let Some(max_songs) = max_songs {
+            settings.max_user_items = if max_songs == 0 { None } else { Some(max_songs as usize) };
+        }
+        if let Some(max_minutes) = max_minutes {
+            settings.max_user_duration = if max_minutes == 0 { None } else { Some(max_minutes * 60) };
+        }
+    });
+
+    let msg = match result {
+        Ok(settings) => format!(
+            "Fair queue: {}\nMax songs per member: {}\nMax minutes per member: {}",
+            if settings.fair_queue { "on" } else { "off" },
+            settings.max_user_items.map(|max| max.to_string()).unwrap_or_else(|| "no limit".into()),
+            settings.max_user_duration.map(|max| (max / 60).to_string()).unwrap_or_else(|| "no limit".into()),
+        ),
+        Err(err) => format!("Cannot save the settings: {}", err),
+    };
+    let _ = ctx.send(|r| r.content(msg)).await;
+
+    Ok(())
+}
+
+fn describe_admission(admission: &AdmissionPolicy) -> String {
+    format!(
+        "Max song length: {}\nAllowed sources: {}\nLive streams: {}\nBlocked words: {}\nBlocked songs: {}",
+        admission.max_duration.map(|max| format!("{} minutes", max / 60)).unwrap_or_else(|| "no limit".into()),
+        if admission.allowed_extractors.is_empty() { "any".to_string() } else { admission.allowed_extractors.join(", ") },
+        if admission.allow_live { "allowed" } else { "not allowed" },
+        if admission.blocked_keywords.is_empty() { "none".to_string() } else { admission.blocked_keywords.join(", ") },
+        admission.blocked_tracks.len(),
+    )
+}
+
+/// Change which songs can be queued
+#[poise::command(slash_command, guild_only, required_permissions = "MANAGE_GUILD")]
+pub async fn admission(
+    ctx: Context<'_>,
+    #[description = "Maximum minutes of a single song, 0 for no limit"]
+    max_minutes: Option<u32>,
+    #[description = "Allow live streams"]
+    live: Option<bool>,
+    #[description = "Comma separated yt-dlp extractors songs can come from, \"any\" to allow all"]
+    sources: Option<String>,
+) -> Result<(), Error> {
+    let guild_id = ctx.guild_id().ok_or_else( || Box::new(crate::CommandError("Cannot get Guild ID".into())))?;
+
+    let result = ctx.data().guild_settings.write().await.update(guild_id, |settings| {
+        if let Some(max_minutes) = max_minutes {
+            settings.admission.max_duration = if max_minutes == 0 { None } else { Some(max_minutes * 60) };
+        }
+        if let Some(live) = live {
+            settings.admission.allow_live = live;
+        }
+        if let Some(sources) = &sources {
+            settings.admission.allowed_extractors = if sources.trim().eq_ignore_ascii_case("any") {
+                Vec::new()
+            } else {
+                sources.split(',').map(|source| source.trim().to_lowercase()).filter(|source| !source.is_empty()).collect()
+            };
+        }
+    });
+
+    let msg = match result {
+        Ok(settings) => describe_admission(&settings.admission),
+        Err(err) => format!("Cannot save the settings: {}", err),
+    };
+    let _ = ctx.send(|r| r.content(msg)).await;
+
+    Ok(())
+}
+
+/// Key a song is blocked by, urls that are not YouTube videos are resolved to the extractor:id of their song
+async fn song_key(song: &str, ctx: Context<'_>) -> Result<String, String> {
+    let key = track_key(song);
+    let url = match url::Url::parse(song) {
+        Ok(url) if matches!(url.scheme(), "http" | "https") && key == song => url,
+        _ => return Ok(key),
+    };
+
+    match SystemPlaylist::resolve(PotPlayInputType::Url(url), ctx.author().id).await {
+        Ok(items) if items.len() == 1 => Ok(items[0].key()),
+        Ok(items) if items.is_empty() => Err(format!("Cannot find a song at {}", song)),
+        Ok(_) => Err(format!("{} is a playlist, block its songs one by one", song)),
+        Err(err) => Err(format!("Cannot find a song at {}: {}", song, err)),
+    }
+}
+
+/// Block a word in song titles or a specific song
+#[poise::command(slash_command, guild_only, required_permissions = "MANAGE_GUILD")]
+pub async fn block(
+    ctx: Context<'_>,
+    #[description = "Reject songs with this word in the title"]
+    word: Option<String>,
+    #[description = "Url, id or extractor:id of a song to reject"]
+    song: Option<String>,
+) -> Result<(), Error> {
+    let guild_id = ctx.guild_id().ok_or_else( || Box::new(crate::CommandError("Cannot get Guild ID".into())))?;
+
+    let key = match &song {
+        Some(song) => match song_key(song.trim(), ctx).await {
+            Ok(key) => Some(key),
+            Err(msg) => {
+                let _ = ctx.send(|r| r.content(msg)).await;
+                return Ok(());
+            },
+        },
+        None => None,
+    };
+
+    let result = ctx.data().guild_settings.write().await.update(guild_id, |settings| {
+        if let Some(word) = &word {
+            let word = word.trim().to_string();
+            if !word.is_empty() && !settings.admission.blocked_keywords.contains(&word) {
+                settings.admission.blocked_keywords.push(word);
+            }
+        }
+        if let Some(key) = key {
+            if !settings.admission.blocked_tracks.contains(&key) {
+                settings.admission.blocked_tracks.push(key);
+            }
+        }
+    });
+
+    let msg = match result {
+        Ok(settings) => describe_admission(&settings.admission),
+        Err(err) => format!("Cannot save the settings: {}", err),
+    };
+    let _ = ctx.send(|r| r.content(msg)).await;
+
+    Ok(())
+}
+
+/// Remove a blocked word or song
+#[poise::command(slash_command, guild_only, required_permissions = "MANAGE_GUILD")]
+pub async fn unblock(
+    ctx: Context<'_>,
+    #[description = "Blocked word to remove"]
+    word: Option<String>,
+    #[description = "Url or id of the blocked song to remove"]
+    song: Option<String>,
+) -> Result<(), Error> {
+    let guild_id = ctx.guild_id().ok_or_else( || Box::new(crate::CommandError("Cannot get Guild ID".into())))?;
+
+    // A song that cannot be resolved anymore can still be removed by the key shown in the settings
+    let key = match &song {
+        Some(song) => Some(song_key(song.trim(), ctx).await.unwrap_or_else(|_| track_key(song.trim()))),
+        None => None,
+    };
+
+    let result = ctx.data().guild_settings.write().await.update(guild_id, |settings| {
+        if let Some(word) = &word {
+            settings.admission.blocked_keywords.retain(|blocked| !blocked.eq_ignore_ascii_case(word.trim()));
+        }
+        if let (Some(song), Some(key)) = (&song, &key) {
+            settings.admission.blocked_tracks.retain(|blocked| blocked != key && *blocked != song.trim());
+        }
+    });
+
+    let msg = match result {
+        Ok(settings) => describe_admission(&settings.admission),
+        Err(err) => format!("Cannot save the settings: {}", err),
+    };
+    let _ = ctx.send(|r| r.content(msg)).await;
+
+    Ok(())
+}
+
+/// Fade between songs instead of stopping one before the next starts
+#[poise::command(slash_command, guild_only, required_permissions = "MANAGE_GUILD")]
+pub async fn crossfade(
+    ctx: Context<'_>,
+    #[description = "Seconds of the fade, 0 to disable it"]
+    #[min = 0]
+    #[max = 12]
+    seconds: u32,
+) -> Result<(), Error> {
+    let guild_id = ctx.guild_id().ok_or_else( || Box::new(crate::CommandError("Cannot get Guild ID".into())))?;
+
+    let result = ctx.data().guild_settings.write().await.update(guild_id, |settings| {
+        settings.crossfade = seconds.min(MAX_CROSSFADE);
+    });
+
+    let msg = match result {
+        Ok(settings) if settings.crossfade > 0 => format!("Songs crossfade for {} seconds", settings.crossfade),
+        Ok(_) => "Crossfade disabled".to_string(),
+        Err(err) => format!("Cannot save the settings: {}", err),
+    };
+    let _ = ctx.send(|r| r.content(msg)).await;
+
+    Ok(())
+}
+
+/// Move on to the next song after a live stream played for a while
+#[poise::command(slash_command, guild_only, required_permissions = "MANAGE_GUILD")]
+pub async fn live(
+    ctx: Context<'_>,
+    #[description = "Minutes a live stream plays, 0 to let it play until it is skipped"]
+    max_minutes: u32,
+) -> Result<(), Error> {
+    let guild_id = ctx.guild_id().ok_or_else( || Box::new(crate::CommandError("Cannot get Guild ID".into())))?;
+
+    let result = ctx.data().guild_settings.write().await.update(guild_id, |settings| {
+        settings.max_live_duration = if max_minutes == 0 { None } else { Some(max_minutes.saturating_mul(60)) };
+    });
+
+    let msg = match result {
+        Ok(settings) => match settings.max_live_duration {
+            Some(max) => format!("Live streams play for {} minutes", max / 60),
+            None => "Live streams play until they are skipped".to_string(),
+        },
+        Err(err) => format!("Cannot save the settings: {}", err),
+    };
+    let _ = ctx.send(|r| r.content(msg)).await;
+
+    Ok(())
+}
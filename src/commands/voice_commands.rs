@@ -1,15 +1,59 @@
 use std::sync::Arc;
-use tokio::{time::{sleep, Duration}, sync::RwLockWriteGuard};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Instant;
+use tokio::{time::{sleep, Duration}};
+use tracing::Instrument;
 
 use songbird::{
     Songbird,
+    tracks::TrackHandle,
     Call, Event, EventContext, EventHandler as VoiceEventHandler, TrackEvent,
 };
 
-use async_recursion::async_recursion;
-use poise::{serenity_prelude::{Context, GuildId, ChannelId, CacheHttp, Mutex, RwLock}, async_trait};
+use poise::{serenity_prelude::{self as serenity, GuildId, ChannelId, UserId, Http, Mutex, RwLock, InteractionResponseType, MessageComponentInteraction}, async_trait};
 
-use crate::{PotPlayInputType, pot::SystemPlaylist};
+use crate::{
+    PotPlayInputType,
+    attachments,
+    autoplay,
+    metrics,
+    pot::{SystemPlaylist, PlaylistItem},
+    history::PlayOutcome,
+    filters::{AudioFilter, FilterPreset},
+    player::{AdvanceReason, LoopMode, PlayerEvent, PlayerState, Requeue, MAX_RESOLVE_FAILURES, LIVE_STABLE_AFTER, votes_needed},
+    settings::{GuildSettings, GuildSettingsStore},
+    permissions::{self, Action, Role},
+    now_playing,
+};
+
+/// Who asked for a player action and where, shared by slash commands and the now playing buttons
+pub struct Control<'a> {
+    pub discord: &'a serenity::Context,
+    pub data: &'a crate::Data,
+    pub guild_id: GuildId,
+    pub channel_id: ChannelId,
+    pub user_id: UserId,
+}
+
+impl<'a> Control<'a> {
+    pub fn from_context(ctx: crate::Context<'a>) -> Result<Self, crate::Error> {
+        let guild_id = ctx.guild_id().ok_or_else( || Box::new(crate::CommandError("Cannot get Guild ID".into())))?;
+
+        Ok(Self {
+            discord: ctx.discord(),
+            data: ctx.data(),
+            guild_id,
+            channel_id: ctx.channel_id(),
+            user_id: ctx.author().id,
+        })
+    }
+
+    /// Check if the member can do the action, see [`permissions::check`]
+    pub async fn check(&self, action: Action) -> Result<Role, crate::Error> {
+        let settings = self.data.guild_settings.read().await.get(self.guild_id);
+        permissions::check(self.discord, self.guild_id, self.user_id, &settings, action).await
+    }
+}
 
 pub struct TrackEndNotifier {
     ctx: poise::serenity_prelude::Context,
@@ -17,20 +61,48 @@ pub struct TrackEndNotifier {
     guild_id: Option<GuildId>,
     handler_lock: Arc<Mutex<Call>>,
     playlist: Arc<RwLock<SystemPlaylist>>,
+    guild_settings: Arc<RwLock<GuildSettingsStore>>,
     manager: Arc<Songbird>
 }
 
 #[async_trait]
 impl VoiceEventHandler for TrackEndNotifier {
     async fn act(&self, ctx: &EventContext<'_>) -> Option<Event> {
-        if let EventContext::Track(_track_list) = ctx {
+        if let EventContext::Track(track_list) = ctx {
             if let Some(guild_id) = self.guild_id {
-                let mut handler = self.handler_lock.lock().await;
+                let ended = {
+                    let mut playlist = self.playlist.write().await;
+
+                    // Only advance when the track that ended is the one the player is on
+                    let current = playlist.player(guild_id).track.as_ref().map(|track| track.uuid());
+                    let (state, handle) = track_list.iter().find(|(_, handle)| Some(handle.uuid()) == current)?;
+                    if playlist.state(guild_id) != PlayerState::Playing {
+                        return None;
+                    }
+
+                    let player = playlist.player(guild_id);
+                    if state.play_time >= LIVE_STABLE_AFTER {
+                        player.live_reconnects = 0;
+                    }
+                    (*handle).clone()
+                };
+
+                if self.reconnect_live(guild_id, &ended).await {
+                    return None;
+                }
+
+                let settings = self.guild_settings.read().await.get(guild_id);
                 let mut playlist = self.playlist.write().await;
-                
-                if consume_and_play_on_end(self, &mut handler, guild_id, &mut playlist).await.is_none() {
-                    let _ = self.channel_id.say(&self.ctx.http(), "Queue finished").await;
-                    let _ = self.channel_id.say(&self.ctx.http(), "Left voice channel").await;
+                // Someone moved the player on while the stream was reconnecting
+                let current = playlist.player(guild_id).track.as_ref().map(|track| track.uuid());
+                if playlist.state(guild_id) != PlayerState::Playing || current != Some(ended.uuid()) {
+                    return None;
+                }
+
+                let mut handler = self.handler_lock.lock().await;
+
+                if !matches!(play_next(&self.ctx.http, self.channel_id, &mut playlist, guild_id, &mut handler, AdvanceReason::TrackEnded, &settings).await, PlayNext::Playing) {
+                    let _ = self.channel_id.say(&self.ctx.http, "Left voice channel").await;
                     drop(handler);
                     let _ = self.manager.remove(guild_id).await;
                 }
@@ -41,17 +113,204 @@ impl VoiceEventHandler for TrackEndNotifier {
     }
 }
 
-pub async fn voice_join(ctx: crate::Context<'_>) -> Result<Arc<poise::serenity_prelude::Mutex<Call>>, crate::Error> {
-    let guild = ctx.guild().ok_or_else( || Box::new(crate::CommandError("Cannot get Guild".into())))?;
-    let guild_id = ctx.guild_id().ok_or_else( || Box::new(crate::CommandError("Cannot get Guild ID".into())))?;
+impl TrackEndNotifier {
+    /// Start a dropped live stream again, waiting longer after every failed attempt
+    ///
+    /// Returns false when the item is not live or gave up, then the queue moves on
+    #[tracing::instrument(skip_all, fields(guild_id = guild_id.0))]
+    async fn reconnect_live(&self, guild_id: GuildId, ended: &TrackHandle) -> bool {
+        loop {
+            let delay = {
+                let mut playlist = self.playlist.write().await;
+                let player = playlist.player(guild_id);
+                if player.track.as_ref().map(|track| track.uuid()) != Some(ended.uuid()) {
+                    return true;
+                }
+                if !player.reconnects() {
+                    return false;
+                }
+                match player.next_reconnect() {
+                    Some(delay) => delay,
+                    None => {
+                        tracing::warn!("Live stream did not come back");
+                        let _ = self.channel_id.say(&self.ctx.http, "The live stream is not coming back, moving on").await;
+                        return false;
+                    },
+                }
+            };
+
+            tracing::info!(delay = delay.as_secs(), "Live stream dropped, reconnecting");
+            let _ = self.channel_id.say(&self.ctx.http, format!("The live stream dropped, reconnecting in {} seconds", delay.as_secs())).await;
+            sleep(delay).await;
 
-    let msg_channel = ctx.channel_id();
+            let mut playlist = self.playlist.write().await;
+            // Skipped or stopped while waiting
+            if playlist.state(guild_id) != PlayerState::Playing
+                || playlist.player(guild_id).track.as_ref().map(|track| track.uuid()) != Some(ended.uuid()) {
+                return true;
+            }
+
+            let player = playlist.player(guild_id);
+            let (item, filter) = match &player.current {
+                Some(item) => (item.clone(), player.filter.clone()),
+                None => return true,
+            };
+
+            match playlist.get_media_stream(&item, &filter, 0.0).await {
+                Ok(source) => {
+                    let track = self.handler_lock.lock().await.play_only_source(source);
+                    playlist.player(guild_id).track = Some(track);
+                    return true;
+                },
+                Err(err) => tracing::warn!("Cannot reconnect the stream: {:?}", err),
+            }
+        }
+    }
+}
+
+/// Moves the queue on when a live stream played for as long as the guild allows
+pub struct LiveLimitNotifier {
+    ctx: poise::serenity_prelude::Context,
+    channel_id: ChannelId,
+    guild_id: GuildId,
+    handler_lock: Arc<Mutex<Call>>,
+    playlist: Arc<RwLock<SystemPlaylist>>,
+    guild_settings: Arc<RwLock<GuildSettingsStore>>,
+    manager: Arc<Songbird>,
+}
+
+/// How often the play time of live streams is checked
+const LIVE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+#[async_trait]
+impl VoiceEventHandler for LiveLimitNotifier {
+    async fn act(&self, _ctx: &EventContext<'_>) -> Option<Event> {
+        let settings = self.guild_settings.read().await.get(self.guild_id);
+        let limit = settings.max_live_duration?;
+
+        let mut playlist = self.playlist.write().await;
+        if !matches!(playlist.state(self.guild_id), PlayerState::Playing | PlayerState::Paused)
+            || !playlist.player(self.guild_id).live_limit_reached(Duration::from_secs(limit as u64)) {
+            return None;
+        }
+
+        let _ = self.channel_id.say(&self.ctx.http, format!("The live stream played for {} minutes, moving on", limit / 60)).await;
+        let mut handler = self.handler_lock.lock().await;
+
+        if !matches!(play_next(&self.ctx.http, self.channel_id, &mut playlist, self.guild_id, &mut handler, AdvanceReason::TimeLimit, &settings).await, PlayNext::Playing) {
+            let _ = self.channel_id.say(&self.ctx.http, "Left voice channel").await;
+            drop(handler);
+            let _ = self.manager.remove(self.guild_id).await;
+        }
+
+        None
+    }
+}
+
+/// Checks how much is left of the current track and starts the next one early when the guild uses crossfade
+pub struct CrossfadeNotifier {
+    ctx: poise::serenity_prelude::Context,
+    channel_id: ChannelId,
+    guild_id: GuildId,
+    handler_lock: Arc<Mutex<Call>>,
+    playlist: Arc<RwLock<SystemPlaylist>>,
+    guild_settings: Arc<RwLock<GuildSettingsStore>>,
+    manager: Arc<Songbird>,
+    /// Track number autoplay had nothing for, so it is not asked again on every check until the track ends
+    autoplay_missed: AtomicUsize,
+}
+
+/// How often the position of the current track is checked
+const CROSSFADE_CHECK_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Volume steps per second of a fade
+const FADE_STEPS_PER_SECOND: u32 = 20;
+
+#[async_trait]
+impl VoiceEventHandler for CrossfadeNotifier {
+    async fn act(&self, _ctx: &EventContext<'_>) -> Option<Event> {
+        let settings = self.guild_settings.read().await.get(self.guild_id);
+        let crossfade = settings.crossfade;
+        if crossfade == 0 {
+            return None;
+        }
+
+        let mut playlist = self.playlist.write().await;
+        if playlist.state(self.guild_id) != PlayerState::Playing {
+            return None;
+        }
+
+        let player = playlist.player(self.guild_id);
+        let fading = player.track.clone()?;
+        let played = fading.get_info().await.ok()?.position.as_secs_f32();
+        match player.remaining(played) {
+            Some(remaining) if remaining <= crossfade as f32 => {},
+            _ => return None,
+        }
+
+        // Without a next item the track plays to its end and the queue finishes as usual
+        let player = playlist.player(self.guild_id);
+        let track_number = player.played;
+        if player.loop_mode == LoopMode::Off && playlist.len(self.guild_id) == 0 {
+            if !settings.autoplay || self.autoplay_missed.load(Ordering::Relaxed) == track_number {
+                return None;
+            }
+            match autoplay::next_item(&mut playlist, self.guild_id, &settings).await {
+                Some(item) => playlist.push_back(self.guild_id, item),
+                None => {
+                    self.autoplay_missed.store(track_number, Ordering::Relaxed);
+                    return None;
+                },
+            }
+        }
+
+        let mut handler = self.handler_lock.lock().await;
+
+        if matches!(play_next(&self.ctx.http, self.channel_id, &mut playlist, self.guild_id, &mut handler, AdvanceReason::Crossfade, &settings).await, PlayNext::Playing) {
+            if let Some(track) = playlist.player(self.guild_id).track.clone() {
+                tokio::spawn(fade(fading, track, crossfade));
+            }
+        } else {
+            // The player stopped the fading track and is idle, its end will not move the queue on
+            let _ = self.channel_id.say(&self.ctx.http, "Left voice channel").await;
+            drop(handler);
+            let _ = self.manager.remove(self.guild_id).await;
+        }
+
+        None
+    }
+}
+
+/// Fade the volume of `fading` out and `track` in over the given seconds, then stop `fading`
+async fn fade(fading: TrackHandle, track: TrackHandle, seconds: u32) {
+    let steps = seconds * FADE_STEPS_PER_SECOND;
+
+    for step in 1..=steps {
+        sleep(Duration::from_secs(1) / FADE_STEPS_PER_SECOND).await;
+        let progress = step as f32 / steps as f32;
+
+        // The track was skipped or stopped during the fade
+        if track.set_volume(progress).is_err() {
+            break;
+        }
+        let _ = fading.set_volume(1.0 - progress);
+    }
+
+    let _ = track.set_volume(1.0);
+    let _ = fading.stop();
+}
+
+pub async fn voice_join(control: &Control<'_>) -> Result<Arc<poise::serenity_prelude::Mutex<Call>>, crate::Error> {
+    let guild = control.discord.cache.guild(control.guild_id).ok_or_else( || Box::new(crate::CommandError("Cannot get Guild".into())))?;
+    let guild_id = control.guild_id;
+
+    let msg_channel = control.channel_id;
+
+    if let Some(channel_id) = guild.voice_states.get(&control.user_id).and_then(|voice_state| voice_state.channel_id) {
+        if control.data.songbird.get(guild_id).is_none() {
+            tracing::debug!(channel_id = channel_id.0, "Joining voice channel");
+            let (call_lock, success) = control.data.songbird.join(guild_id, channel_id).await;
 
-    if let Some(channel_id) = guild.voice_states.get(&ctx.author().id).and_then(|voice_state| voice_state.channel_id) {
-        if ctx.data().songbird.get(guild_id).is_none() {
-            println!("Songbird join");
-            let (call_lock, success) = ctx.data().songbird.join(guild_id, channel_id).await;
-            
             if let Err(why) = success {
                 Err(Box::new(why))
             } else {
@@ -59,12 +318,38 @@ pub async fn voice_join(ctx: crate::Context<'_>) -> Result<Arc<poise::serenity_p
                 call.add_global_event(
                     Event::Track(TrackEvent::End),
                     TrackEndNotifier {
-                        ctx: ctx.discord().clone(),
+                        ctx: control.discord.clone(),
                         channel_id: msg_channel,
                         guild_id: Some(guild_id),
                         handler_lock: call_lock.clone(),
-                        playlist: ctx.data().system_playlist.clone(),
-                        manager: ctx.data().songbird.clone()
+                        playlist: control.data.system_playlist.clone(),
+                        guild_settings: control.data.guild_settings.clone(),
+                        manager: control.data.songbird.clone()
+                    },
+                );
+                call.add_global_event(
+                    Event::Periodic(CROSSFADE_CHECK_INTERVAL, None),
+                    CrossfadeNotifier {
+                        ctx: control.discord.clone(),
+                        channel_id: msg_channel,
+                        guild_id,
+                        handler_lock: call_lock.clone(),
+                        playlist: control.data.system_playlist.clone(),
+                        guild_settings: control.data.guild_settings.clone(),
+                        manager: control.data.songbird.clone(),
+                        autoplay_missed: AtomicUsize::new(0),
+                    },
+                );
+                call.add_global_event(
+                    Event::Periodic(LIVE_CHECK_INTERVAL, None),
+                    LiveLimitNotifier {
+                        ctx: control.discord.clone(),
+                        channel_id: msg_channel,
+                        guild_id,
+                        handler_lock: call_lock.clone(),
+                        playlist: control.data.system_playlist.clone(),
+                        guild_settings: control.data.guild_settings.clone(),
+                        manager: control.data.songbird.clone(),
                     },
                 );
                 drop(call);
@@ -78,17 +363,72 @@ pub async fn voice_join(ctx: crate::Context<'_>) -> Result<Arc<poise::serenity_p
     }
 }
 
-pub async fn voice_leave(ctx: crate::Context<'_>) -> Result<(), crate::Error> {
-    let guild_id = ctx.guild_id().ok_or_else( || Box::new(crate::CommandError("Cannot get Guild ID".into())))?;
+/// Resolve what a member asked to play, library searches are answered from the library index
+pub async fn resolve_input(data: &crate::Data, input: PotPlayInputType, requester: UserId) -> anyhow::Result<Vec<PlaylistItem>> {
+    match input {
+        PotPlayInputType::Library(query) => data.library.read().await.resolve(&query, *requester.as_u64()),
+        input => SystemPlaylist::resolve(input, requester).await,
+    }
+}
+
+/// Add resolved items to the guild playlist, joining the voice channel and starting the player if needed
+///
+/// Returns the lines to answer the member with
+pub async fn queue_items(control: &Control<'_>, items: Vec<PlaylistItem>) -> Result<Vec<String>, crate::Error> {
+    // Songs queued by members are never autoplay songs, even when they come from the history
+    let items: Vec<PlaylistItem> = items.into_iter().map(|mut item| {
+        item.autoplay = false;
+        item
+    }).collect();
+
+    let guild_id = control.guild_id;
+    let mut lines = Vec::new();
+
+    let mut playlist = control.data.system_playlist.write().await;
+
+    match voice_join(control).await {
+        Ok(_) => {
+            lines.push("Joined".to_string());
+            sleep(Duration::from_millis(500)).await;
+        },
+        Err(err) => tracing::warn!("Cannot join voice channel: {}", err),
+    };
+
+    let call_mutex = control.data.songbird.get(guild_id).ok_or_else( || Box::new(crate::CommandError("Not in a voice channel".into())))?;
+    let mut call = call_mutex.lock().await;
+
+    let settings = control.data.guild_settings.read().await.get(guild_id);
+    let outcome = playlist.add(guild_id, items, &settings);
+
+    lines.push(if outcome.added == 1 {
+        "1 song added".to_string()
+    } else {
+        format!("{} songs added", outcome.added)
+    });
+    lines.append(&mut outcome.rejection_summary());
 
-    if ctx.data().songbird.get(guild_id).is_some() {
-        let mut playlist = ctx.data().system_playlist.write().await;
+    if !playlist.is_playing(guild_id) {
+        if !matches!(play_next(&control.discord.http, control.channel_id, &mut playlist, guild_id, &mut call, AdvanceReason::Start, &settings).await, PlayNext::Playing) {
+            drop(call);
+            let _ = control.data.songbird.remove(guild_id).await;
+            lines.push("Left voice channel".to_string());
+        }
+    } else {
+        // Keep the queue size of the now playing message up to date
+        now_playing::refresh(&control.discord.http, &mut playlist, guild_id).await;
+    }
 
-        playlist.clear(guild_id);
-        playlist.set_status(guild_id, false);
+    Ok(lines)
+}
 
-        if let Err(e) = ctx.data().songbird.remove(guild_id).await {
-            let _ = ctx.channel_id().say(&ctx.discord(), format!("Failed: {:?}", e)).await;
+pub async fn voice_leave(control: &Control<'_>) -> Result<(), crate::Error> {
+    let guild_id = control.guild_id;
+
+    if control.data.songbird.get(guild_id).is_some() {
+        control.check(Action::Stop).await?;
+
+        if let Err(e) = stop_session(&control.discord.http, control.data, guild_id).await {
+            let _ = control.channel_id.say(&control.discord.http, format!("Failed: {:?}", e)).await;
             return Err( Box::new(crate::CommandError( format!("Failed: {:?}", e) )) )
         }
 
@@ -98,22 +438,73 @@ pub async fn voice_leave(ctx: crate::Context<'_>) -> Result<(), crate::Error> {
     }
 }
 
-pub async fn song_skip(ctx: crate::Context<'_>) -> Result<String, crate::Error> {
-    let guild_id = ctx.guild_id().ok_or_else( || Box::new(crate::CommandError("Cannot get Guild ID".into())))?;
+/// End the session of the guild, the queue is cleared and the current item is recorded as stopped
+pub async fn stop_session(http: &Http, data: &crate::Data, guild_id: GuildId) -> songbird::error::JoinResult<()> {
+    let mut playlist = data.system_playlist.write().await;
 
-    if let Some(handler_lock) = ctx.data().songbird.get(guild_id) {
-        let mut handler = handler_lock.lock().await;
-        handler.stop();
+    playlist.clear(guild_id);
+    playlist.take_current(guild_id, PlayOutcome::Stopped);
+    now_playing::finish(http, &mut playlist, guild_id, "Stopped").await;
+    playlist.transition(guild_id, PlayerEvent::Stop);
 
-        let mut playlist = ctx.data().system_playlist.write().await;
+    data.songbird.remove(guild_id).await
+}
 
-        if playlist.is_playing(guild_id) {
-            if consume_and_play(ctx.channel_id(), ctx.discord(), &mut playlist, guild_id, &mut handler).await.is_none() {
-                drop(handler);
-                let _ = ctx.data().songbird.remove(guild_id).await;
-                Ok("Queue ended".into())
+/// Move on to the next item of the queue, leaves the voice channel and returns false when nothing is left to play
+pub async fn skip_track(http: &Http, songbird: &Songbird, call_lock: &Mutex<Call>, channel_id: ChannelId, playlist: &mut SystemPlaylist, guild_id: GuildId, settings: &GuildSettings) -> bool {
+    let mut call = call_lock.lock().await;
+
+    match play_next(http, channel_id, playlist, guild_id, &mut call, AdvanceReason::Skipped, settings).await {
+        PlayNext::Playing => true,
+        _ => {
+            drop(call);
+            let _ = songbird.remove(guild_id).await;
+            false
+        }
+    }
+}
+
+pub async fn song_skip(control: &Control<'_>) -> Result<String, crate::Error> {
+    let guild_id = control.guild_id;
+
+    if let Some(handler_lock) = control.data.songbird.get(guild_id) {
+        let mut playlist = control.data.system_playlist.write().await;
+
+        if matches!(playlist.state(guild_id), PlayerState::Playing | PlayerState::Paused) {
+            let requester = playlist.player(guild_id).current.as_ref().and_then(|item| item.requester);
+            let mut skipped_msg = "Song skipped".to_string();
+            let settings = control.data.guild_settings.read().await.get(guild_id);
+
+            let manages_guild = settings.vote_skip && permissions::everyone_is_dj(&settings)
+                && permissions::can_manage_guild(control.discord, guild_id, control.user_id).await;
+            let vote = match permissions::skip_action(&settings, *control.user_id.as_u64(), requester, manages_guild) {
+                Action::VoteSkip => true,
+                action => match control.check(action).await {
+                    Ok(_) => false,
+                    // Members that cannot skip vote instead
+                    Err(_) if settings.vote_skip => true,
+                    Err(err) => return Err(err),
+                },
+            };
+
+            if vote {
+                control.check(Action::VoteSkip).await?;
+
+                let needed = votes_needed(permissions::listeners(control.discord, guild_id), settings.vote_skip_ratio);
+                let skip_votes = &mut playlist.player(guild_id).skip_votes;
+                skip_votes.insert(*control.user_id.as_u64());
+                let votes = skip_votes.len();
+
+                if votes < needed {
+                    return Ok(format!("Vote to skip registered {}/{}", votes, needed));
+                }
+                skipped_msg = format!("Vote passed {}/{}, song skipped", votes, needed);
+            }
+
+            if skip_track(&control.discord.http, &control.data.songbird, &handler_lock, control.channel_id, &mut playlist, guild_id, &settings).await {
+                Ok(skipped_msg)
             } else {
-                Ok("Song skipped".into())
+                Ok("Queue ended".into())
             }
         } else {
             Ok("Nothing to play".into())
@@ -123,71 +514,332 @@ pub async fn song_skip(ctx: crate::Context<'_>) -> Result<String, crate::Error>
     }
 }
 
-#[async_recursion]
-async fn consume_and_play(channel_id: ChannelId, http: &Context, playlist: &mut SystemPlaylist, guild_id: GuildId, call: &mut tokio::sync::MutexGuard<'_, Call>) -> Option<()> {
-    // Try to consume a item from the playlist
-    match playlist.consume(guild_id) {
-        Some(playlist_item) => {
-            // If we found a PlaylistItem available we change the playlist status to playing
-            playlist.set_status(guild_id, true);
-            
-            // Then we try to get the mefia file
-            match playlist.get_media_stream(&playlist_item).await {
-                Ok(source) => {
-                    println!("{:?}", source);
-                    // Send message to channel
-                    let _ = channel_id.say(&http, format!("Playing now {}", playlist_item.title)).await;
+/// Put the current item back at the front of the queue and play the history entry before the last one replayed
+pub async fn song_previous(control: &Control<'_>) -> Result<String, crate::Error> {
+    let guild_id = control.guild_id;
+    control.check(Action::Previous).await?;
 
-                    // Play the source
-                    let _ = call.play_only_source(source);
-                    Some(())
-                },
-                Err(err) => {
-                    println!("{:?}", err);
-                    // Set status to not playing
-                    playlist.set_status(guild_id, false);
-                    // Send message of error
-                    let _ = channel_id.say(&http, format!("Cannot play {}", playlist_item.title)).await;
-                    // Try again
-                    consume_and_play(channel_id, http, playlist, guild_id, call).await
-                }
-            }
+    let settings = control.data.guild_settings.read().await.get(guild_id);
+    let mut playlist = control.data.system_playlist.write().await;
+
+    let cursor = playlist.player(guild_id).history_cursor;
+    let entries = playlist.history.entries(guild_id);
+    let item = match entries.len().checked_sub(cursor + 1) {
+        Some(index) => entries[index].item.clone(),
+        None => return Ok("There is no previous song".into()),
+    };
+
+    if voice_join(control).await.is_ok() {
+        sleep(Duration::from_millis(500)).await;
+    }
+    let call_mutex = control.data.songbird.get(guild_id).ok_or_else( || Box::new(crate::CommandError("Not in a voice channel".into())))?;
+    let mut call = call_mutex.lock().await;
+
+    // The current item was not played to the end, it goes back untouched instead of into the history
+    if let Some(current) = playlist.player(guild_id).current.take() {
+        playlist.push_front(guild_id, current);
+    }
+    let title = item.title.clone();
+    playlist.push_front(guild_id, item);
+
+    match play_next(&control.discord.http, control.channel_id, &mut playlist, guild_id, &mut call, AdvanceReason::Previous, &settings).await {
+        PlayNext::Playing => {
+            playlist.player(guild_id).history_cursor = cursor + 1;
+            Ok(format!("Back to {}", title))
         },
-        None => {
-            // No more items in playlist
-            let _ = channel_id.say(&http, "Queue finished").await;
-            // Set status to not playing
-            playlist.set_status(guild_id, false);
-            None
+        _ => {
+            drop(call);
+            let _ = control.data.songbird.remove(guild_id).await;
+            Ok("Left voice channel".into())
         }
     }
 }
 
-#[async_recursion]
-pub async fn consume_and_play_on_end (slf: &TrackEndNotifier, handler: &mut tokio::sync::MutexGuard<'_, Call>, guild_id: GuildId, playlist: &mut RwLockWriteGuard<SystemPlaylist>) -> Option<()> {
-    match playlist.consume(guild_id) {
-        Some(item) => {
-            println!("consumed");
-            match playlist.get_media_stream(&item).await {
-                Ok(source) => {
-                    println!("media getted");
+pub async fn song_pause(control: &Control<'_>, pause: bool) -> Result<String, crate::Error> {
+    control.check(Action::Pause).await?;
 
-                    let _ = slf.channel_id.say(&slf.ctx.http(), format!("Playing now {}", item.title)).await;
-                    handler.play_only_source(source);
-                    Some(())
-                },
-                Err(err) => {
-                    println!("{:?}", err);
-                    println!("media not getted");
-                    let _ = slf.channel_id.say(&slf.ctx.http(), format!("Cannot play {}", item.title)).await;
-                    consume_and_play_on_end(slf, handler, guild_id, playlist).await
-                },
-            }
+    let mut playlist = control.data.system_playlist.write().await;
+    set_paused(&control.discord.http, &mut playlist, control.guild_id, pause).await
+}
+
+/// Pause or resume the track of the guild player, returns the message for the user
+pub async fn set_paused(http: &Http, playlist: &mut SystemPlaylist, guild_id: GuildId, pause: bool) -> Result<String, crate::Error> {
+    let event = if pause { PlayerEvent::Pause } else { PlayerEvent::Resume };
+
+    // Check the transition first so the track is never paused without the player knowing
+    if playlist.state(guild_id).next(event).is_none() {
+        return Ok(if pause { "Nothing to pause".into() } else { "Nothing to resume".into() });
+    }
+
+    if let Some(track) = &playlist.player(guild_id).track {
+        let result = if pause { track.pause() } else { track.play() };
+        if let Err(err) = result {
+            return Err( Box::new(crate::CommandError(err.to_string())) );
+        }
+    }
+    playlist.transition(guild_id, event);
+    now_playing::refresh(http, playlist, guild_id).await;
+
+    Ok(if pause { "Paused".into() } else { "Resumed".into() })
+}
+
+pub async fn queue_shuffle(control: &Control<'_>) -> Result<String, crate::Error> {
+    control.check(Action::Shuffle).await?;
+    let mut playlist = control.data.system_playlist.write().await;
+
+    match playlist.shuffle(control.guild_id) {
+        0 => Ok("Nothing to shuffle".into()),
+        shuffled => {
+            now_playing::refresh(&control.discord.http, &mut playlist, control.guild_id).await;
+            Ok(format!("{} songs shuffled", shuffled))
+        }
+    }
+}
+
+pub async fn queue_loop(control: &Control<'_>) -> Result<String, crate::Error> {
+    control.check(Action::Loop).await?;
+    let mut playlist = control.data.system_playlist.write().await;
+
+    let player = playlist.player(control.guild_id);
+    player.loop_mode = player.loop_mode.cycle();
+    let loop_mode = player.loop_mode;
+
+    now_playing::refresh(&control.discord.http, &mut playlist, control.guild_id).await;
+
+    Ok(format!("Loop mode: {:?}", loop_mode))
+}
+
+/// Turn autoplay on or off, it keeps the session going with related songs when the queue runs out
+pub async fn queue_autoplay(control: &Control<'_>, enabled: bool) -> Result<String, crate::Error> {
+    control.check(Action::Autoplay).await?;
+
+    let result = control.data.guild_settings.write().await.update(control.guild_id, |settings| {
+        settings.autoplay = enabled;
+    });
+
+    match result {
+        Ok(settings) if settings.autoplay => Ok("Autoplay enabled, related songs play when the queue runs out".to_string()),
+        Ok(_) => Ok("Autoplay disabled".to_string()),
+        Err(err) => Ok(format!("Cannot save the settings: {}", err)),
+    }
+}
+
+/// Change the guild filters, the current track restarts where it was with the new filters
+pub async fn song_filter(control: &Control<'_>, filter: AudioFilter) -> Result<String, crate::Error> {
+    let guild_id = control.guild_id;
+    control.check(Action::Filter).await?;
+
+    let mut playlist = control.data.system_playlist.write().await;
+    let filter = filter.clamped();
+    let description = filter.describe();
+
+    let (track, current) = {
+        let player = playlist.player(guild_id);
+        (player.track.clone(), player.current.clone())
+    };
+    let (track, current, call_mutex) = match (track, current, control.data.songbird.get(guild_id)) {
+        (Some(track), Some(current), Some(call_mutex)) => (track, current, call_mutex),
+        _ => {
+            playlist.player(guild_id).filter = filter;
+            return Ok(description);
         },
-        None => {
-            playlist.set_status(guild_id, false);
-            None
+    };
+
+    let played = track.get_info().await.map(|info| info.position.as_secs_f32()).unwrap_or(0.0);
+    let start = playlist.player(guild_id).source_position(played);
+
+    let source = match playlist.get_media_stream(&current, &filter, start).await {
+        Ok(source) => source,
+        Err(err) => {
+            tracing::error!(track = %current.title, "Cannot restart the song: {:?}", err);
+            return Err(Box::new(crate::CommandError("Cannot restart the song with the new filters".into())));
+        },
+    };
+
+    let mut call = call_mutex.lock().await;
+    let track = call.play_only_source(source);
+    if playlist.state(guild_id) == PlayerState::Paused {
+        let _ = track.pause();
+    }
+
+    let player = playlist.player(guild_id);
+    player.track = Some(track);
+    player.filter = filter;
+    player.source_offset = start;
+
+    Ok(description)
+}
+
+/// Remove the item at the 1 based position of the queue
+pub async fn queue_remove(control: &Control<'_>, position: usize) -> Result<String, crate::Error> {
+    let mut playlist = control.data.system_playlist.write().await;
+
+    let index = position.checked_sub(1).ok_or_else( || Box::new(crate::CommandError("Positions start at 1".into())))?;
+    let requester = match playlist.items(control.guild_id).get(index) {
+        Some(item) => item.requester,
+        None => return Err(Box::new(crate::CommandError(format!("There is no song at position {}", position)))),
+    };
+    control.check(Action::Remove { requester }).await?;
+
+    match playlist.remove(control.guild_id, index) {
+        Some(item) => {
+            now_playing::refresh(&control.discord.http, &mut playlist, control.guild_id).await;
+            Ok(format!("Removed {}", item.title))
+        },
+        None => Err(Box::new(crate::CommandError(format!("There is no song at position {}", position)))),
+    }
+}
+
+/// Run the action of a now playing button, going through the same functions as the slash commands
+pub async fn handle_control_button(discord: &serenity::Context, data: &crate::Data, component: &MessageComponentInteraction) -> Result<(), crate::Error> {
+    let guild_id = match component.guild_id {
+        Some(guild_id) => guild_id,
+        None => return Ok(()),
+    };
+
+    if !component.data.custom_id.starts_with("pot_") {
+        return Ok(());
+    }
+
+    // Skipping may take longer than the interaction timeout, answer later
+    component.create_interaction_response(&discord.http, |r| r
+        .kind(InteractionResponseType::DeferredChannelMessageWithSource)
+        .interaction_response_data(|d| d.ephemeral(true))
+    ).await?;
+
+    let control = Control {
+        discord,
+        data,
+        guild_id,
+        channel_id: component.channel_id,
+        user_id: component.user.id,
+    };
+
+    let result = match component.data.custom_id.as_str() {
+        now_playing::BUTTON_PAUSE => {
+            let paused = data.system_playlist.read().await.state(guild_id) == PlayerState::Paused;
+            song_pause(&control, !paused).await
         },
+        now_playing::BUTTON_SKIP => song_skip(&control).await,
+        now_playing::BUTTON_STOP => voice_leave(&control).await.map(|_| "Left voice channel".into()),
+        now_playing::BUTTON_SHUFFLE => queue_shuffle(&control).await,
+        now_playing::BUTTON_LOOP => queue_loop(&control).await,
+        _ => Ok("Unknown button".into()),
+    };
+
+    let content = match result {
+        Ok(msg) => msg,
+        Err(err) => err.to_string(),
+    };
+
+    component.edit_original_interaction_response(&discord.http, |r| r.content(content)).await?;
+
+    Ok(())
+}
+
+/// Result of trying to play the next item of the queue
+pub enum PlayNext {
+    Playing,
+    QueueFinished,
+    /// Too many items failed to play in a row
+    GaveUp,
+}
+
+/// Drives the guild player until an item is playing or there is nothing left to try
+///
+/// When the queue runs out and the guild uses autoplay, a related song is played instead of finishing
+#[tracing::instrument(skip_all, fields(guild_id = guild_id.0, ?reason))]
+pub async fn play_next(http: &Http, channel_id: ChannelId, playlist: &mut SystemPlaylist, guild_id: GuildId, call: &mut Call, reason: AdvanceReason, settings: &GuildSettings) -> PlayNext {
+    let outcome = match reason {
+        AdvanceReason::Skipped | AdvanceReason::Previous => PlayOutcome::Skipped,
+        AdvanceReason::Start | AdvanceReason::TrackEnded | AdvanceReason::Crossfade | AdvanceReason::TimeLimit => PlayOutcome::Finished,
+    };
+
+    // Put the item that was playing back in the queue if the loop mode asks for it
+    let loop_mode = playlist.player(guild_id).loop_mode;
+    if let Some(item) = playlist.take_current(guild_id, outcome) {
+        match loop_mode.requeue(reason) {
+            Some(Requeue::Front) => playlist.push_front(guild_id, item),
+            Some(Requeue::Back) => playlist.push_back(guild_id, item),
+            None => {},
+        }
+    }
+
+    playlist.transition(guild_id, PlayerEvent::Advance);
+    playlist.player(guild_id).text_channel = Some(channel_id);
+    let mut resume_at = playlist.player(guild_id).resume_at.take();
+
+    loop {
+        // Try to consume a item from the playlist
+        let next_item = match playlist.consume(guild_id) {
+            Some(playlist_item) => Some(playlist_item),
+            None if settings.autoplay => autoplay::next_item(playlist, guild_id, settings).await,
+            None => None,
+        };
+        let playlist_item = match next_item {
+            Some(playlist_item) => playlist_item,
+            None => {
+                // No more items in playlist
+                call.stop();
+                if !now_playing::finish(http, playlist, guild_id, "Queue finished").await {
+                    let _ = channel_id.say(http, "Queue finished").await;
+                }
+                playlist.transition(guild_id, PlayerEvent::QueueEmpty);
+                return PlayNext::QueueFinished;
+            },
+        };
+
+        // Then we try to get the media
+        let span = tracing::info_span!("track", track = %playlist_item.title, extractor = %playlist_item.extractor, id = %playlist_item.id);
+        let filter = playlist.player(guild_id).filter.clone();
+        // Only the first item of a restored queue continues where it stopped
+        let start = match resume_at.take() {
+            Some((key, position)) if key == playlist_item.key() => position,
+            _ => 0.0,
+        };
+        match playlist.get_media_stream(&playlist_item, &filter, start).instrument(span.clone()).await {
+            Ok(source) => {
+                tracing::info!(parent: &span, "Playing");
+                metrics::TRACKS_STARTED.with_label_values(&[&playlist_item.extractor]).inc();
+
+                // Play the source, a crossfade keeps the current track playing while the new one fades in
+                let track = if reason == AdvanceReason::Crossfade {
+                    let (mut track, handle) = songbird::create_player(source);
+                    track.set_volume(0.0);
+                    call.play(track);
+                    handle
+                } else {
+                    call.play_only_source(source)
+                };
+                playlist.transition(guild_id, PlayerEvent::Started);
+
+                let player = playlist.player(guild_id);
+                player.track = Some(track);
+                player.live_since = playlist_item.is_live().then(Instant::now);
+                player.live_reconnects = 0;
+                player.current = Some(playlist_item);
+                player.source_offset = start;
+                player.played += 1;
+
+                now_playing::announce(http, channel_id, playlist, guild_id).await;
+                return PlayNext::Playing;
+            },
+            Err(err) => {
+                tracing::warn!(parent: &span, "Cannot play: {:?}", err);
+                metrics::TRACKS_FAILED.with_label_values(&[&playlist_item.extractor]).inc();
+                let _ = channel_id.say(http, format!("Cannot play {}", playlist_item.title)).await;
+
+                if playlist.transition(guild_id, PlayerEvent::Failed) == Some(PlayerState::Idle) {
+                    call.stop();
+                    let reason = format!("{} items in a row failed to play, stopping", MAX_RESOLVE_FAILURES);
+                    if !now_playing::finish(http, playlist, guild_id, &reason).await {
+                        let _ = channel_id.say(http, reason).await;
+                    }
+                    return PlayNext::GaveUp;
+                }
+                playlist.transition(guild_id, PlayerEvent::Advance);
+            },
+        }
     }
 }
 
@@ -195,10 +847,12 @@ pub async fn consume_and_play_on_end (slf: &TrackEndNotifier, handler: &mut toki
 pub async fn join(
     ctx: crate::Context<'_>,
 ) -> Result<(), crate::Error> {
-    match voice_join(ctx).await {
+    let control = Control::from_context(ctx)?;
+
+    match voice_join(&control).await {
         Ok(_) => { let _ = ctx.send(|r| r.content("Joined")).await;},
         Err(err) => {
-            println!("voice join error {}", err);
+            tracing::warn!("Cannot join voice channel: {}", err);
             let _ = ctx.send(|r| r.content("Cannot join")).await;
         },
     };
@@ -210,7 +864,9 @@ pub async fn join(
 pub async fn leave(
     ctx: crate::Context<'_>,
 ) -> Result<(), crate::Error> {
-    match voice_leave(ctx).await {
+    let control = Control::from_context(ctx)?;
+
+    match voice_leave(&control).await {
         Ok(_) => { let _ = ctx.send(|r| r.content("Left voice channel")).await;},
         Err(err) => {
             let _ = ctx.send(|r| r.content(err.to_string())).await;
@@ -220,67 +876,274 @@ pub async fn leave(
     Ok(())
 }
 
+/// Download the audio files and queue them, files that are not accepted are listed with the reason
+async fn queue_attachments(control: &Control<'_>, attachments: &[serenity::Attachment]) -> String {
+    let mut lines = Vec::new();
+    let mut items = Vec::new();
+
+    for attachment in attachments {
+        match attachments::download(attachment, *control.user_id.as_u64()).await {
+            Ok(item) => items.push(item),
+            Err(err) => lines.push(err.to_string()),
+        }
+    }
+
+    if !items.is_empty() {
+        match queue_items(control, items).await {
+            Ok(queued) => lines.extend(queued),
+            Err(err) => lines.push(err.to_string()),
+        }
+    }
+
+    lines.join("\n")
+}
+
 #[poise::command(slash_command, guild_only)]
 pub async fn play(
     ctx: crate::Context<'_>,
-    #[description = "Search a song or use a url to a song"]
-    song: String,
+    #[description = "Search a song, use a url to a song or library:<query> to play a local file"]
+    song: Option<String>,
+    #[description = "Audio file to play"]
+    file: Option<serenity::Attachment>,
 ) -> Result<(), crate::Error> {
-    use url::{Url};
-
-    let src = song;
-    
-    // Get pot input type from src
-    let input = match Url::parse(&src) {
-        Ok(url_parsed) => PotPlayInputType::Url(url_parsed),
-        Err(_) => PotPlayInputType::Search(src)
+    if song.is_none() && file.is_none() {
+        let _ = ctx.send(|r| r.content("Search a song, use a url or attach an audio file")).await;
+        return Ok(());
+    }
+
+    let control = Control::from_context(ctx)?;
+
+    if let Err(err) = control.check(Action::Play).await {
+        let _ = ctx.send(|r| r.content(err.to_string())).await;
+        return Ok(());
+    }
+
+    // Resolving can take longer than the interaction timeout
+    ctx.defer().await?;
+
+    let mut lines = Vec::new();
+
+    if let Some(song) = song {
+        let input = PotPlayInputType::parse(song);
+        let is_library = matches!(input, PotPlayInputType::Library(_));
+
+        lines.push(match resolve_input(ctx.data(), input, ctx.author().id).await {
+            Ok(items) => match queue_items(&control, items).await {
+                Ok(queued) => queued.join("\n"),
+                Err(err) => err.to_string(),
+            },
+            Err(err) if is_library => err.to_string(),
+            Err(err) => {
+                tracing::warn!("Cannot resolve the song: {:?}", err);
+                "Error adding to the playlist".to_string()
+            },
+        });
+    }
+    if let Some(file) = file {
+        lines.push(queue_attachments(&control, &[file]).await);
+    }
+
+    let _ = ctx.send(|r| r.content(lines.join("\n"))).await;
+
+    Ok(())
+}
+
+/// Queue the audio files attached to a message
+#[poise::command(context_menu_command = "Play attachment", guild_only)]
+pub async fn play_attachment(
+    ctx: crate::Context<'_>,
+    #[description = "Message with audio files"]
+    message: serenity::Message,
+) -> Result<(), crate::Error> {
+    if message.attachments.is_empty() {
+        let _ = ctx.send(|r| r.content("The message has no attachments").ephemeral(true)).await;
+        return Ok(());
+    }
+
+    let control = Control::from_context(ctx)?;
+
+    if let Err(err) = control.check(Action::Play).await {
+        let _ = ctx.send(|r| r.content(err.to_string()).ephemeral(true)).await;
+        return Ok(());
+    }
+
+    // Downloading the files can take longer than the interaction timeout
+    ctx.defer().await?;
+
+    let content = queue_attachments(&control, &message.attachments).await;
+    let _ = ctx.send(|r| r.content(content)).await;
+
+    Ok(())
+}
+
+#[poise::command(slash_command, guild_only)]
+pub async fn skip(
+    ctx: crate::Context<'_>,
+) -> Result<(), crate::Error> {
+    let control = Control::from_context(ctx)?;
+
+    match song_skip(&control).await {
+        Ok(msg) => {
+            let _ = ctx.send(|r| r.content(msg)).await;
+        },
+        Err(err) => {
+            let _ = ctx.send(|r| r.content(err.to_string())).await;
+        },
     };
 
-    let guild_id = ctx.guild_id().ok_or_else( || Box::new(crate::CommandError("Cannot get Guild ID".into())))?;
+    Ok(())
+}
 
-    let songbird = ctx.data().songbird.clone();
-    let mut playlist = ctx.data().system_playlist.write().await;
+/// Replay the previous song, use it again to go further back
+#[poise::command(slash_command, guild_only)]
+pub async fn previous(
+    ctx: crate::Context<'_>,
+) -> Result<(), crate::Error> {
+    let control = Control::from_context(ctx)?;
 
-    match voice_join(ctx).await {
-        Ok(_) => { 
-            let _ = ctx.send(|r| r.content("Joined")).await;
-            sleep(Duration::from_millis(500)).await;
+    match song_previous(&control).await {
+        Ok(msg) => {
+            let _ = ctx.send(|r| r.content(msg)).await;
+        },
+        Err(err) => {
+            let _ = ctx.send(|r| r.content(err.to_string())).await;
         },
-        Err(err) => {println!("voice join error {}", err);},
     };
 
-    if let Some(call_mutex) = ctx.data().songbird.get(guild_id) {
-        let mut call = call_mutex.lock().await;
-        
-        match playlist.add(guild_id, input).await {
-            Ok(items_added) => {
-                if items_added > 1 {
-                    let _ = ctx.channel_id().say(&ctx.discord(), format!("{} songs added", items_added)).await;
-                } else {
-                    let _ = ctx.channel_id().say(&ctx.discord(), "1 song added").await;
-                }
+    Ok(())
+}
 
-                if !playlist.is_playing(guild_id) && consume_and_play(ctx.channel_id(), ctx.discord(), &mut playlist, guild_id, &mut call).await.is_none(){
-                    drop(call);
-                    let _ = songbird.remove(guild_id).await;
-                    let _ = ctx.channel_id().say(&ctx.discord(), "Left voice channel").await;
-                }
-            },
-            Err(_err) => {
-                let _ = ctx.channel_id().say(&ctx.discord(), "Error adding to the playlist").await;
-            }
+#[poise::command(slash_command, guild_only)]
+pub async fn pause(
+    ctx: crate::Context<'_>,
+) -> Result<(), crate::Error> {
+    let control = Control::from_context(ctx)?;
+
+    match song_pause(&control, true).await {
+        Ok(msg) => {
+            let _ = ctx.send(|r| r.content(msg)).await;
+        },
+        Err(err) => {
+            let _ = ctx.send(|r| r.content(err.to_string())).await;
+        },
+    };
+
+    Ok(())
+}
+
+#[poise::command(slash_command, guild_only)]
+pub async fn resume(
+    ctx: crate::Context<'_>,
+) -> Result<(), crate::Error> {
+    let control = Control::from_context(ctx)?;
+
+    match song_pause(&control, false).await {
+        Ok(msg) => {
+            let _ = ctx.send(|r| r.content(msg)).await;
+        },
+        Err(err) => {
+            let _ = ctx.send(|r| r.content(err.to_string())).await;
+        },
+    };
+
+    Ok(())
+}
+
+#[poise::command(slash_command, guild_only)]
+pub async fn shuffle(
+    ctx: crate::Context<'_>,
+) -> Result<(), crate::Error> {
+    let control = Control::from_context(ctx)?;
+
+    match queue_shuffle(&control).await {
+        Ok(msg) => {
+            let _ = ctx.send(|r| r.content(msg)).await;
+        },
+        Err(err) => {
+            let _ = ctx.send(|r| r.content(err.to_string())).await;
+        },
+    };
+
+    Ok(())
+}
+
+#[poise::command(slash_command, guild_only, rename = "loop")]
+pub async fn loop_mode(
+    ctx: crate::Context<'_>,
+) -> Result<(), crate::Error> {
+    let control = Control::from_context(ctx)?;
+
+    match queue_loop(&control).await {
+        Ok(msg) => {
+            let _ = ctx.send(|r| r.content(msg)).await;
+        },
+        Err(err) => {
+            let _ = ctx.send(|r| r.content(err.to_string())).await;
+        },
+    };
+
+    Ok(())
+}
+
+/// Keep playing related songs when the queue runs out
+#[poise::command(slash_command, guild_only)]
+pub async fn autoplay(
+    ctx: crate::Context<'_>,
+    #[description = "Play related songs when the queue runs out"]
+    enabled: bool,
+) -> Result<(), crate::Error> {
+    let control = Control::from_context(ctx)?;
+
+    match queue_autoplay(&control, enabled).await {
+        Ok(msg) => {
+            let _ = ctx.send(|r| r.content(msg)).await;
+        },
+        Err(err) => {
+            let _ = ctx.send(|r| r.content(err.to_string())).await;
+        },
+    };
+
+    Ok(())
+}
+
+#[poise::command(slash_command, guild_only)]
+pub async fn queue(
+    ctx: crate::Context<'_>,
+) -> Result<(), crate::Error> {
+    let guild_id = ctx.guild_id().ok_or_else( || Box::new(crate::CommandError("Cannot get Guild ID".into())))?;
+    let playlist = ctx.data().system_playlist.read().await;
+    let items = playlist.items(guild_id);
+
+    if items.is_empty() {
+        let _ = ctx.send(|r| r.content("The queue is empty")).await;
+        return Ok(());
+    }
+
+    let mut lines: Vec<String> = items.iter().take(10).enumerate().map(|(index, item)| {
+        match item.requester {
+            Some(requester) => format!("{}. {} - <@{}>", index + 1, item.title, requester),
+            None => format!("{}. {}", index + 1, item.title),
         }
+    }).collect();
+    if items.len() > 10 {
+        lines.push(format!("And {} more", items.len() - 10));
     }
 
+    let _ = ctx.send(|r| r.embed(|e| e.title("Queue").description(lines.join("\n")))).await;
+
     Ok(())
 }
 
 #[poise::command(slash_command, guild_only)]
-pub async fn skip(
+pub async fn remove(
     ctx: crate::Context<'_>,
+    #[description = "Position of the song in the queue"]
+    position: usize,
 ) -> Result<(), crate::Error> {
-    match song_skip(ctx).await {
-        Ok(msg) => { 
+    let control = Control::from_context(ctx)?;
+
+    match queue_remove(&control, position).await {
+        Ok(msg) => {
             let _ = ctx.send(|r| r.content(msg)).await;
         },
         Err(err) => {
@@ -289,4 +1152,79 @@ pub async fn skip(
     };
 
     Ok(())
-}
\ No newline at end of file
+}
+/// Change the audio filters, options that are not set keep their value
+#[allow(clippy::too_many_arguments)]
+#[poise::command(slash_command, guild_only)]
+pub async fn filter(
+    ctx: crate::Context<'_>,
+    #[description = "Filter preset"]
+    preset: Option<FilterPreset>,
+    #[description = "Speed multiplier, from 0.5 to 2"]
+    #[min = 0.5]
+    #[max = 2.0]
+    speed: Option<f64>,
+    #[description = "Pitch multiplier, from 0.5 to 2"]
+    #[min = 0.5]
+    #[max = 2.0]
+    pitch: Option<f64>,
+    #[description = "Bass gain in dB, from -20 to 20"]
+    #[min = -20]
+    #[max = 20]
+    bass: Option<i32>,
+    #[description = "Mid gain in dB, from -20 to 20"]
+    #[min = -20]
+    #[max = 20]
+    mid: Option<i32>,
+    #[description = "Treble gain in dB, from -20 to 20"]
+    #[min = -20]
+    #[max = 20]
+    treble: Option<i32>,
+    #[description = "Bring every song to the same loudness"]
+    normalize: Option<bool>,
+    #[description = "Remove every filter"]
+    reset: Option<bool>,
+) -> Result<(), crate::Error> {
+    let control = Control::from_context(ctx)?;
+
+    let mut filter = if reset == Some(true) {
+        AudioFilter::new()
+    } else {
+        ctx.data().system_playlist.write().await.player(control.guild_id).filter.clone()
+    };
+    if let Some(preset) = preset {
+        filter.preset = preset;
+    }
+    if let Some(speed) = speed {
+        filter.speed = speed as f32;
+    }
+    if let Some(pitch) = pitch {
+        filter.pitch = pitch as f32;
+    }
+    if let Some(bass) = bass {
+        filter.bass = bass as f32;
+    }
+    if let Some(mid) = mid {
+        filter.mid = mid as f32;
+    }
+    if let Some(treble) = treble {
+        filter.treble = treble as f32;
+    }
+    if let Some(normalize) = normalize {
+        filter.normalize = normalize;
+    }
+
+    // Restarting the song fetches it again
+    ctx.defer().await?;
+
+    match song_filter(&control, filter).await {
+        Ok(msg) => {
+            let _ = ctx.send(|r| r.content(msg)).await;
+        },
+        Err(err) => {
+            let _ = ctx.send(|r| r.content(err.to_string())).await;
+        },
+    };
+
+    Ok(())
+}
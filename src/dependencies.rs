@@ -0,0 +1,178 @@
+use std::env;
+use std::fmt;
+use std::process::{Command, Stdio};
+use std::sync::Mutex;
+
+use anyhow::anyhow;
+
+pub const YTDLP: &str = "yt-dlp";
+pub const FFMPEG: &str = "ffmpeg";
+pub const FFPROBE: &str = "ffprobe";
+
+/// Environment variable with what to do when a required program is missing or too old, `strict` (default) or `degrade`
+pub const DEPENDENCY_CHECK_ENV: &str = "DEPENDENCY_CHECK";
+
+struct Dependency {
+    name: &'static str,
+    version_flag: &'static str,
+    /// Older versions cannot play from YouTube anymore or lack filters the bot uses
+    minimum: &'static [u32],
+    /// Without it nothing can be played, ffprobe is only used for local files and attachments
+    required: bool,
+}
+
+const DEPENDENCIES: [Dependency; 3] = [
+    Dependency { name: YTDLP, version_flag: "--version", minimum: &[2023, 3, 4], required: true },
+    Dependency { name: FFMPEG, version_flag: "-version", minimum: &[4, 3], required: true },
+    Dependency { name: FFPROBE, version_flag: "-version", minimum: &[4, 3], required: false },
+];
+
+/// Programs that were not found at startup, the features that need them answer with an error
+static MISSING: Mutex<Vec<&'static str>> = Mutex::new(Vec::new());
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Status {
+    Found(String),
+    /// The version could not be read, like in git builds of ffmpeg
+    UnknownVersion,
+    Outdated(String),
+    Missing,
+}
+
+pub struct Report {
+    pub name: &'static str,
+    pub required: bool,
+    pub minimum: String,
+    pub status: Status,
+}
+
+impl Report {
+    /// The program can be used, an unknown version gets the benefit of the doubt
+    pub fn is_usable(&self) -> bool {
+        matches!(self.status, Status::Found(_) | Status::UnknownVersion)
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.status {
+            Status::Found(version) => write!(f, "{}: {}", self.name, version),
+            Status::UnknownVersion => write!(f, "{}: unknown version", self.name),
+            Status::Outdated(version) => write!(f, "{}: {} is older than {}", self.name, version, self.minimum),
+            Status::Missing => write!(f, "{}: not found", self.name),
+        }
+    }
+}
+
+fn format_version(version: &[u32]) -> String {
+    version.iter().map(|part| part.to_string()).collect::<Vec<_>>().join(".")
+}
+
+/// Read the version from the first line of a version output, like `2023.03.04` or `ffmpeg version 4.4.2-0ubuntu0.22.04.1 Copyright...`
+pub fn parse_version(output: &str) -> Option<Vec<u32>> {
+    let line = output.lines().next()?;
+    let mut words = line.split_whitespace();
+    let word = if line.contains(" version ") {
+        words.find(|word| *word == "version").and_then(|_| words.next())?
+    } else {
+        words.next()?
+    };
+
+    // Some builds prefix the version with n, like n6.0
+    let word = word.strip_prefix('n').unwrap_or(word);
+    let number: String = word.chars().take_while(|c| c.is_ascii_digit() || *c == '.').collect();
+    if number.is_empty() {
+        return None;
+    }
+
+    number.split('.').filter(|part| !part.is_empty()).map(|part| part.parse().ok()).collect()
+}
+
+fn probe_dependency(dependency: &Dependency) -> Report {
+    let output = Command::new(dependency.name)
+        .arg(dependency.version_flag)
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .output();
+
+    let status = match output {
+        Ok(output) if output.status.success() => {
+            match parse_version(&String::from_utf8_lossy(&output.stdout)) {
+                Some(version) if version.as_slice() < dependency.minimum => Status::Outdated(format_version(&version)),
+                Some(version) => Status::Found(format_version(&version)),
+                None => Status::UnknownVersion,
+            }
+        },
+        _ => Status::Missing,
+    };
+
+    Report {
+        name: dependency.name,
+        required: dependency.required,
+        minimum: format_version(dependency.minimum),
+        status,
+    }
+}
+
+/// Run every program the bot needs and read its version
+pub fn probe() -> Vec<Report> {
+    DEPENDENCIES.iter().map(probe_dependency).collect()
+}
+
+/// Check the programs at startup and remember the missing ones, false if the bot should not start
+pub fn preflight() -> bool {
+    let strict = env::var(DEPENDENCY_CHECK_ENV).map(|mode| mode != "degrade").unwrap_or(true);
+    let reports = probe();
+
+    let mut missing = MISSING.lock().unwrap_or_else(|err| err.into_inner());
+    missing.clear();
+
+    let mut ready = true;
+    for report in &reports {
+        match (&report.status, report.is_usable()) {
+            (Status::UnknownVersion, _) => tracing::warn!("{}", report),
+            (_, true) => tracing::info!("{}: OK", report),
+            (_, false) if report.required && strict => {
+                tracing::error!("{}", report);
+                ready = false;
+            },
+            (_, false) => tracing::warn!("{}, the features that need it are disabled", report),
+        }
+
+        if matches!(report.status, Status::Missing) {
+            missing.push(report.name);
+        }
+    }
+
+    ready
+}
+
+/// Fail with a clear error instead of trying to start a program that is not installed
+pub fn ensure(program: &str) -> anyhow::Result<()> {
+    if MISSING.lock().unwrap_or_else(|err| err.into_inner()).contains(&program) {
+        return Err(anyhow!("{} is not installed", program));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_versions() {
+        assert_eq!(parse_version("2023.03.04\n"), Some(vec![2023, 3, 4]));
+        assert_eq!(parse_version("ffmpeg version 4.4.2-0ubuntu0.22.04.1 Copyright (c) 2000-2021 the FFmpeg developers\nbuilt with gcc"), Some(vec![4, 4, 2]));
+        assert_eq!(parse_version("ffprobe version n6.0 Copyright (c) 2007-2023"), Some(vec![6, 0]));
+        assert_eq!(parse_version("ffmpeg version N-109876-g1c5ac7bb Copyright (c) 2000-2023"), None);
+        assert_eq!(parse_version(""), None);
+    }
+
+    #[test]
+    fn compares_with_the_minimum() {
+        let minimum: &[u32] = &[4, 3];
+        assert!(vec![4, 2, 9].as_slice() < minimum);
+        assert!(vec![4, 3].as_slice() >= minimum);
+        assert!(vec![6].as_slice() >= minimum);
+    }
+}
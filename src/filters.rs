@@ -0,0 +1,206 @@
+use crate::loudness::{self, LoudnessMeasurement};
+
+/// Output sample rate of the ffmpeg stage, presets that change the pitch resample around it
+const SAMPLE_RATE: u32 = 48000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, poise::ChoiceParameter)]
+pub enum FilterPreset {
+    #[name = "off"]
+    Off,
+    #[name = "bassboost"]
+    BassBoost,
+    /// Faster and higher
+    #[name = "nightcore"]
+    Nightcore,
+    /// Slower and lower
+    #[name = "vaporwave"]
+    Vaporwave,
+    /// The sound moves around the listener
+    #[name = "8d"]
+    EightD,
+    /// Removes what is in the center of the stereo image, usually the vocals
+    #[name = "karaoke"]
+    Karaoke,
+}
+
+impl FilterPreset {
+    /// How much faster the preset plays the source, it also changes the pitch
+    fn rate(&self) -> f32 {
+        match self {
+            FilterPreset::Nightcore => 1.25,
+            FilterPreset::Vaporwave => 0.8,
+            _ => 1.0,
+        }
+    }
+
+    fn graph(&self) -> Option<String> {
+        match self {
+            FilterPreset::Off => None,
+            FilterPreset::BassBoost => Some("bass=g=10".to_string()),
+            FilterPreset::Nightcore | FilterPreset::Vaporwave => Some(resample(self.rate())),
+            FilterPreset::EightD => Some("apulsator=hz=0.125".to_string()),
+            FilterPreset::Karaoke => Some("pan=stereo|c0=c0-c1|c1=c1-c0".to_string()),
+        }
+    }
+}
+
+/// Play the source `rate` times faster, changing its pitch with it
+fn resample(rate: f32) -> String {
+    format!("aresample={0},asetrate={0}*{1},aresample={0}", SAMPLE_RATE, rate)
+}
+
+/// Audio filters of a guild, applied on the ffmpeg stage of every track
+#[derive(Debug, Clone, PartialEq)]
+pub struct AudioFilter {
+    pub preset: FilterPreset,
+    /// Tempo multiplier, keeps the pitch
+    pub speed: f32,
+    /// Pitch multiplier, keeps the tempo
+    pub pitch: f32,
+    /// EQ gains in dB
+    pub bass: f32,
+    pub mid: f32,
+    pub treble: f32,
+    /// Bring every track to the same loudness
+    pub normalize: bool,
+}
+
+pub const MIN_MULTIPLIER: f32 = 0.5;
+pub const MAX_MULTIPLIER: f32 = 2.0;
+pub const MAX_GAIN: f32 = 20.0;
+
+impl AudioFilter {
+    pub fn new() -> Self {
+        Self {
+            preset: FilterPreset::Off,
+            speed: 1.0,
+            pitch: 1.0,
+            bass: 0.0,
+            mid: 0.0,
+            treble: 0.0,
+            normalize: false,
+        }
+    }
+
+    /// Keep every parameter in the range ffmpeg accepts
+    pub fn clamped(self) -> Self {
+        Self {
+            speed: self.speed.clamp(MIN_MULTIPLIER, MAX_MULTIPLIER),
+            pitch: self.pitch.clamp(MIN_MULTIPLIER, MAX_MULTIPLIER),
+            bass: self.bass.clamp(-MAX_GAIN, MAX_GAIN),
+            mid: self.mid.clamp(-MAX_GAIN, MAX_GAIN),
+            treble: self.treble.clamp(-MAX_GAIN, MAX_GAIN),
+            ..self
+        }
+    }
+
+    pub fn is_off(&self) -> bool {
+        self.graph(None).is_none()
+    }
+
+    /// Seconds of the source played in a second of output
+    pub fn tempo(&self) -> f32 {
+        self.preset.rate() * self.speed
+    }
+
+    /// The -af argument of ffmpeg, None when no filter is active
+    ///
+    /// Normalization uses the loudness measured on the cached file when there is one
+    pub fn graph(&self, loudness: Option<&LoudnessMeasurement>) -> Option<String> {
+        let mut filters = Vec::new();
+
+        // Measurements are of the original audio, normalize before anything changes it
+        if self.normalize {
+            filters.push(loudness::filter(loudness));
+        }
+        filters.extend(self.preset.graph());
+
+        if self.pitch != 1.0 {
+            // Resampling changes both pitch and tempo, atempo brings the tempo back
+            filters.push(format!("{},atempo={}", resample(self.pitch), 1.0 / self.pitch));
+        }
+        if self.speed != 1.0 {
+            filters.push(format!("atempo={}", self.speed));
+        }
+        if self.bass != 0.0 {
+            filters.push(format!("bass=g={}", self.bass));
+        }
+        if self.mid != 0.0 {
+            filters.push(format!("equalizer=f=1000:t=q:w=1:g={}", self.mid));
+        }
+        if self.treble != 0.0 {
+            filters.push(format!("treble=g={}", self.treble));
+        }
+
+        if filters.is_empty() {
+            None
+        } else {
+            Some(filters.join(","))
+        }
+    }
+
+    /// Human readable list of the active filters
+    pub fn describe(&self) -> String {
+        let mut parts = Vec::new();
+
+        if self.normalize {
+            parts.push("loudness normalization".to_string());
+        }
+        if self.preset != FilterPreset::Off {
+            parts.push(format!("preset {}", self.preset.name()));
+        }
+        if self.speed != 1.0 {
+            parts.push(format!("speed {}x", self.speed));
+        }
+        if self.pitch != 1.0 {
+            parts.push(format!("pitch {}x", self.pitch));
+        }
+        for (band, gain) in [("bass", self.bass), ("mid", self.mid), ("treble", self.treble)] {
+            if gain != 0.0 {
+                parts.push(format!("{} {:+}dB", band, gain));
+            }
+        }
+
+        if parts.is_empty() {
+            "No filters".to_string()
+        } else {
+            parts.join(", ")
+        }
+    }
+}
+
+impl Default for AudioFilter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_filter_is_off() {
+        assert!(AudioFilter::default().is_off());
+        assert_eq!(AudioFilter::default().tempo(), 1.0);
+    }
+
+    #[test]
+    fn filters_are_chained() {
+        let filter = AudioFilter { preset: FilterPreset::BassBoost, speed: 1.5, bass: 3.0, ..AudioFilter::default() };
+        assert_eq!(filter.graph(None).unwrap(), "bass=g=10,atempo=1.5,bass=g=3");
+        assert_eq!(filter.tempo(), 1.5);
+
+        let filter = AudioFilter { normalize: true, ..AudioFilter::default() };
+        assert_eq!(filter.graph(None).unwrap(), "loudnorm=I=-16:TP=-1.5:LRA=11");
+
+        let filter = AudioFilter { preset: FilterPreset::Nightcore, speed: 2.0, ..AudioFilter::default() };
+        assert_eq!(filter.tempo(), 2.5);
+    }
+
+    #[test]
+    fn parameters_are_clamped() {
+        let filter = AudioFilter { speed: 10.0, pitch: 0.1, treble: -40.0, ..AudioFilter::default() }.clamped();
+        assert_eq!((filter.speed, filter.pitch, filter.treble), (MAX_MULTIPLIER, MIN_MULTIPLIER, -MAX_GAIN));
+    }
+}
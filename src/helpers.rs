@@ -1,50 +1,46 @@
 use std::fs::File;
-use std::{fs};
-use std::path::Path;
+use std::{fs, process};
+use std::path::{Path, PathBuf};
 use std::io::{Error, ErrorKind, Result, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
 
-extern crate termion;
-use termion::{color};
+use crate::dependencies;
+use crate::layout;
 
 pub fn setup_system() -> Result<()> {
     let setup_dirs_complete = setup_directories_structure();
-    if setup_dirs_complete {
-        Ok(())
-    }else{
-        Err(Error::new(ErrorKind::Other, "Checks for directory structure failed"))
+    if !setup_dirs_complete {
+        return Err(Error::other("Checks for directory structure failed"));
     }
-}
 
-fn setup_directories_structure() -> bool{
-    println!("{}- Initializing directories", color::Fg(color::Yellow));
-    // Root directory
-    if !graceful_mkdir("data") {return false;}
+    tracing::info!("Checking programs");
+    if !dependencies::preflight() {
+        return Err(Error::other(format!("Required programs are missing or too old, set {}=degrade to start anyway", dependencies::DEPENDENCY_CHECK_ENV)));
+    }
 
-    // Cache directories
-    if !graceful_mkdir("data/cache") {return false;}
-    if !graceful_mkdir("data/cache/media") {return false;}
-    if !graceful_mkdir("data/cache/meta") {return false;}
+    Ok(())
+}
 
-    true
+fn setup_directories_structure() -> bool{
+    let layout = layout::layout();
+    tracing::info!(root = %layout.root().display(), cache = %layout.cache().display(), "Initializing directories");
+    layout.directories().iter().all(|dir| graceful_mkdir(dir))
 }
 
-pub fn graceful_mkdir(dir_path: &str) -> bool {
-    let current_path = std::env::current_dir().unwrap();
-    let current_path = current_path.as_path().display();
-    let path = Path::new(dir_path);
+pub fn graceful_mkdir(path: &Path) -> bool {
     // Get the metadata attributes of a file/dir and check if it exists or something is wrong
     match fs::metadata(path) {
         Ok(attributes) => {
             if attributes.is_dir() {
                 if attributes.permissions().readonly() {
-                    println!("{}\t{}/{}: Is not writable{}", color::Fg(color::Red), current_path, path.display(), color::Fg(color::Reset));
+                    tracing::error!("{}: Is not writable", path.display());
                     return false;
                 }
-                println!("{}\t{}/{}: OK{}", color::Fg(color::Green), current_path, path.display(), color::Fg(color::Reset));
+                tracing::debug!("{}: OK", path.display());
                 true
             }
             else {
-                println!("{}\t{}/{}: Is not a directory{}", color::Fg(color::Red), current_path,path.display(), color::Fg(color::Reset));
+                tracing::error!("{}: Is not a directory", path.display());
                 false
             }
         },
@@ -52,21 +48,21 @@ pub fn graceful_mkdir(dir_path: &str) -> bool {
             // Get the error kind to compare later
             match error.kind() {
                 ErrorKind::NotFound => {
-                    // The dir not exists, create dir
-                    let create_result = fs::create_dir(path);
+                    // The dir not exists, create it with its parents, the data root can be anywhere
+                    let create_result = fs::create_dir_all(path);
                     match create_result {
                         Ok(_) => {
-                            println!("{}\t{}/{}: Created{}", color::Fg(color::Cyan), current_path,path.display(), color::Fg(color::Reset));
+                            tracing::info!("{}: Created", path.display());
                             true
                         },
                         Err(create_error) =>  {
-                            println!("{}\t{}/{}: {}{}", color::Fg(color::Red), current_path,path.display(), create_error, color::Fg(color::Reset));
+                            tracing::error!("{}: {}", path.display(), create_error);
                             false
                         }
                     }
                 },
                 _ => {
-                    println!("{}\tError not managed {}{}", color::Fg(color::Red), error, color::Fg(color::Reset));
+                    tracing::error!("{}: Error not managed {}", path.display(), error);
                     false
                 }
             }
@@ -74,53 +70,127 @@ pub fn graceful_mkdir(dir_path: &str) -> bool {
     }
 }
 
-pub fn _write_json(file_path: &str, content: String) -> Result<()>
+/// Extension of the files being written, they are renamed to their final name once complete
+pub const TEMP_EXTENSION: &str = "tmp";
+
+/// Temporary files of this process, so concurrent writes of the same file do not share one
+static TEMP_FILES: AtomicU64 = AtomicU64::new(0);
+
+/// A temporary path next to the target, a rename from it to the target never crosses devices
+pub fn temp_path(target: &Path) -> PathBuf {
+    let name = target.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default();
+    let count = TEMP_FILES.fetch_add(1, Ordering::Relaxed);
+    target.with_file_name(format!("{}.{}-{}.{}", name, process::id(), count, TEMP_EXTENSION))
+}
+
+/// Flush the directory entry of a renamed file, without it the rename can be lost on a crash
+fn sync_parent(path: &Path) -> Result<()> {
+    match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => File::open(dir)?.sync_all(),
+        _ => Ok(()),
+    }
+}
+
+/// Write the file so a crash leaves either the old or the new content, never a mix
+///
+/// The content goes to a temporary file in the same directory, it is flushed to disk and then renamed over the target
+pub fn write_atomic(path: &Path, content: &[u8]) -> Result<()> {
+    let temp = temp_path(path);
+    let written = File::create(&temp)
+        .and_then(|mut file| {
+            file.write_all(content)?;
+            file.sync_all()
+        })
+        .and_then(|_| fs::rename(&temp, path));
+
+    if let Err(error) = written {
+        let _ = fs::remove_file(&temp);
+        return Err(error);
+    }
+    sync_parent(path)
+}
+
+/// Write the JSON through [`write_atomic`], a crash leaves either the old or the new content
+pub fn write_json(file_path: impl AsRef<Path>, content: String) -> Result<()>
 {
-    // Current dir to display in log
-    let current_path = std::env::current_dir().unwrap();
-    let current_path = current_path.as_path().display();
-
-    // Create the path
-    let path = Path::new(file_path);
-    
-    // Create the file, truncates if it exist
-    match File::create(path) {
-        Ok(mut file) => {
-            // Try to write the content
-            match file.write_all(content.as_bytes()) {
-                Ok(_) => {
-                    // Content written
-                    //println!("{} File Writed: {}/{}", color::Fg(color::Cyan), current_path, path.display());
-                    Ok(())
-                },
-                Err(error) => {
-                    // Pretty error, Cannot be written
-                    Err(Error::new(
-                        error.kind(), 
-                        format!("{}File cannot be written: {}/{}", color::Fg(color::Red), current_path, path.display())
-                    ))
-                }
-            }
-        },
-        Err(error) => {
-            // Pretty error, Cannot be created
-            Err(Error::new(
-                error.kind(), 
-                format!("{}File: {}/{} Cannot be created {:?}", color::Fg(color::Red), current_path, path.display(), error)
-            ))
-        }
+    let path = file_path.as_ref();
+
+    write_atomic(path, content.as_bytes()).map_err(|error| {
+        // Pretty error, Cannot be written
+        Error::new(
+            error.kind(),
+            format!("File cannot be written: {} {}", path.display(), error)
+        )
+    })
+}
+
+/// Move a file, renaming it when both paths are in the same filesystem
+///
+/// Across devices the file is copied next to the target, flushed and renamed into place before the origin is removed,
+/// so the target is never left half copied
+pub fn move_file(origin_path: &Path, target_path: &Path) -> Result<()> {
+    match fs::rename(origin_path, target_path) {
+        Err(error) if error.kind() == ErrorKind::CrossesDevices => (),
+        result => return result.and_then(|_| sync_parent(target_path)),
+    }
+
+    let temp = temp_path(target_path);
+    let copied = fs::copy(origin_path, &temp)
+        .and_then(|_| File::open(&temp)?.sync_all())
+        .and_then(|_| fs::rename(&temp, target_path));
+
+    if let Err(error) = copied {
+        let _ = fs::remove_file(&temp);
+        return Err(error);
     }
+    sync_parent(target_path)?;
+    fs::remove_file(origin_path)
 }
 
-#[allow(dead_code)]
-pub fn move_file(origin_path: &str, target_path: &str) -> Result<()> {
-    match fs::copy(origin_path, target_path) {
-        Ok(_) => {
-            match fs::remove_file(origin_path) {
-                Ok(_) => Ok(()),
-                Err(error) => Err(error),
-            }
-        },
-        Err(error) => Err(error),
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("potv2-{}-{}", name, process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn file_names(dir: &Path) -> Vec<String> {
+        let mut names: Vec<String> = fs::read_dir(dir).unwrap().flatten().map(|entry| entry.file_name().to_string_lossy().into_owned()).collect();
+        names.sort();
+        names
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn writes_replace_the_file() {
+        let dir = test_dir("write");
+        let path = dir.join("1.json");
+
+        write_json(&path, "old".to_string()).unwrap();
+        write_json(&path, "new".to_string()).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "new");
+        assert_eq!(file_names(&dir), vec!["1.json"]);
+
+        // Missing directories fail without leaving anything behind
+        assert!(write_atomic(&dir.join("missing").join("1.json"), b"new").is_err());
+        assert_eq!(file_names(&dir), vec!["1.json"]);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn moves_files() {
+        let dir = test_dir("move");
+        let origin = dir.join("origin");
+        let target = dir.join("target");
+
+        fs::write(&origin, "media").unwrap();
+        move_file(&origin, &target).unwrap();
+        assert_eq!(fs::read_to_string(&target).unwrap(), "media");
+        assert_eq!(file_names(&dir), vec!["target"]);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
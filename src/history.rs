@@ -0,0 +1,205 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use poise::serenity_prelude::GuildId;
+use tokio::runtime::Handle;
+
+use crate::helpers;
+use crate::layout::{layout, DataLayout};
+use crate::pot::PlaylistItem;
+
+
+/// Entries kept per guild, older entries are dropped
+const MAX_ENTRIES: usize = 500;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PlayOutcome {
+    Finished,
+    Skipped,
+    /// The player was stopped while the item was playing
+    Stopped,
+}
+
+impl PlayOutcome {
+    pub fn label(&self) -> &'static str {
+        match self {
+            PlayOutcome::Finished => "finished",
+            PlayOutcome::Skipped => "skipped",
+            PlayOutcome::Stopped => "stopped",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HistoryEntry {
+    pub item: PlaylistItem,
+    /// Unix timestamp of when the item stopped playing
+    pub played_at: u64,
+    pub outcome: PlayOutcome,
+}
+
+/// Histories waiting to be saved, a guild is in the map while its writer runs, with the next content to write if any
+type PendingWrites = Arc<Mutex<HashMap<u64, Option<String>>>>;
+
+/// Played items of every guild, stored as history/{guild_id}.json in the data directory
+pub struct PlayHistory {
+    dir: PathBuf,
+    guilds: HashMap<u64, Vec<HistoryEntry>>,
+    pending: PendingWrites,
+}
+
+impl PlayHistory {
+    pub fn new() -> Self {
+        Self::with_dir(layout().history())
+    }
+
+    /// History stored in `dir` instead of the history directory of the data layout
+    pub fn with_dir(dir: PathBuf) -> Self {
+        Self {
+            dir,
+            guilds: HashMap::new(),
+            pending: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    fn path(dir: &Path, guild: GuildId) -> PathBuf {
+        DataLayout::guild_file(dir, guild.0)
+    }
+
+    /// Get the guild history, loading it from disk the first time, oldest entry first
+    pub fn entries(&mut self, guild: GuildId) -> &Vec<HistoryEntry> {
+        self.guild_entries(guild)
+    }
+
+    fn guild_entries(&mut self, guild: GuildId) -> &mut Vec<HistoryEntry> {
+        let path = Self::path(&self.dir, guild);
+        self.guilds.entry(*guild.as_u64()).or_insert_with(|| {
+            match fs::read_to_string(path) {
+                Ok(json) => serde_json::from_str(&json).unwrap_or_else(|err| {
+                    tracing::error!(guild_id = guild.0, "Cannot parse history: {}", err);
+                    Vec::new()
+                }),
+                Err(_) => Vec::new(),
+            }
+        })
+    }
+
+    /// Add an entry to the guild history and save it
+    pub fn record(&mut self, guild: GuildId, item: PlaylistItem, outcome: PlayOutcome) {
+        let played_at = SystemTime::now().duration_since(UNIX_EPOCH).map(|time| time.as_secs()).unwrap_or(0);
+
+        let entries = self.guild_entries(guild);
+        entries.push(HistoryEntry { item, played_at, outcome });
+        if entries.len() > MAX_ENTRIES {
+            let overflow = entries.len() - MAX_ENTRIES;
+            entries.drain(..overflow);
+        }
+
+        match serde_json::to_string(entries) {
+            Ok(json) => self.save(guild, json),
+            Err(err) => tracing::error!(guild_id = guild.0, "Cannot save history: {}", err),
+        }
+    }
+
+    /// Save the history without blocking the caller, it runs while the playlist lock is held
+    ///
+    /// Histories recorded while the previous one is being written are coalesced into a single write
+    fn save(&self, guild: GuildId, json: String) {
+        {
+            let mut pending = self.pending.lock().unwrap_or_else(|err| err.into_inner());
+            if let Some(next) = pending.get_mut(&guild.0) {
+                *next = Some(json);
+                return;
+            }
+            pending.insert(guild.0, None);
+        }
+
+        let pending = self.pending.clone();
+        let path = Self::path(&self.dir, guild);
+        let write = move || Self::write_pending(pending, &path, guild, json);
+        match Handle::try_current() {
+            Ok(handle) => { handle.spawn_blocking(write); },
+            Err(_) => write(),
+        }
+    }
+
+    /// Write the history, then whatever was recorded meanwhile, so the file never goes back to older content
+    fn write_pending(pending: PendingWrites, path: &Path, guild: GuildId, mut json: String) {
+        loop {
+            if let Err(err) = helpers::write_json(path, json) {
+                tracing::error!(guild_id = guild.0, "Cannot save history: {}", err);
+            }
+
+            let mut pending = pending.lock().unwrap_or_else(|err| err.into_inner());
+            match pending.get_mut(&guild.0).and_then(Option::take) {
+                Some(next) => json = next,
+                None => {
+                    pending.remove(&guild.0);
+                    return;
+                },
+            }
+        }
+    }
+}
+
+impl Default for PlayHistory {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("potv2-history-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn record_keeps_the_last_entries() {
+        let dir = test_dir("trim");
+        let guild = GuildId(1);
+        let mut history = PlayHistory::with_dir(dir.clone());
+
+        for index in 0..MAX_ENTRIES + 10 {
+            history.record(guild, PlaylistItem::for_test("youtube", &index.to_string(), "song"), PlayOutcome::Finished);
+        }
+        assert_eq!(history.entries(guild).len(), MAX_ENTRIES);
+        assert_eq!(history.entries(guild)[0].item.id, "10");
+
+        // Without a runtime the history is written right away
+        let entries = PlayHistory::with_dir(dir.clone()).entries(guild).clone();
+        assert_eq!(entries.len(), MAX_ENTRIES);
+        assert_eq!(entries[MAX_ENTRIES - 1].item.id, (MAX_ENTRIES + 9).to_string());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn writes_during_a_write_are_coalesced() {
+        let dir = test_dir("coalesce");
+        let guild = GuildId(1);
+        let history = PlayHistory::with_dir(dir.clone());
+
+        // A writer is running for the guild, the saves only replace what it writes next
+        history.pending.lock().unwrap().insert(guild.0, None);
+        history.save(guild, "\"second\"".to_string());
+        history.save(guild, "\"third\"".to_string());
+        assert_eq!(history.pending.lock().unwrap().get(&guild.0), Some(&Some("\"third\"".to_string())));
+        assert!(!PlayHistory::path(&dir, guild).exists());
+
+        PlayHistory::write_pending(history.pending.clone(), &PlayHistory::path(&dir, guild), guild, "\"first\"".to_string());
+        assert_eq!(fs::read_to_string(PlayHistory::path(&dir, guild)).unwrap(), "\"third\"");
+        assert!(history.pending.lock().unwrap().is_empty());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,131 @@
+use std::env;
+use std::path::{Path, PathBuf};
+use std::sync::LazyLock;
+
+/// Environment variable with the directory the bot keeps its state in, `data` in the working directory by default
+pub const DATA_DIR_ENV: &str = "DATA_DIR";
+
+/// Environment variable with the directory of the media cache, `cache` in the data directory by default
+pub const CACHE_DIR_ENV: &str = "CACHE_DIR";
+
+static LAYOUT: LazyLock<DataLayout> = LazyLock::new(DataLayout::from_env);
+
+/// Where every file of the bot goes, the cache can be on another volume than the persistent state
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DataLayout {
+    root: PathBuf,
+    cache: PathBuf,
+}
+
+/// Layout of this process, read from the environment the first time it is used
+pub fn layout() -> &'static DataLayout {
+    &LAYOUT
+}
+
+impl DataLayout {
+    /// Relative directories are taken from `base`, so the layout does not change if the working directory does
+    pub fn new(base: &Path, root: Option<&str>, cache: Option<&str>) -> Self {
+        let root = base.join(root.unwrap_or("data"));
+        let cache = match cache {
+            Some(cache) => base.join(cache),
+            None => root.join("cache"),
+        };
+
+        Self { root, cache }
+    }
+
+    fn from_env() -> Self {
+        let base = env::current_dir().unwrap_or_default();
+        let root = env::var(DATA_DIR_ENV).ok().filter(|dir| !dir.is_empty());
+        let cache = env::var(CACHE_DIR_ENV).ok().filter(|dir| !dir.is_empty());
+
+        Self::new(&base, root.as_deref(), cache.as_deref())
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    pub fn cache(&self) -> &Path {
+        &self.cache
+    }
+
+    /// Downloaded media, stored as media/{extractor}/{id}
+    pub fn media(&self) -> PathBuf {
+        self.cache.join("media")
+    }
+
+    /// Metadata of the downloaded media, stored as meta/{extractor}/{id}.json
+    pub fn meta(&self) -> PathBuf {
+        self.cache.join("meta")
+    }
+
+    pub fn settings(&self) -> PathBuf {
+        self.root.join("settings")
+    }
+
+    pub fn history(&self) -> PathBuf {
+        self.root.join("history")
+    }
+
+    pub fn playlists(&self) -> PathBuf {
+        self.root.join("playlists")
+    }
+
+    pub fn library(&self) -> PathBuf {
+        self.root.join("library")
+    }
+
+    /// Queues saved when the bot was stopped
+    pub fn queues(&self) -> PathBuf {
+        self.root.join("queues")
+    }
+
+    /// Index saved by the last library scan
+    pub fn library_index(&self) -> PathBuf {
+        self.library().join("index.json")
+    }
+
+    /// File of a guild in one of the persistent state directories
+    pub fn guild_file(dir: &Path, guild_id: u64) -> PathBuf {
+        dir.join(format!("{}.json", guild_id))
+    }
+
+    /// Every directory of the layout, parents first
+    pub fn directories(&self) -> Vec<PathBuf> {
+        vec![
+            // Persistent state directories
+            self.root.clone(),
+            self.settings(),
+            self.history(),
+            self.playlists(),
+            self.library(),
+            self.queues(),
+            // Cache directories
+            self.cache.clone(),
+            self.media(),
+            self.meta(),
+        ]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn directories_are_absolute() {
+        let layout = DataLayout::new(Path::new("/srv/pot"), None, None);
+        assert_eq!(layout.root(), Path::new("/srv/pot/data"));
+        assert_eq!(layout.media(), Path::new("/srv/pot/data/cache/media"));
+        assert_eq!(layout.history(), Path::new("/srv/pot/data/history"));
+
+        let layout = DataLayout::new(Path::new("/srv/pot"), Some("/var/lib/pot"), Some("/var/cache/pot"));
+        assert_eq!(layout.settings(), Path::new("/var/lib/pot/settings"));
+        assert_eq!(layout.meta(), Path::new("/var/cache/pot/meta"));
+
+        let layout = DataLayout::new(Path::new("/srv/pot"), Some("state"), Some("cache"));
+        assert_eq!(layout.playlists(), Path::new("/srv/pot/state/playlists"));
+        assert_eq!(layout.media(), Path::new("/srv/pot/cache/media"));
+    }
+}
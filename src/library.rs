@@ -0,0 +1,340 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::time::UNIX_EPOCH;
+
+use anyhow::anyhow;
+use poise::serenity_prelude::RwLock;
+use tokio::task;
+
+use crate::dependencies::{self, FFPROBE};
+use crate::helpers;
+use crate::layout::layout;
+use crate::metrics;
+use crate::pot::PlaylistItem;
+
+/// Extractor of the items that come from the library
+pub const LOCAL_EXTRACTOR: &str = "local";
+
+/// Environment variable with the directory of the library
+pub const LIBRARY_DIR_ENV: &str = "LIBRARY_DIR";
+
+
+const AUDIO_EXTENSIONS: [&str; 9] = ["mp3", "flac", "ogg", "opus", "m4a", "aac", "wav", "wma", "webm"];
+
+/// A file of the library and its tags
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LibraryEntry {
+    pub path: String,
+    pub title: String,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub duration: Option<f32>,
+    /// Modification time of the file when it was indexed, unchanged files are not probed again
+    pub modified: u64,
+}
+
+impl LibraryEntry {
+    pub fn display_title(&self) -> String {
+        match &self.artist {
+            Some(artist) => format!("{} - {}", artist, self.title),
+            None => self.title.to_owned(),
+        }
+    }
+
+    pub fn to_item(&self) -> PlaylistItem {
+        PlaylistItem {
+            id: self.path.to_owned(),
+            title: self.display_title(),
+            original_url: self.path.to_owned(),
+            extractor: LOCAL_EXTRACTOR.to_string(),
+            thumbnail: None,
+            duration: self.duration,
+            playlist_id: None,
+            webpage_url: None,
+            is_live: Some(false),
+            was_live: None,
+            requester: None,
+            autoplay: false,
+        }
+    }
+
+    /// How well the entry matches every term of the query, None if a term is missing
+    fn score(&self, terms: &[String]) -> Option<usize> {
+        let title = self.title.to_lowercase();
+        let rest = format!("{} {} {}",
+            self.artist.as_deref().unwrap_or_default(),
+            self.album.as_deref().unwrap_or_default(),
+            self.path).to_lowercase();
+
+        terms.iter().try_fold(0, |score, term| {
+            if title.contains(term.as_str()) {
+                Some(score + 2)
+            } else if rest.contains(term.as_str()) {
+                Some(score + 1)
+            } else {
+                None
+            }
+        })
+    }
+}
+
+/// Index of the local music directory, stored in library/index.json in the data directory
+pub struct Library {
+    root: Option<PathBuf>,
+    entries: Vec<LibraryEntry>,
+}
+
+impl Library {
+    /// Load the index saved by the last scan, the library is disabled when no directory is configured
+    pub fn load() -> Self {
+        let root = std::env::var(LIBRARY_DIR_ENV).ok().map(PathBuf::from);
+
+        let entries = match (&root, fs::read_to_string(layout().library_index())) {
+            (Some(_), Ok(json)) => serde_json::from_str(&json).unwrap_or_else(|err| {
+                tracing::error!("Cannot parse the library index: {}", err);
+                Vec::new()
+            }),
+            _ => Vec::new(),
+        };
+
+        Self {
+            root,
+            entries
+        }
+    }
+
+    pub fn root(&self) -> Option<&Path> {
+        self.root.as_deref()
+    }
+
+    pub fn entries(&self) -> &[LibraryEntry] {
+        &self.entries
+    }
+
+    /// Best matches first
+    pub fn search(&self, query: &str, limit: usize) -> Vec<&LibraryEntry> {
+        let terms: Vec<String> = query.split_whitespace().map(|term| term.to_lowercase()).collect();
+        if terms.is_empty() {
+            return Vec::new();
+        }
+
+        let mut matches: Vec<(usize, &LibraryEntry)> = self.entries.iter()
+            .filter_map(|entry| entry.score(&terms).map(|score| (score, entry)))
+            .collect();
+        matches.sort_by(|(a_score, a), (b_score, b)| b_score.cmp(a_score).then_with(|| a.path.cmp(&b.path)));
+
+        matches.into_iter().take(limit).map(|(_, entry)| entry).collect()
+    }
+
+    /// Item of the best match of a /play library:<query>
+    pub fn resolve(&self, query: &str, requester: u64) -> anyhow::Result<Vec<PlaylistItem>> {
+        if self.root.is_none() {
+            return Err(anyhow!("The library is not configured"));
+        }
+
+        let mut item = self.search(query, 1).first()
+            .ok_or_else(|| anyhow!("Nothing in the library matches {}", query))?
+            .to_item();
+        item.requester = Some(requester);
+
+        Ok(vec![item])
+    }
+
+    /// Replace the index with the result of a scan and save it
+    pub fn replace(&mut self, entries: Vec<LibraryEntry>) -> std::io::Result<()> {
+        self.entries = entries;
+
+        let json = serde_json::to_string(&self.entries)?;
+        helpers::write_json(layout().library_index(), json)
+    }
+}
+
+/// Scan the library directory again and save the index, returns how many files it has
+///
+/// The index stays readable while the scan runs
+pub async fn rescan(library: &RwLock<Library>) -> anyhow::Result<usize> {
+    let (root, previous) = {
+        let library = library.read().await;
+        match library.root() {
+            Some(root) => (root.to_path_buf(), library.entries().to_vec()),
+            None => return Err(anyhow!("The library is not configured")),
+        }
+    };
+
+    let entries = task::spawn_blocking(move || scan(&root, &previous)).await?;
+    let len = entries.len();
+    library.write().await.replace(entries)?;
+
+    Ok(len)
+}
+
+fn modified(path: &Path) -> u64 {
+    fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+        .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+        .map(|modified| modified.as_secs())
+        .unwrap_or(0)
+}
+
+fn is_audio(path: &Path) -> bool {
+    path.extension()
+        .and_then(|extension| extension.to_str())
+        .map(|extension| AUDIO_EXTENSIONS.contains(&extension.to_lowercase().as_str()))
+        .unwrap_or(false)
+}
+
+fn audio_files(dir: &Path, files: &mut Vec<PathBuf>) {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(err) => {
+            tracing::warn!(path = %dir.display(), "Cannot read the directory: {}", err);
+            return;
+        },
+    };
+
+    for entry in entries.flatten() {
+        let path = entry.path();
+        match entry.file_type() {
+            Ok(file_type) if file_type.is_dir() => audio_files(&path, files),
+            Ok(file_type) if file_type.is_file() && is_audio(&path) => files.push(path),
+            _ => {},
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct ProbeOutput {
+    format: ProbeFormat,
+}
+
+#[derive(Deserialize)]
+struct ProbeFormat {
+    duration: Option<String>,
+    #[serde(default)]
+    tags: HashMap<String, String>,
+}
+
+/// Read the tags and duration of a file with ffprobe, the title falls back to the file name
+///
+/// Fails only when ffprobe cannot run, a file it cannot read gets an entry without tags or duration
+pub fn probe(path: &Path) -> anyhow::Result<LibraryEntry> {
+    dependencies::ensure(FFPROBE)?;
+
+    let output = Command::new(FFPROBE)
+        .args(["-v", "quiet", "-print_format", "json", "-show_format"])
+        .arg(path)
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .output()
+        .inspect_err(|_| metrics::spawn_failed(FFPROBE))
+        .map_err(|err| anyhow!("{} could not be started: {}", FFPROBE, err))?;
+
+    let format = serde_json::from_slice::<ProbeOutput>(&output.stdout).ok().map(|output| output.format);
+    Ok(LibraryEntry::from_format(path, format))
+}
+
+/// Entry of a file that could not be probed, titled after the file name
+pub fn untagged(path: &Path) -> LibraryEntry {
+    LibraryEntry::from_format(path, None)
+}
+
+impl LibraryEntry {
+    fn from_format(path: &Path, format: Option<ProbeFormat>) -> Self {
+        // Tag names are not consistent between containers
+        let tag = |name: &str| -> Option<String> {
+            format.as_ref()?.tags.iter()
+                .find(|(key, _)| key.eq_ignore_ascii_case(name))
+                .map(|(_, value)| value.trim().to_string())
+                .filter(|value| !value.is_empty())
+        };
+
+        LibraryEntry {
+            path: path.to_string_lossy().into_owned(),
+            title: tag("title").unwrap_or_else(|| path.file_stem().map(|stem| stem.to_string_lossy().into_owned()).unwrap_or_default()),
+            artist: tag("artist"),
+            album: tag("album"),
+            duration: format.as_ref().and_then(|format| format.duration.as_ref()).and_then(|duration| duration.parse::<f32>().ok()),
+            modified: modified(path),
+        }
+    }
+}
+
+/// Index every audio file under the root, files that did not change keep their previous entry
+///
+/// This blocks while ffprobe reads the new files
+pub fn scan(root: &Path, previous: &[LibraryEntry]) -> Vec<LibraryEntry> {
+    let previous: HashMap<&str, &LibraryEntry> = previous.iter().map(|entry| (entry.path.as_str(), entry)).collect();
+
+    let mut files = Vec::new();
+    audio_files(root, &mut files);
+    files.sort();
+
+    // Without ffprobe new files are still indexed, titled after their file name
+    let can_probe = match dependencies::ensure(FFPROBE) {
+        Ok(_) => true,
+        Err(err) => {
+            tracing::warn!("{}, new library files have no tags or duration", err);
+            false
+        },
+    };
+
+    files.iter().map(|path| {
+        let key = path.to_string_lossy();
+        match previous.get(key.as_ref()) {
+            Some(entry) if entry.modified == modified(path) => (*entry).clone(),
+            _ if !can_probe => untagged(path),
+            _ => probe(path).unwrap_or_else(|err| {
+                tracing::warn!(path = %path.display(), "Cannot read the tags: {:?}", err);
+                untagged(path)
+            }),
+        }
+    }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(path: &str, title: &str, artist: Option<&str>) -> LibraryEntry {
+        LibraryEntry {
+            path: path.to_string(),
+            title: title.to_string(),
+            artist: artist.map(|artist| artist.to_string()),
+            album: None,
+            duration: None,
+            modified: 0,
+        }
+    }
+
+    #[test]
+    fn search_needs_every_term_and_prefers_titles() {
+        let library = Library {
+            root: None,
+            entries: vec![
+                entry("/music/a.mp3", "Blue Monday", Some("New Order")),
+                entry("/music/b.mp3", "Monday Blues", None),
+                entry("/music/blue/c.mp3", "Ceremony", Some("New Order")),
+            ],
+        };
+
+        let titles: Vec<&str> = library.search("blue", 10).iter().map(|entry| entry.title.as_str()).collect();
+        assert_eq!(titles, ["Blue Monday", "Monday Blues", "Ceremony"]);
+
+        let titles: Vec<&str> = library.search("new order monday", 10).iter().map(|entry| entry.title.as_str()).collect();
+        assert_eq!(titles, ["Blue Monday"]);
+
+        assert!(library.search("  ", 10).is_empty());
+    }
+
+    #[test]
+    fn library_items_are_local() {
+        let item = entry("/music/a.mp3", "Blue Monday", Some("New Order")).to_item();
+        assert_eq!(item.extractor, LOCAL_EXTRACTOR);
+        assert_eq!(item.title, "New Order - Blue Monday");
+        assert_eq!(item.original_url, "/music/a.mp3");
+    }
+}
@@ -0,0 +1,100 @@
+use std::env;
+use std::io::IsTerminal;
+
+use poise::serenity_prelude as serenity;
+use poise::{ApplicationContext, BoxFuture, ContextMenuCommandAction, FrameworkError, PrefixContext};
+use tracing::Instrument;
+use tracing_subscriber::EnvFilter;
+
+use crate::{Data, Error};
+
+/// Environment variable with the log filter, like `info` or `warn,potv2=debug`
+pub const LOG_LEVEL_ENV: &str = "LOG_LEVEL";
+
+/// Environment variable with the log format, `json` or `text`
+pub const LOG_FORMAT_ENV: &str = "LOG_FORMAT";
+
+/// Our logs at info, the libraries only when something goes wrong
+const DEFAULT_FILTER: &str = "warn,potv2=info";
+
+/// Start logging to stdout, colors are only used when stdout is a terminal
+pub fn init() {
+    let filter = EnvFilter::try_from_env(LOG_LEVEL_ENV).unwrap_or_else(|_| EnvFilter::new(DEFAULT_FILTER));
+    let builder = tracing_subscriber::fmt().with_env_filter(filter);
+
+    match env::var(LOG_FORMAT_ENV).as_deref() {
+        Ok("json") => builder.json().with_current_span(true).with_span_list(true).init(),
+        _ => builder.with_ansi(std::io::stdout().is_terminal()).init(),
+    }
+}
+
+type PrefixAction = for<'a> fn(PrefixContext<'a, Data, Error>) -> BoxFuture<'a, Result<(), FrameworkError<'a, Data, Error>>>;
+type SlashAction = for<'a> fn(ApplicationContext<'a, Data, Error>) -> BoxFuture<'a, Result<(), FrameworkError<'a, Data, Error>>>;
+
+/// The actions generated by poise, kept in the command custom data while the traced ones run them
+struct CommandActions {
+    prefix: Option<PrefixAction>,
+    slash: Option<SlashAction>,
+    context_menu: Option<ContextMenuCommandAction<Data, Error>>,
+}
+
+fn actions(command: &poise::Command<Data, Error>) -> &CommandActions {
+    command.custom_data.downcast_ref().expect("command actions were not traced")
+}
+
+fn command_span(ctx: crate::Context<'_>) -> tracing::Span {
+    tracing::info_span!(
+        "command",
+        command = %ctx.command().qualified_name,
+        guild_id = ctx.guild_id().map(|guild_id| guild_id.0),
+        user_id = ctx.author().id.0,
+    )
+}
+
+fn traced_prefix(ctx: PrefixContext<'_, Data, Error>) -> BoxFuture<'_, Result<(), FrameworkError<'_, Data, Error>>> {
+    let action = actions(ctx.command).prefix.expect("traced a command without prefix action");
+    Box::pin(action(ctx).instrument(command_span(poise::Context::Prefix(ctx))))
+}
+
+fn traced_slash(ctx: ApplicationContext<'_, Data, Error>) -> BoxFuture<'_, Result<(), FrameworkError<'_, Data, Error>>> {
+    let action = actions(ctx.command).slash.expect("traced a command without slash action");
+    Box::pin(action(ctx).instrument(command_span(poise::Context::Application(ctx))))
+}
+
+fn traced_user(ctx: ApplicationContext<'_, Data, Error>, user: serenity::User) -> BoxFuture<'_, Result<(), FrameworkError<'_, Data, Error>>> {
+    let Some(ContextMenuCommandAction::User(action)) = actions(ctx.command).context_menu else {
+        unreachable!("traced a user context menu command without user action");
+    };
+    Box::pin(action(ctx, user).instrument(command_span(poise::Context::Application(ctx))))
+}
+
+fn traced_message(ctx: ApplicationContext<'_, Data, Error>, message: serenity::Message) -> BoxFuture<'_, Result<(), FrameworkError<'_, Data, Error>>> {
+    let Some(ContextMenuCommandAction::Message(action)) = actions(ctx.command).context_menu else {
+        unreachable!("traced a message context menu command without message action");
+    };
+    Box::pin(action(ctx, message).instrument(command_span(poise::Context::Application(ctx))))
+}
+
+/// Run every command, and its subcommands, inside a span with the command name, guild and user
+///
+/// poise has no hook around the command future, so the generated actions are moved to the command
+/// custom data and replaced by ones that instrument them
+pub fn trace_commands(commands: &mut [poise::Command<Data, Error>]) {
+    for command in commands {
+        let original = CommandActions {
+            prefix: command.prefix_action,
+            slash: command.slash_action,
+            context_menu: command.context_menu_action,
+        };
+
+        command.prefix_action = original.prefix.map(|_| traced_prefix as PrefixAction);
+        command.slash_action = original.slash.map(|_| traced_slash as SlashAction);
+        command.context_menu_action = original.context_menu.map(|action| match action {
+            ContextMenuCommandAction::User(_) => ContextMenuCommandAction::User(traced_user),
+            ContextMenuCommandAction::Message(_) => ContextMenuCommandAction::Message(traced_message),
+        });
+        command.custom_data = Box::new(original);
+
+        trace_commands(&mut command.subcommands);
+    }
+}
@@ -0,0 +1,126 @@
+use serde::{Deserialize, Serialize};
+use std::io::Read;
+use std::process::{Command, Stdio};
+
+use crate::dependencies::{self, FFMPEG};
+use crate::metrics;
+use crate::shutdown;
+
+/// EBU R128 targets, integrated loudness in LUFS, true peak in dBTP and loudness range in LU
+const TARGET_I: f32 = -16.0;
+const TARGET_TP: f32 = -1.5;
+const TARGET_LRA: f32 = 11.0;
+
+/// Values of the first loudnorm pass over a file, the second pass uses them to apply a fixed gain
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LoudnessMeasurement {
+    pub input_i: f32,
+    pub input_tp: f32,
+    pub input_lra: f32,
+    pub input_thresh: f32,
+    pub target_offset: f32,
+}
+
+/// loudnorm prints every value as a string
+#[derive(Deserialize)]
+struct LoudnormOutput {
+    input_i: String,
+    input_tp: String,
+    input_lra: String,
+    input_thresh: String,
+    target_offset: String,
+}
+
+fn targets() -> String {
+    format!("loudnorm=I={}:TP={}:LRA={}", TARGET_I, TARGET_TP, TARGET_LRA)
+}
+
+/// The loudnorm filter, with a measurement the gain is exact, without one it normalizes as it plays
+pub fn filter(measurement: Option<&LoudnessMeasurement>) -> String {
+    match measurement {
+        Some(measurement) => format!("{}:measured_I={}:measured_TP={}:measured_LRA={}:measured_thresh={}:offset={}:linear=true",
+            targets(),
+            measurement.input_i,
+            measurement.input_tp,
+            measurement.input_lra,
+            measurement.input_thresh,
+            measurement.target_offset),
+        None => targets(),
+    }
+}
+
+/// Read the JSON block loudnorm prints at the end of the ffmpeg output
+pub fn parse_measurement(output: &str) -> Option<LoudnessMeasurement> {
+    let start = output.rfind('{')?;
+    let end = output[start..].find('}')? + start + 1;
+    let output: LoudnormOutput = serde_json::from_str(&output[start..end]).ok()?;
+
+    Some(LoudnessMeasurement {
+        input_i: output.input_i.parse().ok()?,
+        input_tp: output.input_tp.parse().ok()?,
+        input_lra: output.input_lra.parse().ok()?,
+        input_thresh: output.input_thresh.parse().ok()?,
+        target_offset: output.target_offset.parse().ok()?,
+    }).filter(|measurement| measurement.input_i.is_finite())
+}
+
+/// Run the measurement pass over the whole file, this blocks until ffmpeg is done
+pub fn measure(path: &str) -> anyhow::Result<LoudnessMeasurement> {
+    dependencies::ensure(FFMPEG)?;
+
+    let _timer = metrics::process_timer(FFMPEG, "measure");
+    let mut ffmpeg = Command::new(FFMPEG)
+        .args(["-hide_banner", "-nostats", "-i", path, "-af"])
+        .arg(format!("{}:print_format=json", targets()))
+        .args(["-f", "null", "-"])
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .inspect_err(|_| metrics::spawn_failed(FFMPEG))?;
+
+    // The summary is printed at the end, reading stops when ffmpeg exits or is killed on shutdown
+    let mut stderr = String::new();
+    let pipe = ffmpeg.stderr.take();
+    let ffmpeg = shutdown::track(ffmpeg);
+    if let Some(mut pipe) = pipe {
+        pipe.read_to_string(&mut stderr)?;
+    }
+    ffmpeg.wait()?;
+
+    parse_measurement(&stderr)
+        .ok_or_else(|| anyhow::anyhow!("Cannot measure the loudness of {}", path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_loudnorm_output() {
+        let output = r#"[Parsed_loudnorm_0 @ 0x55d5c4b3a0c0]
+{
+	"input_i" : "-27.61",
+	"input_tp" : "-4.47",
+	"input_lra" : "18.06",
+	"input_thresh" : "-39.20",
+	"output_i" : "-16.58",
+	"output_tp" : "-1.50",
+	"output_lra" : "14.78",
+	"output_thresh" : "-27.71",
+	"normalization_type" : "dynamic",
+	"target_offset" : "0.58"
+}"#;
+
+        let measurement = parse_measurement(output).unwrap();
+        assert_eq!(measurement.input_i, -27.61);
+        assert_eq!(measurement.target_offset, 0.58);
+        assert!(filter(Some(&measurement)).ends_with(":measured_I=-27.61:measured_TP=-4.47:measured_LRA=18.06:measured_thresh=-39.2:offset=0.58:linear=true"));
+    }
+
+    #[test]
+    fn silent_files_have_no_measurement() {
+        let output = r#"{"input_i" : "-inf", "input_tp" : "-inf", "input_lra" : "0.00", "input_thresh" : "-inf", "target_offset" : "inf"}"#;
+        assert_eq!(parse_measurement(output), None);
+    }
+}
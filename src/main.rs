@@ -1,12 +1,33 @@
 mod helpers;
+mod layout;
 mod commands;
 mod pot;
+mod player;
+mod now_playing;
+mod settings;
+mod permissions;
+mod history;
+mod saved_playlists;
+mod playlist_formats;
+mod filters;
+mod loudness;
+mod cache;
+mod library;
+mod metrics;
+#[cfg(feature = "http-api")]
+mod api;
+mod logging;
+mod cli;
+mod dependencies;
+mod attachments;
+mod autoplay;
+mod shutdown;
 mod yt;
 
-use std::{sync::Arc, fmt};
+use std::{sync::Arc, fmt, env};
 use poise::{serenity_prelude::{self as serenity, RwLock}};
 
-use crate::{pot::{SystemPlaylist, PotPlayInputType}};
+use crate::{pot::{SystemPlaylist, PotPlayInputType}, settings::GuildSettingsStore, saved_playlists::SavedPlaylistStore, library::Library};
 
 pub type Error = Box<dyn std::error::Error + Send + Sync>;
 pub type Context<'a> = poise::Context<'a, Data, Error>;
@@ -23,9 +44,13 @@ impl fmt::Display for CommandError {
 impl std::error::Error for CommandError {}
 
 // User data, which is stored and accessible in all command invocations
+#[derive(Clone)]
 pub struct Data {
     pub songbird: Arc<songbird::Songbird>,
-    pub system_playlist: Arc<RwLock<SystemPlaylist>>
+    pub system_playlist: Arc<RwLock<SystemPlaylist>>,
+    pub guild_settings: Arc<RwLock<GuildSettingsStore>>,
+    pub saved_playlists: Arc<RwLock<SavedPlaylistStore>>,
+    pub library: Arc<RwLock<Library>>
 }
 
 #[poise::command(prefix_command)]
@@ -43,24 +68,133 @@ pub async fn ping(
     Ok(())
 }
 
+async fn event_listener(
+    ctx: &serenity::Context,
+    event: &poise::Event<'_>,
+    _framework: poise::FrameworkContext<'_, Data, Error>,
+    data: &Data,
+) -> Result<(), Error> {
+    if shutdown::is_shutting_down() {
+        return Ok(());
+    }
+
+    if let poise::Event::InteractionCreate { interaction: serenity::Interaction::MessageComponent(component) } = event {
+        if component.data.custom_id.starts_with(commands::history_commands::BUTTON_PREFIX) {
+            commands::history_commands::handle_history_button(ctx, data, component).await?;
+        } else {
+            commands::voice_commands::handle_control_button(ctx, data, component).await?;
+        }
+    }
+
+    Ok(())
+}
+
 #[tokio::main]
+#[allow(clippy::option_env_unwrap)]
 async fn main() {
+    let cli = <cli::Cli as clap::Parser>::parse();
+    logging::init();
+
+    if let Some(command) = cli.command {
+        std::process::exit(cli::run(command).await);
+    }
+
     // Setup dir structure
     match helpers::setup_system() {
-        Ok(_) => println!("Directories setup complete"),
+        Ok(_) => tracing::info!("Setup complete"),
         Err(err) => {
             panic!("{:?}", err);
         },
     }
 
     let songbird = songbird::Songbird::serenity();
-    let system_playlist = Arc::new(RwLock::new(SystemPlaylist::new()));
+    let mut playlist = SystemPlaylist::new();
+    shutdown::restore_queues(&mut playlist);
+    let system_playlist = Arc::new(RwLock::new(playlist));
+    let guild_settings = Arc::new(RwLock::new(GuildSettingsStore::load()));
+    let library = Arc::new(RwLock::new(Library::load()));
+
+    // Pick up files added while the bot was offline, the saved index is used until the scan is done
+    if library.read().await.root().is_some() {
+        let library = library.clone();
+        tokio::spawn(async move {
+            match library::rescan(&library).await {
+                Ok(len) => tracing::info!(songs = len, "Library scanned"),
+                Err(err) => tracing::error!("Cannot scan the library: {:?}", err),
+            }
+        });
+    }
+
+    if let Ok(addr) = env::var(metrics::METRICS_ADDR_ENV) {
+        match addr.parse() {
+            Ok(addr) => {
+                let system_playlist = system_playlist.clone();
+                let songbird = songbird.clone();
+                tokio::spawn(async move {
+                    if let Err(err) = metrics::serve(addr, system_playlist, songbird).await {
+                        tracing::error!("Cannot serve the metrics: {:?}", err);
+                    }
+                });
+            },
+            Err(err) => tracing::error!("Invalid {} {}: {}", metrics::METRICS_ADDR_ENV, addr, err),
+        }
+    }
 
     let data = Data {
         songbird: songbird.clone(),
-        system_playlist: system_playlist.clone()
+        system_playlist: system_playlist.clone(),
+        guild_settings,
+        saved_playlists: Arc::new(RwLock::new(SavedPlaylistStore::new())),
+        library
     };
 
+    let shutdown_data = data.clone();
+
+    let token = option_env!("DISCORD_TOKEN").expect("No DISCORD_TOKEN set on compile time");
+
+    #[cfg(feature = "http-api")]
+    if let Ok(addr) = env::var(api::API_ADDR_ENV) {
+        match (addr.parse(), env::var(api::API_TOKEN_ENV)) {
+            (Ok(addr), Ok(api_token)) if !api_token.is_empty() => {
+                let data = data.clone();
+                let http = Arc::new(serenity::Http::new(token));
+                tokio::spawn(async move {
+                    if let Err(err) = api::serve(addr, api_token, data, http).await {
+                        tracing::error!("Cannot serve the control API: {:?}", err);
+                    }
+                });
+            },
+            (Ok(_), _) => tracing::error!("{} is not set, the control API is disabled", api::API_TOKEN_ENV),
+            (Err(err), _) => tracing::error!("Invalid {} {}: {}", api::API_ADDR_ENV, addr, err),
+        }
+    }
+
+    let mut commands = vec![
+        register(),
+        ping(),
+        commands::shitpost_reactions::shut(),
+        commands::shitpost_reactions::pato(),
+        commands::voice_commands::join(),
+        commands::voice_commands::play(),
+        commands::voice_commands::play_attachment(),
+        commands::voice_commands::skip(),
+        commands::voice_commands::previous(),
+        commands::voice_commands::pause(),
+        commands::voice_commands::resume(),
+        commands::voice_commands::leave(),
+        commands::voice_commands::shuffle(),
+        commands::voice_commands::loop_mode(),
+        commands::voice_commands::autoplay(),
+        commands::voice_commands::queue(),
+        commands::voice_commands::remove(),
+        commands::voice_commands::filter(),
+        commands::settings_commands::settings(),
+        commands::history_commands::history(),
+        commands::playlist_commands::playlist(),
+        commands::library_commands::library(),
+    ];
+    logging::trace_commands(&mut commands);
+
     // Start poise framework
     let framework = poise::Framework::builder()
         .options(poise::FrameworkOptions {
@@ -70,20 +204,13 @@ async fn main() {
                 case_insensitive_commands: true,
                 ..Default::default()
             },
-            commands: vec![
-                register(),
-                ping(),
-                commands::shitpost_reactions::shut(),
-                commands::shitpost_reactions::pato(),
-                commands::voice_commands::join(),
-                commands::voice_commands::play(),
-                commands::voice_commands::skip(),
-                commands::voice_commands::leave(),
-            ],
+            commands,
+            command_check: Some(|ctx| Box::pin(shutdown::command_check(ctx))),
+            listener: |ctx, event, framework, data| Box::pin(event_listener(ctx, event, framework, data)),
             ..Default::default()
         })
         // .token(std::env::var("DISCORD_TOKEN").expect("missing DISCORD_TOKEN"))
-        .token(option_env!("DISCORD_TOKEN").expect("No DISCORD_TOKEN set on compile time"))
+        .token(token)
         .client_settings(|f| f.voice_manager_arc(songbird))
         .intents(serenity::GatewayIntents::GUILDS
             | serenity::GatewayIntents::GUILD_MESSAGES
@@ -92,7 +219,11 @@ async fn main() {
             | serenity::GatewayIntents::MESSAGE_CONTENT)
         .user_data_setup(move |_ctx, _ready, _framework| Box::pin(async move { 
             Ok(data) 
-        }));
+        }))
+        .build().await.unwrap();
+
+    tokio::spawn(shutdown::on_signal(shutdown_data, Arc::new(serenity::Http::new(token)), framework.shard_manager().clone()));
 
-    framework.run().await.unwrap();
+    framework.start().await.unwrap();
+    tracing::info!("Stopped");
 }
\ No newline at end of file
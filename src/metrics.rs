@@ -0,0 +1,139 @@
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::{Arc, LazyLock};
+
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use poise::serenity_prelude::RwLock;
+use prometheus::{
+    Encoder, Histogram, HistogramTimer, HistogramVec, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, TextEncoder,
+    register_histogram, register_histogram_vec, register_int_counter, register_int_counter_vec, register_int_gauge, register_int_gauge_vec,
+};
+
+use crate::pot::SystemPlaylist;
+
+/// Environment variable with the address the metrics endpoint listens on, like 127.0.0.1:9100
+pub const METRICS_ADDR_ENV: &str = "METRICS_ADDR";
+
+pub static TRACKS_STARTED: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!("pot_tracks_started_total", "Tracks that started playing", &["extractor"]).unwrap()
+});
+
+pub static TRACKS_FINISHED: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!("pot_tracks_finished_total", "Tracks that stopped playing, by how they stopped", &["extractor", "outcome"]).unwrap()
+});
+
+pub static TRACKS_FAILED: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!("pot_tracks_failed_total", "Tracks whose media could not be fetched", &["extractor"]).unwrap()
+});
+
+pub static PROCESS_SPAWN_FAILURES: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!("pot_process_spawn_failures_total", "yt-dlp and ffmpeg processes that could not be started", &["command"]).unwrap()
+});
+
+pub static PROCESS_DURATION: LazyLock<HistogramVec> = LazyLock::new(|| {
+    register_histogram_vec!(
+        "pot_process_duration_seconds",
+        "Time until yt-dlp and ffmpeg processes are done, or until a stream starts",
+        &["command", "operation"],
+        vec![0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 300.0]
+    ).unwrap()
+});
+
+pub static YOUTUBE_API_CALLS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!("pot_youtube_api_calls_total", "Requests to the YouTube Data API", &["endpoint"]).unwrap()
+});
+
+pub static YOUTUBE_API_ERRORS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!("pot_youtube_api_errors_total", "Requests to the YouTube Data API that failed or were refused", &["endpoint"]).unwrap()
+});
+
+pub static YOUTUBE_API_QUOTA: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!("pot_youtube_api_quota_units_total", "Quota units spent on the YouTube Data API", &["endpoint"]).unwrap()
+});
+
+pub static YOUTUBE_API_DURATION: LazyLock<Histogram> = LazyLock::new(|| {
+    register_histogram!("pot_youtube_api_duration_seconds", "Time until the YouTube Data API answers").unwrap()
+});
+
+pub static CACHE_HITS: LazyLock<IntCounter> = LazyLock::new(|| {
+    register_int_counter!("pot_cache_hits_total", "Tracks played from the media cache").unwrap()
+});
+
+pub static CACHE_MISSES: LazyLock<IntCounter> = LazyLock::new(|| {
+    register_int_counter!("pot_cache_misses_total", "Tracks streamed because they were not in the media cache").unwrap()
+});
+
+pub static CACHE_BYTES: LazyLock<IntCounter> = LazyLock::new(|| {
+    register_int_counter!("pot_cache_written_bytes_total", "Bytes of media written to the cache").unwrap()
+});
+
+pub static QUEUE_LENGTH: LazyLock<IntGaugeVec> = LazyLock::new(|| {
+    register_int_gauge_vec!("pot_queue_length", "Items waiting in the queue of each guild", &["guild"]).unwrap()
+});
+
+pub static VOICE_CONNECTIONS: LazyLock<IntGauge> = LazyLock::new(|| {
+    register_int_gauge!("pot_voice_connections", "Guilds the bot is connected to a voice channel in").unwrap()
+});
+
+/// Count a process that could not be started
+pub fn spawn_failed(command: &str) {
+    PROCESS_SPAWN_FAILURES.with_label_values(&[command]).inc();
+}
+
+/// Measure a process until the timer is dropped
+pub fn process_timer(command: &str, operation: &str) -> HistogramTimer {
+    PROCESS_DURATION.with_label_values(&[command, operation]).start_timer()
+}
+
+/// Gauges are read from the player state on every scrape instead of being kept up to date
+async fn update_gauges(playlist: &RwLock<SystemPlaylist>, songbird: &songbird::Songbird) {
+    let playlist = playlist.read().await;
+
+    QUEUE_LENGTH.reset();
+    let mut connections = 0;
+    for guild_id in playlist.guilds() {
+        QUEUE_LENGTH.with_label_values(&[&guild_id.to_string()]).set(playlist.len(guild_id) as i64);
+        if songbird.get(guild_id).is_some() {
+            connections += 1;
+        }
+    }
+    VOICE_CONNECTIONS.set(connections);
+}
+
+async fn handle(request: Request<Body>, playlist: Arc<RwLock<SystemPlaylist>>, songbird: Arc<songbird::Songbird>) -> Result<Response<Body>, Infallible> {
+    if request.method() != Method::GET || request.uri().path() != "/metrics" {
+        let mut response = Response::new(Body::from("Not found"));
+        *response.status_mut() = StatusCode::NOT_FOUND;
+        return Ok(response);
+    }
+
+    update_gauges(&playlist, &songbird).await;
+
+    let encoder = TextEncoder::new();
+    let mut buffer = Vec::new();
+    if let Err(err) = encoder.encode(&prometheus::gather(), &mut buffer) {
+        tracing::error!("Cannot encode the metrics: {}", err);
+        let mut response = Response::new(Body::empty());
+        *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+        return Ok(response);
+    }
+
+    let mut response = Response::new(Body::from(buffer));
+    response.headers_mut().insert(hyper::header::CONTENT_TYPE, hyper::header::HeaderValue::from_static("text/plain; version=0.0.4"));
+    Ok(response)
+}
+
+/// Serve /metrics in the Prometheus text format until the bot stops
+pub async fn serve(addr: SocketAddr, playlist: Arc<RwLock<SystemPlaylist>>, songbird: Arc<songbird::Songbird>) -> hyper::Result<()> {
+    let make_service = make_service_fn(move |_connection| {
+        let playlist = playlist.clone();
+        let songbird = songbird.clone();
+
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request| handle(request, playlist.clone(), songbird.clone())))
+        }
+    });
+
+    Server::try_bind(&addr)?.serve(make_service).await
+}
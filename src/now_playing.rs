@@ -0,0 +1,207 @@
+use poise::serenity_prelude::{ButtonStyle, ChannelId, CreateComponents, CreateEmbed, GuildId, Http};
+use serde::Serialize;
+
+use crate::{pot::{SystemPlaylist, PlaylistItem}, player::{LoopMode, PlayerState}};
+
+pub const BUTTON_PAUSE: &str = "pot_pause";
+pub const BUTTON_SKIP: &str = "pot_skip";
+pub const BUTTON_STOP: &str = "pot_stop";
+pub const BUTTON_SHUFFLE: &str = "pot_shuffle";
+pub const BUTTON_LOOP: &str = "pot_loop";
+
+/// Everything the now playing message shows, taken from the guild player
+struct NowPlaying {
+    item: PlaylistItem,
+    paused: bool,
+    loop_mode: LoopMode,
+    played: usize,
+    queued: usize,
+}
+
+impl NowPlaying {
+    fn from_playlist(playlist: &mut SystemPlaylist, guild_id: GuildId) -> Option<Self> {
+        let queued = playlist.len(guild_id);
+        let player = playlist.player(guild_id);
+
+        Some(Self {
+            item: player.current.clone()?,
+            paused: player.state == PlayerState::Paused,
+            loop_mode: player.loop_mode,
+            played: player.played,
+            queued,
+        })
+    }
+}
+
+/// Change of the now playing state of a guild, streamed by the HTTP API
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum NowPlayingEvent {
+    Update {
+        guild_id: u64,
+        state: &'static str,
+        current: Option<PlaylistItem>,
+        queued: usize,
+    },
+    Finished {
+        guild_id: u64,
+        reason: String,
+    },
+}
+
+impl NowPlayingEvent {
+    pub fn guild_id(&self) -> u64 {
+        match self {
+            NowPlayingEvent::Update { guild_id, .. } | NowPlayingEvent::Finished { guild_id, .. } => *guild_id,
+        }
+    }
+}
+
+/// Send the current state of the guild player to the event subscribers
+fn publish(playlist: &mut SystemPlaylist, guild_id: GuildId) {
+    let queued = playlist.len(guild_id);
+    let player = playlist.player(guild_id);
+    let event = NowPlayingEvent::Update {
+        guild_id: guild_id.0,
+        state: player.state.name(),
+        current: player.current.clone(),
+        queued,
+    };
+
+    // Fails when nobody is subscribed
+    let _ = playlist.events.send(event);
+}
+
+/// Format seconds as h:mm:ss or m:ss
+pub fn format_duration(seconds: f32) -> String {
+    let seconds = seconds as u64;
+    let (hours, minutes, seconds) = (seconds / 3600, (seconds / 60) % 60, seconds % 60);
+
+    if hours > 0 {
+        format!("{}:{:02}:{:02}", hours, minutes, seconds)
+    } else {
+        format!("{}:{:02}", minutes, seconds)
+    }
+}
+
+fn thumbnail(item: &PlaylistItem) -> Option<String> {
+    match &item.thumbnail {
+        Some(thumbnail) => Some(thumbnail.to_owned()),
+        None if item.extractor == "youtube" => Some(format!("https://i.ytimg.com/vi/{}/hqdefault.jpg", item.id)),
+        None => None,
+    }
+}
+
+fn fill_embed<'b>(embed: &'b mut CreateEmbed, now_playing: &NowPlaying) -> &'b mut CreateEmbed {
+    let item = &now_playing.item;
+
+    embed
+        .author(|a| a.name(if now_playing.paused { "Paused" } else { "Playing now" }))
+        .title(&item.title)
+        .field("Requested by", match item.requester {
+            _ if item.autoplay => "Autoplay".to_string(),
+            Some(user_id) => format!("<@{}>", user_id),
+            None => "Unknown".to_string(),
+        }, true)
+        .field("Duration", match item.duration {
+            _ if item.is_live() => "LIVE".to_string(),
+            Some(duration) => format_duration(duration),
+            None => "Unknown".to_string(),
+        }, true)
+        .field("Position", format!("#{} · {} in queue", now_playing.played, now_playing.queued), true)
+        .footer(|f| f.text(match now_playing.loop_mode {
+            LoopMode::Off => "Loop off",
+            LoopMode::Track => "Looping track",
+            LoopMode::Queue => "Looping queue",
+        }));
+
+    if let Some(link) = item.link() {
+        embed.url(link);
+    }
+    if let Some(thumbnail) = thumbnail(item) {
+        embed.thumbnail(thumbnail);
+    }
+
+    embed
+}
+
+fn fill_components<'b>(components: &'b mut CreateComponents, now_playing: &NowPlaying) -> &'b mut CreateComponents {
+    components.create_action_row(|row| {
+        row
+            .create_button(|b| b
+                .custom_id(BUTTON_PAUSE)
+                .label(if now_playing.paused { "Resume" } else { "Pause" })
+                .style(ButtonStyle::Primary))
+            .create_button(|b| b
+                .custom_id(BUTTON_SKIP)
+                .label("Skip")
+                .style(ButtonStyle::Secondary))
+            .create_button(|b| b
+                .custom_id(BUTTON_STOP)
+                .label("Stop")
+                .style(ButtonStyle::Danger))
+            .create_button(|b| b
+                .custom_id(BUTTON_SHUFFLE)
+                .label("Shuffle")
+                .style(ButtonStyle::Secondary))
+            .create_button(|b| b
+                .custom_id(BUTTON_LOOP)
+                .label("Loop")
+                .style(if now_playing.loop_mode == LoopMode::Off { ButtonStyle::Secondary } else { ButtonStyle::Success }))
+    })
+}
+
+/// Post the now playing message of the session, or edit it if the session already has one
+pub async fn announce(http: &Http, channel_id: ChannelId, playlist: &mut SystemPlaylist, guild_id: GuildId) {
+    publish(playlist, guild_id);
+
+    let now_playing = match NowPlaying::from_playlist(playlist, guild_id) {
+        Some(now_playing) => now_playing,
+        None => return,
+    };
+
+    if let Some((message_channel, message_id)) = playlist.player(guild_id).now_playing {
+        let edited = message_channel.edit_message(http, message_id, |m| m
+            .embed(|e| fill_embed(e, &now_playing))
+            .components(|c| fill_components(c, &now_playing))
+        ).await;
+
+        // The message may have been deleted, post a new one in that case
+        if edited.is_ok() {
+            return;
+        }
+    }
+
+    let sent = channel_id.send_message(http, |m| m
+        .embed(|e| fill_embed(e, &now_playing))
+        .components(|c| fill_components(c, &now_playing))
+    ).await;
+
+    match sent {
+        Ok(message) => playlist.player(guild_id).now_playing = Some((channel_id, message.id)),
+        Err(err) => tracing::warn!(guild_id = guild_id.0, "Cannot send now playing message {:?}", err),
+    }
+}
+
+/// Edit the now playing message after a change that does not start a new track
+pub async fn refresh(http: &Http, playlist: &mut SystemPlaylist, guild_id: GuildId) {
+    match playlist.player(guild_id).now_playing {
+        Some((channel_id, _)) => announce(http, channel_id, playlist, guild_id).await,
+        None => publish(playlist, guild_id),
+    }
+}
+
+/// Turn the now playing message into the final message of the session and remove its buttons
+pub async fn finish(http: &Http, playlist: &mut SystemPlaylist, guild_id: GuildId, reason: &str) -> bool {
+    let _ = playlist.events.send(NowPlayingEvent::Finished { guild_id: guild_id.0, reason: reason.to_string() });
+
+    match playlist.player(guild_id).now_playing.take() {
+        Some((channel_id, message_id)) => {
+            channel_id.edit_message(http, message_id, |m| m
+                .embed(|e| e.title(reason))
+                .components(|c| c)
+            ).await.is_ok()
+        },
+        None => false,
+    }
+}
\ No newline at end of file
@@ -0,0 +1,194 @@
+use poise::serenity_prelude::{GuildId, Permissions, RoleId, UserId, Context};
+
+use crate::settings::GuildSettings;
+
+/// How much control a member has over the player
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    /// Full control, members with the DJ role or that can manage the server
+    Dj,
+    /// Can queue songs and control only the items they requested
+    Listener,
+}
+
+/// Something a member wants to do with the player
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    Play,
+    /// Skip the current track, requested by `requester`
+    Skip { requester: Option<u64> },
+    /// Vote to skip the current track
+    VoteSkip,
+    /// Remove a queued item, requested by `requester`
+    Remove { requester: Option<u64> },
+    /// Go back to the previous songs of the history
+    Previous,
+    Pause,
+    Stop,
+    Shuffle,
+    Loop,
+    Filter,
+    Autoplay,
+}
+
+impl Action {
+    fn describe(&self) -> &'static str {
+        match self {
+            Action::Play => "queue songs",
+            Action::Skip { .. } => "skip songs requested by others",
+            Action::VoteSkip => "vote to skip",
+            Action::Remove { .. } => "remove songs requested by others",
+            Action::Previous => "go back to previous songs",
+            Action::Pause => "pause or resume the player",
+            Action::Stop => "stop the player",
+            Action::Shuffle => "shuffle the queue",
+            Action::Loop => "change the loop mode",
+            Action::Filter => "change the audio filters",
+            Action::Autoplay => "turn autoplay on or off",
+        }
+    }
+}
+
+pub fn is_allowed(role: Role, user_id: u64, action: Action) -> bool {
+    match (role, action) {
+        (Role::Dj, _) => true,
+        (Role::Listener, Action::Play | Action::VoteSkip) => true,
+        (Role::Listener, Action::Skip { requester } | Action::Remove { requester }) => requester == Some(user_id),
+        (Role::Listener, _) => false,
+    }
+}
+
+/// Without a DJ role configured every member is a DJ
+pub fn everyone_is_dj(settings: &GuildSettings) -> bool {
+    settings.dj_role.is_none()
+}
+
+/// What a skip from the member asks for, with vote skip on and no DJ role only the requester and server managers skip right away, the others vote
+pub fn skip_action(settings: &GuildSettings, user_id: u64, requester: Option<u64>, manages_guild: bool) -> Action {
+    if settings.vote_skip && everyone_is_dj(settings) && requester != Some(user_id) && !manages_guild {
+        Action::VoteSkip
+    } else {
+        Action::Skip { requester }
+    }
+}
+
+/// Get the role of a member, see [`everyone_is_dj`] for guilds without a DJ role
+pub async fn member_role(discord: &Context, guild_id: GuildId, user_id: UserId, settings: &GuildSettings) -> Role {
+    if everyone_is_dj(settings) || can_manage_guild(discord, guild_id, user_id).await {
+        return Role::Dj;
+    }
+
+    let (dj_role, guild) = match (settings.dj_role, discord.cache.guild(guild_id)) {
+        (Some(dj_role), Some(guild)) => (RoleId(dj_role), guild),
+        _ => return Role::Listener,
+    };
+
+    match guild.member(discord, user_id).await {
+        Ok(member) if member.roles.contains(&dj_role) => Role::Dj,
+        _ => Role::Listener,
+    }
+}
+
+/// Members that can manage the server, they can also change anything other members own
+pub async fn can_manage_guild(discord: &Context, guild_id: GuildId, user_id: UserId) -> bool {
+    let guild = match discord.cache.guild(guild_id) {
+        Some(guild) => guild,
+        None => return false,
+    };
+
+    match guild.member_permissions(discord, user_id).await {
+        Ok(permissions) => permissions.contains(Permissions::MANAGE_GUILD),
+        Err(_) => false,
+    }
+}
+
+/// Check if the member can do the action, this is the only place where player permissions are decided
+pub async fn check(discord: &Context, guild_id: GuildId, user_id: UserId, settings: &GuildSettings, action: Action) -> Result<Role, crate::Error> {
+    let guild = discord.cache.guild(guild_id).ok_or_else( || Box::new(crate::CommandError("Cannot get Guild".into())))?;
+
+    // Only members listening with the bot can control it
+    let member_channel = guild.voice_states.get(&user_id).and_then(|voice_state| voice_state.channel_id);
+    let bot_channel = guild.voice_states.get(&discord.cache.current_user_id()).and_then(|voice_state| voice_state.channel_id);
+
+    match (member_channel, bot_channel) {
+        (None, _) => return Err(Box::new(crate::CommandError("You are not in a voice channel".into()))),
+        (Some(member_channel), Some(bot_channel)) if member_channel != bot_channel => {
+            return Err(Box::new(crate::CommandError("You are not in the same voice channel as the bot".into())))
+        },
+        _ => {},
+    }
+
+    let role = member_role(discord, guild_id, user_id, settings).await;
+
+    if is_allowed(role, *user_id.as_u64(), action) {
+        Ok(role)
+    } else {
+        Err(Box::new(crate::CommandError(format!("Only a DJ can {}", action.describe()))))
+    }
+}
+
+/// Number of members that are not bots listening in the voice channel of the bot
+pub fn listeners(discord: &Context, guild_id: GuildId) -> usize {
+    let guild = match discord.cache.guild(guild_id) {
+        Some(guild) => guild,
+        None => return 0,
+    };
+
+    let bot_channel = match guild.voice_states.get(&discord.cache.current_user_id()).and_then(|voice_state| voice_state.channel_id) {
+        Some(bot_channel) => bot_channel,
+        None => return 0,
+    };
+
+    guild.voice_states.values().filter(|voice_state| {
+        let is_bot = match &voice_state.member {
+            Some(member) => member.user.bot,
+            None => discord.cache.user(voice_state.user_id).map(|user| user.bot).unwrap_or(false),
+        };
+        voice_state.channel_id == Some(bot_channel) && !is_bot
+    }).count()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dj_can_do_everything() {
+        for action in [Action::Play, Action::Skip { requester: Some(2) }, Action::VoteSkip, Action::Remove { requester: None }, Action::Previous, Action::Pause, Action::Stop, Action::Shuffle, Action::Loop, Action::Filter] {
+            assert!(is_allowed(Role::Dj, 1, action));
+        }
+    }
+
+    #[test]
+    fn listener_controls_only_own_items() {
+        assert!(is_allowed(Role::Listener, 1, Action::Play));
+        assert!(is_allowed(Role::Listener, 1, Action::VoteSkip));
+        assert!(is_allowed(Role::Listener, 1, Action::Skip { requester: Some(1) }));
+        assert!(is_allowed(Role::Listener, 1, Action::Remove { requester: Some(1) }));
+        assert!(!is_allowed(Role::Listener, 1, Action::Skip { requester: Some(2) }));
+        assert!(!is_allowed(Role::Listener, 1, Action::Remove { requester: None }));
+        assert!(!is_allowed(Role::Listener, 1, Action::Stop));
+        assert!(!is_allowed(Role::Listener, 1, Action::Pause));
+        assert!(!is_allowed(Role::Listener, 1, Action::Previous));
+    }
+
+    #[test]
+    fn vote_skip_without_dj_role_only_votes_skips() {
+        let mut settings = GuildSettings::default();
+        assert!(everyone_is_dj(&settings));
+        assert_eq!(skip_action(&settings, 1, Some(2), false), Action::Skip { requester: Some(2) });
+
+        // Without a DJ role everyone keeps full control, but skipping a song requested by someone else is a vote
+        settings.vote_skip = true;
+        assert!(everyone_is_dj(&settings));
+        assert_eq!(skip_action(&settings, 1, Some(2), false), Action::VoteSkip);
+        assert_eq!(skip_action(&settings, 1, None, false), Action::VoteSkip);
+        assert_eq!(skip_action(&settings, 1, Some(1), false), Action::Skip { requester: Some(1) });
+        assert_eq!(skip_action(&settings, 1, Some(2), true), Action::Skip { requester: Some(2) });
+
+        // With a DJ role the role decides who skips right away
+        settings.dj_role = Some(3);
+        assert!(!everyone_is_dj(&settings));
+        assert_eq!(skip_action(&settings, 1, Some(2), false), Action::Skip { requester: Some(2) });
+    }
+}
@@ -0,0 +1,427 @@
+use std::collections::HashSet;
+use std::time::{Duration, Instant};
+
+use poise::serenity_prelude::{ChannelId, MessageId};
+use songbird::tracks::TrackHandle;
+
+use crate::filters::AudioFilter;
+use crate::pot::PlaylistItem;
+
+/// How many items in a row can fail to resolve before the player gives up
+pub const MAX_RESOLVE_FAILURES: u32 = 5;
+
+/// How many times in a row a dropped live stream is started again before the queue moves on
+pub const MAX_LIVE_RECONNECTS: u32 = 5;
+
+/// A live stream that played this long before dropping is considered recovered, its reconnects start over
+pub const LIVE_STABLE_AFTER: Duration = Duration::from_secs(60);
+
+/// Wait before the reconnect number `attempt`, doubling from 1 second up to 30
+pub fn reconnect_delay(attempt: u32) -> Duration {
+    Duration::from_secs(2u64.saturating_pow(attempt).min(30))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PlayerState {
+    /// Nothing is playing and nothing is being fetched
+    Idle,
+    /// An item was taken from the queue and its media is being fetched
+    Resolving { failures: u32 },
+    Playing,
+    Paused,
+}
+
+impl PlayerState {
+    pub fn name(&self) -> &'static str {
+        match self {
+            PlayerState::Idle => "idle",
+            PlayerState::Resolving { .. } => "resolving",
+            PlayerState::Playing => "playing",
+            PlayerState::Paused => "paused",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PlayerEvent {
+    /// Start fetching the next item, sent when the queue starts, a track ends or a track is skipped
+    Advance,
+    /// The media for the current item was fetched and is playing
+    Started,
+    /// The media for the current item could not be fetched
+    Failed,
+    /// There are no more items in the queue
+    QueueEmpty,
+    Pause,
+    Resume,
+    Stop,
+}
+
+/// Why the player is moving to the next item
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AdvanceReason {
+    /// The queue was idle and something was added
+    Start,
+    /// The current track reached its end
+    TrackEnded,
+    /// Someone skipped the current track
+    Skipped,
+    /// Someone went back to an item of the history
+    Previous,
+    /// The current track is about to end and fades out while the next one starts
+    Crossfade,
+    /// The live stream played for as long as the guild allows
+    TimeLimit,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LoopMode {
+    Off,
+    /// Repeat the current track until it is skipped
+    Track,
+    /// Send every played item back to the end of the queue
+    Queue,
+}
+
+impl LoopMode {
+    /// The mode after pressing the loop button
+    pub fn cycle(self) -> LoopMode {
+        match self {
+            LoopMode::Off => LoopMode::Track,
+            LoopMode::Track => LoopMode::Queue,
+            LoopMode::Queue => LoopMode::Off,
+        }
+    }
+
+    /// Whether the item that was playing should go back into the queue and where
+    pub fn requeue(self, reason: AdvanceReason) -> Option<Requeue> {
+        match (self, reason) {
+            (_, AdvanceReason::Start | AdvanceReason::Previous) => None,
+            (LoopMode::Track, AdvanceReason::TrackEnded | AdvanceReason::Crossfade) => Some(Requeue::Front),
+            (LoopMode::Queue, _) => Some(Requeue::Back),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Requeue {
+    Front,
+    Back,
+}
+
+impl PlayerState {
+    /// Returns the state after the event or None if the event is not valid on the current state
+    pub fn next(self, event: PlayerEvent) -> Option<PlayerState> {
+        use PlayerEvent::*;
+        use PlayerState::*;
+
+        match (self, event) {
+            (_, Stop) => Some(Idle),
+
+            (Idle | Playing | Paused, Advance) => Some(Resolving { failures: 0 }),
+            // Moving to the next item after a failure keeps the failure count
+            (Resolving { failures }, Advance) => Some(Resolving { failures }),
+
+            (Resolving { .. }, Started) => Some(Playing),
+            (Resolving { failures }, Failed) => {
+                if failures + 1 >= MAX_RESOLVE_FAILURES {
+                    Some(Idle)
+                } else {
+                    Some(Resolving { failures: failures + 1 })
+                }
+            },
+            (Resolving { .. }, QueueEmpty) => Some(Idle),
+
+            (Playing, Pause) => Some(Paused),
+            (Paused, Resume) => Some(Playing),
+
+            _ => None,
+        }
+    }
+
+    pub fn is_active(&self) -> bool {
+        !matches!(self, PlayerState::Idle)
+    }
+}
+
+/// Playback state of a single guild
+pub struct GuildPlayer {
+    pub state: PlayerState,
+    /// Handle of the track currently attached to the call
+    pub track: Option<TrackHandle>,
+    /// Item currently attached to the call
+    pub current: Option<PlaylistItem>,
+    pub loop_mode: LoopMode,
+    /// Now playing message of the session, edited on every track change
+    pub now_playing: Option<(ChannelId, MessageId)>,
+    /// Text channel the session was started from, messages of actions without a channel go there
+    pub text_channel: Option<ChannelId>,
+    /// Number of tracks started in the session
+    pub played: usize,
+    /// Users that voted to skip the current track
+    pub skip_votes: HashSet<u64>,
+    /// How many history entries back /previous went, reset when an item is recorded in the history
+    pub history_cursor: usize,
+    /// Filters applied to every track, kept between sessions
+    pub filter: AudioFilter,
+    /// Seconds of the source skipped when the track was started, see [`GuildPlayer::source_position`]
+    pub source_offset: f32,
+    /// When the current live stream started, reconnects keep it
+    pub live_since: Option<Instant>,
+    /// Reconnects of the current live stream since it last played steadily
+    pub live_reconnects: u32,
+    /// Key of the item of a restored queue and the seconds of it that were played before the shutdown
+    pub resume_at: Option<(String, f32)>,
+}
+
+impl GuildPlayer {
+    pub fn new() -> Self {
+        Self {
+            state: PlayerState::Idle,
+            track: None,
+            current: None,
+            loop_mode: LoopMode::Off,
+            now_playing: None,
+            text_channel: None,
+            played: 0,
+            skip_votes: HashSet::new(),
+            history_cursor: 0,
+            filter: AudioFilter::new(),
+            source_offset: 0.0,
+            live_since: None,
+            live_reconnects: 0,
+            resume_at: None,
+        }
+    }
+
+    /// Apply the event and return the new state, invalid events leave the state unchanged
+    pub fn transition(&mut self, event: PlayerEvent) -> Option<PlayerState> {
+        let next = self.state.next(event)?;
+        self.state = next;
+
+        if !matches!(next, PlayerState::Playing | PlayerState::Paused) {
+            self.track = None;
+        }
+
+        // Votes only count for the track they were cast on
+        if event == PlayerEvent::Advance {
+            self.skip_votes.clear();
+        }
+
+        // The session ends when the player goes idle
+        if next == PlayerState::Idle {
+            self.current = None;
+            self.now_playing = None;
+            self.text_channel = None;
+            self.played = 0;
+            self.history_cursor = 0;
+        }
+
+        Some(next)
+    }
+}
+
+impl GuildPlayer {
+    /// Position in the source of the current track after it played for `played` seconds
+    pub fn source_position(&self, played: f32) -> f32 {
+        self.source_offset + played * self.filter.tempo()
+    }
+
+    /// Wait before starting the dropped live stream again, None when it failed too many times in a row
+    pub fn next_reconnect(&mut self) -> Option<Duration> {
+        if self.live_reconnects >= MAX_LIVE_RECONNECTS {
+            return None;
+        }
+
+        let delay = reconnect_delay(self.live_reconnects);
+        self.live_reconnects += 1;
+        Some(delay)
+    }
+
+    /// Whether the end of the current track is a dropped stream to reconnect, other items move the queue on when they end
+    pub fn reconnects(&self) -> bool {
+        self.current.as_ref().is_some_and(PlaylistItem::is_live)
+    }
+
+    /// Whether the current live stream played for at least `limit`
+    pub fn live_limit_reached(&self, limit: Duration) -> bool {
+        match (&self.current, self.live_since) {
+            (Some(current), Some(since)) if current.is_live() => since.elapsed() >= limit,
+            _ => false,
+        }
+    }
+
+    /// Seconds of output left in the current track after it played for `played` seconds,
+    /// None when the length is unknown like in live streams
+    pub fn remaining(&self, played: f32) -> Option<f32> {
+        let current = self.current.as_ref()?;
+        if current.is_live() {
+            return None;
+        }
+        let duration = current.duration?;
+
+        Some(((duration - self.source_position(played)) / self.filter.tempo()).max(0.0))
+    }
+}
+
+/// Votes needed to skip a track with the given number of listeners, never less than one
+pub fn votes_needed(listeners: usize, ratio: f32) -> usize {
+    let ratio = ratio.clamp(0.0, 1.0);
+    ((listeners as f32 * ratio).ceil() as usize).max(1)
+}
+
+impl Default for GuildPlayer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use PlayerEvent::*;
+    use PlayerState::*;
+
+    #[test]
+    fn idle_advances_to_resolving() {
+        assert_eq!(Idle.next(Advance), Some(Resolving { failures: 0 }));
+    }
+
+    #[test]
+    fn resolving_starts_playing() {
+        assert_eq!(Resolving { failures: 2 }.next(Started), Some(Playing));
+    }
+
+    #[test]
+    fn empty_queue_goes_idle() {
+        assert_eq!(Resolving { failures: 0 }.next(QueueEmpty), Some(Idle));
+    }
+
+    #[test]
+    fn track_end_or_skip_resolves_next() {
+        assert_eq!(Playing.next(Advance), Some(Resolving { failures: 0 }));
+        assert_eq!(Paused.next(Advance), Some(Resolving { failures: 0 }));
+    }
+
+    #[test]
+    fn failures_are_counted_across_items() {
+        let state = Resolving { failures: 0 }.next(Failed).unwrap();
+        assert_eq!(state, Resolving { failures: 1 });
+        assert_eq!(state.next(Advance), Some(Resolving { failures: 1 }));
+    }
+
+    #[test]
+    fn failures_are_capped() {
+        let mut state = Idle.next(Advance).unwrap();
+        for _ in 0..MAX_RESOLVE_FAILURES - 1 {
+            state = state.next(Failed).unwrap();
+            assert!(matches!(state, Resolving { .. }));
+        }
+        assert_eq!(state.next(Failed), Some(Idle));
+    }
+
+    #[test]
+    fn pause_and_resume() {
+        assert_eq!(Playing.next(Pause), Some(Paused));
+        assert_eq!(Paused.next(Resume), Some(Playing));
+        assert_eq!(Idle.next(Pause), None);
+        assert_eq!(Playing.next(Resume), None);
+        assert_eq!(Resolving { failures: 0 }.next(Pause), None);
+    }
+
+    #[test]
+    fn stop_always_goes_idle() {
+        for state in [Idle, Resolving { failures: 3 }, Playing, Paused] {
+            assert_eq!(state.next(Stop), Some(Idle));
+        }
+    }
+
+    #[test]
+    fn loop_modes_requeue() {
+        assert_eq!(LoopMode::Off.requeue(AdvanceReason::TrackEnded), None);
+        assert_eq!(LoopMode::Track.requeue(AdvanceReason::TrackEnded), Some(Requeue::Front));
+        assert_eq!(LoopMode::Track.requeue(AdvanceReason::Skipped), None);
+        assert_eq!(LoopMode::Queue.requeue(AdvanceReason::Skipped), Some(Requeue::Back));
+        assert_eq!(LoopMode::Queue.requeue(AdvanceReason::Start), None);
+        assert_eq!(LoopMode::Queue.requeue(AdvanceReason::Previous), None);
+        // A live stream that reached its limit does not start again
+        assert_eq!(LoopMode::Track.requeue(AdvanceReason::TimeLimit), None);
+        assert_eq!(LoopMode::Track.requeue(AdvanceReason::Crossfade), Some(Requeue::Front));
+        assert_eq!(LoopMode::Off.cycle().cycle().cycle(), LoopMode::Off);
+    }
+
+    #[test]
+    fn votes_needed_rounds_up() {
+        assert_eq!(votes_needed(4, 0.5), 2);
+        assert_eq!(votes_needed(5, 0.5), 3);
+        assert_eq!(votes_needed(0, 0.5), 1);
+        assert_eq!(votes_needed(3, 1.0), 3);
+        assert_eq!(votes_needed(3, 2.0), 3);
+    }
+
+    #[test]
+    fn votes_reset_on_track_change() {
+        let mut player = GuildPlayer::new();
+        player.transition(Advance);
+        player.transition(Started);
+        player.skip_votes.insert(1);
+
+        player.transition(Pause);
+        assert_eq!(player.skip_votes.len(), 1);
+
+        player.transition(Advance);
+        assert!(player.skip_votes.is_empty());
+    }
+
+    #[test]
+    fn invalid_events_keep_state() {
+        let mut player = GuildPlayer::new();
+        assert_eq!(player.transition(Started), None);
+        assert_eq!(player.state, Idle);
+        assert!(!player.state.is_active());
+
+        player.transition(Advance);
+        player.transition(Started);
+        assert_eq!(player.state, Playing);
+        assert!(player.state.is_active());
+    }
+
+    #[test]
+    fn remaining_follows_offset_and_tempo() {
+        let mut player = GuildPlayer::new();
+        assert_eq!(player.remaining(0.0), None);
+
+        player.current = Some(PlaylistItem { duration: Some(200.0), ..PlaylistItem::for_test("youtube", "abc", "song") });
+        assert_eq!(player.remaining(50.0), Some(150.0));
+
+        // Restarted at 100s with double speed
+        player.source_offset = 100.0;
+        player.filter.speed = 2.0;
+        assert_eq!(player.remaining(10.0), Some(40.0));
+
+        player.current.as_mut().unwrap().is_live = Some(true);
+        assert_eq!(player.remaining(10.0), None);
+    }
+
+    #[test]
+    fn live_reconnects_back_off_and_give_up() {
+        let mut player = GuildPlayer::new();
+        let delays: Vec<u64> = std::iter::from_fn(|| player.next_reconnect()).map(|delay| delay.as_secs()).collect();
+        assert_eq!(delays, [1, 2, 4, 8, 16]);
+        assert_eq!(reconnect_delay(10), Duration::from_secs(30));
+    }
+
+    #[test]
+    fn unknown_length_songs_move_the_queue_on() {
+        let pls = "[playlist]\nFile1=http://example.com/song.mp3\nLength1=-1\n";
+        let mut player = GuildPlayer::new();
+        player.current = crate::playlist_formats::PlaylistFormat::Pls.import(pls).unwrap().pop();
+        assert!(!player.reconnects());
+        assert_eq!(player.remaining(10.0), None);
+
+        // Only items found to be streams are started again when they end
+        player.current.as_mut().unwrap().is_live = Some(true);
+        assert!(player.reconnects());
+    }
+}
@@ -0,0 +1,372 @@
+use anyhow::anyhow;
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use reqwest::header::{HeaderMap, CONTENT_LENGTH, CONTENT_TYPE};
+use tokio::task::JoinSet;
+
+use crate::pot::{PlaylistItem, track_key};
+
+/// Largest playlist file that is downloaded, bigger files are not playlists of songs
+pub const MAX_FILE_SIZE: u64 = 1024 * 1024;
+
+/// Playlist files are small, a download that takes longer is probably a stream
+const FETCH_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// Time a stream url gets to answer with its headers
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Playlist files the queue can be exported to and imported from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, poise::ChoiceParameter)]
+pub enum PlaylistFormat {
+    #[name = "M3U8"]
+    M3u,
+    #[name = "XSPF"]
+    Xspf,
+    #[name = "JSON"]
+    Json,
+    #[name = "PLS"]
+    Pls,
+}
+
+impl PlaylistFormat {
+    /// Guess the format from the extension of a file name or url path
+    pub fn from_path(path: &str) -> Option<Self> {
+        let extension = path.rsplit_once('.')?.1.to_lowercase();
+
+        match extension.as_str() {
+            "m3u" | "m3u8" => Some(PlaylistFormat::M3u),
+            "xspf" => Some(PlaylistFormat::Xspf),
+            "json" => Some(PlaylistFormat::Json),
+            "pls" => Some(PlaylistFormat::Pls),
+            _ => None,
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            PlaylistFormat::M3u => "m3u8",
+            PlaylistFormat::Xspf => "xspf",
+            PlaylistFormat::Json => "json",
+            PlaylistFormat::Pls => "pls",
+        }
+    }
+
+    pub fn export(&self, items: &[PlaylistItem]) -> String {
+        match self {
+            PlaylistFormat::M3u => export_m3u(items),
+            PlaylistFormat::Xspf => export_xspf(items),
+            PlaylistFormat::Json => export_json(items),
+            PlaylistFormat::Pls => export_pls(items),
+        }
+    }
+
+    /// Read the items of a playlist file, entries that are not http urls are left out
+    pub fn import(&self, content: &str) -> anyhow::Result<Vec<PlaylistItem>> {
+        match self {
+            PlaylistFormat::M3u => Ok(import_m3u(content)),
+            PlaylistFormat::Xspf => Ok(import_xspf(content)),
+            PlaylistFormat::Json => import_json(content),
+            PlaylistFormat::Pls => Ok(import_pls(content)),
+        }
+    }
+}
+
+/// HLS media playlists are also m3u8 files, but they are a single stream and not a list of songs
+pub fn is_media_playlist(content: &str) -> bool {
+    content.contains("#EXT-X-")
+}
+
+/// Download a playlist file linked in /play, None when the url is not a playlist file
+pub async fn fetch(url: &url::Url) -> anyhow::Result<Option<Vec<PlaylistItem>>> {
+    let format = match PlaylistFormat::from_path(url.path()) {
+        Some(format @ (PlaylistFormat::M3u | PlaylistFormat::Pls)) => format,
+        _ => return Ok(None),
+    };
+
+    let client = reqwest::Client::builder().timeout(FETCH_TIMEOUT).build()?;
+    let mut response = client.get(url.as_str()).send().await?;
+    if response.content_length().unwrap_or(0) > MAX_FILE_SIZE {
+        return Err(anyhow!("Playlist file is too big"));
+    }
+
+    // Chunked responses have no length, stop reading once the body is too big instead of trusting the header
+    let mut body = Vec::new();
+    while let Some(chunk) = response.chunk().await? {
+        if (body.len() + chunk.len()) as u64 > MAX_FILE_SIZE {
+            return Err(anyhow!("Playlist file is too big"));
+        }
+        body.extend_from_slice(&chunk);
+    }
+    let content = String::from_utf8_lossy(&body);
+
+    if is_media_playlist(&content) {
+        return Ok(None);
+    }
+
+    format.import(&content).map(Some)
+}
+
+/// Internet radio servers send icy headers, other streams are audio without a length
+pub fn is_stream_response(headers: &HeaderMap) -> bool {
+    if headers.keys().any(|name| name.as_str().starts_with("icy-")) {
+        return true;
+    }
+
+    let content_type = headers.get(CONTENT_TYPE).and_then(|value| value.to_str().ok()).unwrap_or_default().to_lowercase();
+    let is_audio = content_type.starts_with("audio/") || content_type.starts_with("application/ogg");
+    is_audio && !headers.contains_key(CONTENT_LENGTH)
+}
+
+/// Mark the items that are radio streams as live, a missing length alone does not make an item live
+///
+/// Only generic urls without a length or a live status are asked, their response headers tell if they are streams
+pub async fn mark_streams(items: &mut [PlaylistItem]) {
+    let client = match reqwest::Client::builder().timeout(PROBE_TIMEOUT).build() {
+        Ok(client) => client,
+        Err(_) => return,
+    };
+
+    let mut probes = JoinSet::new();
+    for (index, item) in items.iter().enumerate() {
+        if item.extractor != "generic" || item.is_live.is_some() || item.duration.is_some() {
+            continue;
+        }
+
+        let request = client.get(&item.original_url).header("Icy-MetaData", "1");
+        probes.spawn(async move {
+            // Only the headers are read, the body of a stream never ends
+            let is_stream = request.send().await.is_ok_and(|response| is_stream_response(response.headers()));
+            (index, is_stream)
+        });
+    }
+
+    while let Some(probe) = probes.join_next().await {
+        if let Ok((index, true)) = probe {
+            items[index].is_live = Some(true);
+        }
+    }
+}
+
+/// Build an item from a playlist entry, YouTube urls keep their video id so blocked tracks still match
+fn entry_item(url: &str, title: Option<String>, duration: Option<f32>) -> Option<PlaylistItem> {
+    let parsed = url::Url::parse(url.trim()).ok()?;
+    if !matches!(parsed.scheme(), "http" | "https") {
+        return None;
+    }
+
+    let url = parsed.to_string();
+    let (extractor, id) = match track_key(&url).split_once(':') {
+        Some(("youtube", id)) => ("youtube".to_string(), id.to_string()),
+        _ => ("generic".to_string(), url.to_owned()),
+    };
+
+    Some(PlaylistItem {
+        title: title.filter(|title| !title.trim().is_empty()).unwrap_or_else(|| url.to_owned()),
+        id,
+        original_url: url,
+        extractor,
+        thumbnail: None,
+        duration: duration.filter(|duration| *duration > 0.0),
+        playlist_id: None,
+        webpage_url: None,
+        is_live: None,
+        was_live: None,
+        requester: None,
+        autoplay: false,
+    })
+}
+
+fn export_m3u(items: &[PlaylistItem]) -> String {
+    let mut content = String::from("#EXTM3U\n");
+    for item in items {
+        let duration = item.duration.map(|duration| duration.round() as i64).unwrap_or(-1);
+        content.push_str(&format!("#EXTINF:{},{}\n{}\n", duration, item.title.replace('\n', " "), item.original_url));
+    }
+    content
+}
+
+fn import_m3u(content: &str) -> Vec<PlaylistItem> {
+    let mut items = Vec::new();
+    let mut info: Option<(Option<f32>, Option<String>)> = None;
+
+    for line in content.lines().map(|line| line.trim()) {
+        if let Some(extinf) = line.strip_prefix("#EXTINF:") {
+            let (duration, title) = extinf.split_once(',').unwrap_or((extinf, ""));
+            info = Some((duration.trim().parse::<f32>().ok(), Some(title.trim().to_string())));
+        } else if !line.is_empty() && !line.starts_with('#') {
+            let (duration, title) = info.take().unwrap_or((None, None));
+            items.extend(entry_item(line, title, duration));
+        }
+    }
+
+    items
+}
+
+fn export_pls(items: &[PlaylistItem]) -> String {
+    let mut content = String::from("[playlist]\n");
+    for (index, item) in items.iter().enumerate() {
+        let duration = item.duration.map(|duration| duration.round() as i64).unwrap_or(-1);
+        content.push_str(&format!("File{0}={1}\nTitle{0}={2}\nLength{0}={3}\n", index + 1, item.original_url, item.title.replace('\n', " "), duration));
+    }
+    content.push_str(&format!("NumberOfEntries={}\nVersion=2\n", items.len()));
+    content
+}
+
+/// Keys of a numbered PLS entry
+#[derive(Default)]
+struct PlsEntry {
+    file: Option<String>,
+    title: Option<String>,
+    length: Option<f32>,
+}
+
+fn import_pls(content: &str) -> Vec<PlaylistItem> {
+    // Entries are numbered and their keys can come in any order
+    let mut entries: BTreeMap<u32, PlsEntry> = BTreeMap::new();
+
+    for line in content.lines() {
+        let (key, value) = match line.split_once('=') {
+            Some((key, value)) => (key.trim().to_lowercase(), value.trim()),
+            None => continue,
+        };
+
+        if let Some(number) = key.strip_prefix("file").and_then(|number| number.parse::<u32>().ok()) {
+            entries.entry(number).or_default().file = Some(value.to_string());
+        } else if let Some(number) = key.strip_prefix("title").and_then(|number| number.parse::<u32>().ok()) {
+            entries.entry(number).or_default().title = Some(value.to_string());
+        } else if let Some(number) = key.strip_prefix("length").and_then(|number| number.parse::<u32>().ok()) {
+            entries.entry(number).or_default().length = value.parse::<f32>().ok();
+        }
+    }
+
+    entries.into_values().filter_map(|entry| entry_item(&entry.file?, entry.title, entry.length)).collect()
+}
+
+fn escape_xml(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;").replace('\'', "&apos;")
+}
+
+fn unescape_xml(text: &str) -> String {
+    text.replace("&lt;", "<").replace("&gt;", ">").replace("&quot;", "\"").replace("&apos;", "'").replace("&amp;", "&")
+}
+
+/// Text inside the first <tag>...</tag> of the content
+fn xml_value(content: &str, tag: &str) -> Option<String> {
+    let start = content.find(&format!("<{}>", tag))? + tag.len() + 2;
+    let end = content[start..].find(&format!("</{}>", tag))? + start;
+    Some(unescape_xml(content[start..end].trim()))
+}
+
+fn export_xspf(items: &[PlaylistItem]) -> String {
+    let mut content = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<playlist version=\"1\" xmlns=\"http://xspf.org/ns/0/\">\n  <trackList>\n");
+    for item in items {
+        content.push_str("    <track>\n");
+        content.push_str(&format!("      <location>{}</location>\n", escape_xml(&item.original_url)));
+        content.push_str(&format!("      <title>{}</title>\n", escape_xml(&item.title)));
+        if let Some(duration) = item.duration {
+            // XSPF durations are in milliseconds
+            content.push_str(&format!("      <duration>{}</duration>\n", (duration * 1000.0).round() as u64));
+        }
+        content.push_str("    </track>\n");
+    }
+    content.push_str("  </trackList>\n</playlist>\n");
+    content
+}
+
+fn import_xspf(content: &str) -> Vec<PlaylistItem> {
+    content.split("<track>").skip(1).filter_map(|track| {
+        let track = track.split("</track>").next()?;
+        let duration = xml_value(track, "duration").and_then(|duration| duration.parse::<f32>().ok()).map(|duration| duration / 1000.0);
+        entry_item(&xml_value(track, "location")?, xml_value(track, "title"), duration)
+    }).collect()
+}
+
+fn export_json(items: &[PlaylistItem]) -> String {
+    let items: Vec<PlaylistItem> = items.iter().cloned().map(|mut item| {
+        item.requester = None;
+        item
+    }).collect();
+    serde_json::to_string_pretty(&items).unwrap_or_else(|_| "[]".to_string())
+}
+
+fn import_json(content: &str) -> anyhow::Result<Vec<PlaylistItem>> {
+    let items: Vec<PlaylistItem> = serde_json::from_str(content)?;
+    Ok(items.into_iter().filter_map(|item| {
+        // Only keep what a file from someone else can be trusted with
+        let mut imported = entry_item(&item.original_url, Some(item.title), item.duration)?;
+        imported.is_live = item.is_live;
+        imported.thumbnail = item.thumbnail;
+        Some(imported)
+    }).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn items() -> Vec<PlaylistItem> {
+        vec![
+            entry_item("https://www.youtube.com/watch?v=abc", Some("Song & <Friends>".to_string()), Some(200.0)).unwrap(),
+            entry_item("https://radio.example.com/stream", Some("Radio".to_string()), None).unwrap(),
+        ]
+    }
+
+    #[test]
+    fn youtube_entries_keep_their_id() {
+        let items = items();
+        assert_eq!(items[0].extractor, "youtube");
+        assert_eq!(items[0].id, "abc");
+        assert_eq!(items[1].extractor, "generic");
+        assert!(entry_item("/home/user/song.mp3", None, None).is_none());
+    }
+
+    #[test]
+    fn every_format_round_trips() {
+        for format in [PlaylistFormat::M3u, PlaylistFormat::Xspf, PlaylistFormat::Json, PlaylistFormat::Pls] {
+            let imported = format.import(&format.export(&items())).unwrap();
+            assert_eq!(imported.len(), 2, "{:?}", format);
+            assert_eq!(imported[0].title, "Song & <Friends>", "{:?}", format);
+            assert_eq!(imported[0].duration, Some(200.0), "{:?}", format);
+            assert_eq!(imported[1].original_url, "https://radio.example.com/stream", "{:?}", format);
+            assert_eq!(imported[1].duration, None, "{:?}", format);
+        }
+    }
+
+    #[test]
+    fn radio_station_lists() {
+        let pls = "[playlist]\nNumberOfEntries=2\nFile2=http://b.example.com/\nFile1=http://a.example.com/\nTitle1=A\n";
+        let imported = PlaylistFormat::Pls.import(pls).unwrap();
+        assert_eq!(imported.iter().map(|item| item.title.as_str()).collect::<Vec<_>>(), ["A", "http://b.example.com/"]);
+
+        let m3u = "http://a.example.com/live\n# comment\nsong.mp3\n";
+        assert_eq!(PlaylistFormat::M3u.import(m3u).unwrap().len(), 1);
+        assert!(is_media_playlist("#EXTM3U\n#EXT-X-TARGETDURATION:10\n"));
+    }
+
+    #[test]
+    fn unknown_length_entries_are_not_live() {
+        // Other players write -1 for songs they did not read the length of
+        let pls = "[playlist]\nFile1=http://example.com/song.mp3\nTitle1=Song\nLength1=-1\nFile2=http://example.com/other.mp3\nLength2=180\n";
+        let imported = PlaylistFormat::Pls.import(pls).unwrap();
+        assert_eq!(imported[0].duration, None);
+        assert!(!imported[0].is_live());
+        assert!(!imported[1].is_live());
+    }
+
+    #[test]
+    fn radio_streams_are_told_by_their_headers() {
+        let headers = |pairs: &[(&'static str, &'static str)]| {
+            let mut headers = HeaderMap::new();
+            for (name, value) in pairs {
+                headers.insert(*name, value.parse().unwrap());
+            }
+            headers
+        };
+
+        assert!(is_stream_response(&headers(&[("icy-name", "Radio"), ("content-type", "audio/mpeg")])));
+        assert!(is_stream_response(&headers(&[("content-type", "audio/aacp")])));
+        assert!(!is_stream_response(&headers(&[("content-type", "audio/mpeg"), ("content-length", "3000000")])));
+        assert!(!is_stream_response(&headers(&[("content-type", "text/html")])));
+    }
+}
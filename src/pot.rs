@@ -1,46 +1,67 @@
 use anyhow::{anyhow};
-use serde::{Deserialize};
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
-use std::fs;
 use std::io::{BufReader, BufRead};
-use std::path::Path;
-use std::process::ChildStdout;
 use std::{
+    fs,
     io::{Read},
+    path::Path,
     process::{Command, Stdio},
 };
 
-use poise::{serenity_prelude::{ GuildId, TypeMapKey}};
+use poise::{serenity_prelude::{ GuildId, UserId}};
 
+use tokio::{task, sync::broadcast};
 
-#[cfg(not(feature = "tokio-02-marker"))]
-use tokio::{task};
-#[cfg(feature = "tokio-02-marker")]
-use tokio_compat::{task};
-
+use crate::player::{GuildPlayer, PlayerEvent, PlayerState};
+use crate::settings::GuildSettings;
+use crate::history::{PlayHistory, PlayOutcome};
+use crate::filters::AudioFilter;
+use crate::loudness::LoudnessMeasurement;
+use crate::cache;
 use crate::helpers;
+use crate::shutdown;
+use crate::metrics;
+use crate::dependencies::{self, FFMPEG, YTDLP};
+use crate::library::LOCAL_EXTRACTOR;
 use crate::yt::YoutubeResult;
+use crate::now_playing::NowPlayingEvent;
 
-const YOUTUBE_DL_COMMAND: &str = "yt-dlp";
-
+const YOUTUBE_DL_COMMAND: &str = YTDLP;
+const LIBRARY_PREFIX: &str = "library:";
 
-pub struct SystemPlaylistData;
+/// Now playing events kept for subscribers that fall behind
+const EVENTS_CAPACITY: usize = 64;
 
-impl TypeMapKey for SystemPlaylistData {
-     type Value = SystemPlaylist;
-}
 
 pub struct SystemPlaylist {
     guilds_playlists: HashMap<u64, Vec<PlaylistItem>>,
-    guilds_playing: HashMap<u64, bool>
+    guilds_players: HashMap<u64, GuildPlayer>,
+    pub history: PlayHistory,
+    /// Now playing changes of every guild, nobody listens unless the HTTP API is running
+    pub events: broadcast::Sender<NowPlayingEvent>
 }
 
 pub enum PotPlayInputType {
     Url(url::Url),
-    Search(String)
+    Search(String),
+    /// Search in the local library, written as library:<query>
+    Library(String)
 }
 
 impl PotPlayInputType {
+    /// Urls are fetched as they are, anything else is searched
+    pub fn parse(src: String) -> Self {
+        if let Some(query) = src.strip_prefix(LIBRARY_PREFIX) {
+            return Self::Library(query.trim().to_string());
+        }
+
+        match url::Url::parse(&src) {
+            Ok(url_parsed) => Self::Url(url_parsed),
+            Err(_) => Self::Search(src)
+        }
+    }
+
     fn is_url(&self) -> bool {
         matches!(*self, Self::Url(_))
     }
@@ -72,6 +93,17 @@ fn youtube_url_extractor (url: &url::Url) -> YoutubeUrlType {
     }
 }
 
+/// Turn a YouTube url into extractor:id, anything else is used as it is
+pub fn track_key (input: &str) -> String {
+    match url::Url::parse(input) {
+        Ok(url) => match youtube_url_extractor(&url) {
+            YoutubeUrlType::Video(video_id) => format!("youtube:{}", video_id),
+            _ => input.to_string(),
+        },
+        Err(_) => input.to_string(),
+    }
+}
+
 fn query_pairs_to_hashmap (url: &url::Url) -> HashMap<String, String> {
     let mut map: HashMap<String, String> = HashMap::new();
     for (key, value) in url.query_pairs() {
@@ -97,6 +129,8 @@ fn youtube_result_to_playlist_items (yt_result: YoutubeResult) -> Vec<PlaylistIt
                     webpage_url: None,
                     is_live: None,
                     was_live: None,
+                    requester: None,
+                    autoplay: false,
                 })
             } else {
                 None
@@ -107,34 +141,156 @@ fn youtube_result_to_playlist_items (yt_result: YoutubeResult) -> Vec<PlaylistIt
     }
 }
 
+/// Why an item was not added to the guild playlist
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Rejection {
+    /// The requester already has the maximum number of items queued
+    UserItemLimit(usize),
+    /// The item would take the requester over the maximum queued seconds
+    UserDurationLimit(u32),
+    /// The item is longer than the maximum seconds allowed
+    TooLong(u32),
+    ExtractorNotAllowed(String),
+    LiveNotAllowed,
+    /// The title contains a blocked keyword
+    BlockedKeyword(String),
+    BlockedTrack,
+}
+
+impl std::fmt::Display for Rejection {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Rejection::UserItemLimit(limit) => write!(f, "you can only have {} songs in the queue", limit),
+            Rejection::UserDurationLimit(limit) => write!(f, "you can only have {} minutes of songs in the queue", limit / 60),
+            Rejection::TooLong(limit) => write!(f, "longer than {} minutes", limit / 60),
+            Rejection::ExtractorNotAllowed(extractor) => write!(f, "{} is not an allowed source", extractor),
+            Rejection::LiveNotAllowed => write!(f, "live streams are not allowed"),
+            Rejection::BlockedKeyword(keyword) => write!(f, "the title contains the blocked word \"{}\"", keyword),
+            Rejection::BlockedTrack => write!(f, "the song is blocked"),
+        }
+    }
+}
+
+/// Result of adding items to a guild playlist
+#[derive(Debug, Default)]
+pub struct AddOutcome {
+    pub added: usize,
+    /// Items that were not added and why
+    pub rejected: Vec<(PlaylistItem, Rejection)>,
+}
+
+impl AddOutcome {
+    /// One line per rejection reason with the number of items rejected for it
+    pub fn rejection_summary(&self) -> Vec<String> {
+        let mut reasons: Vec<(String, usize)> = Vec::new();
+
+        for (_, rejection) in &self.rejected {
+            let reason = rejection.to_string();
+            match reasons.iter_mut().find(|(existing, _)| *existing == reason) {
+                Some((_, count)) => *count += 1,
+                None => reasons.push((reason, 1)),
+            }
+        }
+
+        let mut lines: Vec<String> = reasons.into_iter().map(|(reason, count)| {
+            if count > 1 {
+                format!("{} songs not added, {}", count, reason)
+            } else {
+                format!("1 song not added, {}", reason)
+            }
+        }).collect();
+
+        let total = self.added + self.rejected.len();
+        if total > 1 && !self.rejected.is_empty() {
+            lines.push(format!("{} of {} playlist entries were filtered out", self.rejected.len(), total));
+        }
+
+        lines
+    }
+}
+
+/// Check the per user limits of the guild against what the requester already has queued
+fn check_user_limits(queue: &[PlaylistItem], item: &PlaylistItem, settings: &GuildSettings) -> Result<(), Rejection> {
+    let queued: Vec<&PlaylistItem> = queue.iter().filter(|queued| queued.requester == item.requester).collect();
+
+    if let Some(max_items) = settings.max_user_items {
+        if queued.len() >= max_items {
+            return Err(Rejection::UserItemLimit(max_items));
+        }
+    }
+
+    // Items with unknown duration do not count towards the limit
+    if let Some(max_duration) = settings.max_user_duration {
+        let queued_duration: f32 = queued.iter().filter_map(|queued| queued.duration).sum();
+        if queued_duration + item.duration.unwrap_or(0.0) > max_duration as f32 {
+            return Err(Rejection::UserDurationLimit(max_duration));
+        }
+    }
+
+    Ok(())
+}
+
+/// Insert the item so the queue takes one item from each requester in turn
+///
+/// An item is in round N when its requester has N items before it, the new item goes
+/// after every item of its round or an earlier one.
+fn fair_insert(queue: &mut Vec<PlaylistItem>, item: PlaylistItem) {
+    let mut seen: HashMap<Option<u64>, usize> = HashMap::new();
+    let round = queue.iter().filter(|queued| queued.requester == item.requester).count();
+
+    let position = queue.iter().position(|queued| {
+        let queued_round = seen.entry(queued.requester).or_insert(0);
+        *queued_round += 1;
+        *queued_round - 1 > round
+    });
+
+    match position {
+        Some(position) => queue.insert(position, item),
+        None => queue.push(item),
+    }
+}
+
 impl SystemPlaylist {
     pub fn new () -> Self {
         Self {
             guilds_playlists: HashMap::new(),
-            guilds_playing: HashMap::new()
+            guilds_players: HashMap::new(),
+            history: PlayHistory::new(),
+            events: broadcast::channel(EVENTS_CAPACITY).0
         }
     }
 
-    pub fn set_status (&mut self, guild: GuildId, is_playing: bool) {
-        let guild_id = guild.as_u64();
-        if self.guilds_playing.contains_key(guild_id) {
-            let guild_playlist_status = self.guilds_playing.get_mut(guild_id).unwrap();
-            *guild_playlist_status = is_playing;
+    /// Get the player of the guild, creating an idle one if the guild has none
+    pub fn player (&mut self, guild: GuildId) -> &mut GuildPlayer {
+        self.guilds_players.entry(*guild.as_u64()).or_default()
+    }
 
-            println!("status set :{}", guild_playlist_status);
-        } else {
-            self.guilds_playing.insert(guild_id.to_owned(), is_playing);
-            println!("status set :{}", is_playing);
+    pub fn state (&self, guild: GuildId) -> PlayerState {
+        match self.guilds_players.get(guild.as_u64()) {
+            Some(player) => player.state,
+            None => PlayerState::Idle,
         }
     }
 
+    /// Apply a player event to the guild player, returns None if the event is not valid on the current state
+    pub fn transition (&mut self, guild: GuildId, event: PlayerEvent) -> Option<PlayerState> {
+        let result = self.player(guild).transition(event);
+        tracing::debug!(guild_id = guild.0, ?event, state = ?result, "Player event");
+        result
+    }
+
     pub fn is_playing (&self, guild: GuildId) -> bool {
-        let guild_id = guild.as_u64();
-        if self.guilds_playing.contains_key(guild_id) {
-            *self.guilds_playing.get(guild_id).unwrap()
-        } else {
-            false
-        }
+        self.state(guild).is_active()
+    }
+
+    /// Take the item the guild player was on and record it in the guild history
+    pub fn take_current(&mut self, guild: GuildId, outcome: PlayOutcome) -> Option<PlaylistItem> {
+        let player = self.player(guild);
+        let item = player.current.take()?;
+        player.history_cursor = 0;
+        metrics::TRACKS_FINISHED.with_label_values(&[&item.extractor, outcome.label()]).inc();
+        self.history.record(guild, item.clone(), outcome);
+        Some(item)
     }
 
     /// Consumes and return a item from the the guild playlist removing the item
@@ -152,17 +308,16 @@ impl SystemPlaylist {
         }
     }
 
-    /// Try to fetch a playlist or a single media item and add it to the guild playlist
-    pub async fn add(&mut self, guild: GuildId, input: PotPlayInputType) -> anyhow::Result<usize> {
+    /// Try to fetch a playlist or a single media item, the items are not added to any guild playlist
+    ///
+    /// Library searches are not resolved here, they need the library index
+    pub async fn resolve(input: PotPlayInputType, requester: UserId) -> anyhow::Result<Vec<PlaylistItem>> {
         use crate::yt::YoutubeAPI;
 
-        let token_env = option_env!("YOUTUBE_TOKEN");
-        let token = match token_env {
-            Some(token_env) => String::from(token_env),
-            None => panic!("No YOUTUBE_TOKEN set on compile time"),
-        };
+        // `potv2 check` reports a binary built without the token, resolving only fails
+        let token = option_env!("YOUTUBE_TOKEN").ok_or_else(|| anyhow!("The bot was built without YOUTUBE_TOKEN"))?;
 
-        let api = YoutubeAPI::new(&token);
+        let api = YoutubeAPI::new(token);
 
         let is_url = input.is_url();
 
@@ -171,11 +326,16 @@ impl SystemPlaylist {
             let extractor_result = youtube_url_extractor (&url);
             
             if let YoutubeUrlType::Playlist(playlist_id) = extractor_result {
-                Ok(youtube_result_to_playlist_items(api.playlist(&playlist_id).await))
+                Ok(Self::with_video_details(&api, youtube_result_to_playlist_items(api.playlist(&playlist_id).await)).await)
             } else if let YoutubeUrlType::Video(video_id) = extractor_result {
-                Ok(youtube_result_to_playlist_items(api.video(&video_id).await))
+                Ok(Self::with_video_details(&api, youtube_result_to_playlist_items(api.video(&video_id).await)).await)
             } else {
-                Self::get_playlist(url.as_str()).await
+                // Playlist files like internet radio station lists are read here, yt-dlp only plays their first entry
+                match crate::playlist_formats::fetch(&url).await {
+                    Ok(Some(items)) => Ok(items),
+                    Ok(None) => Self::get_playlist(url.as_str()).await,
+                    Err(err) => Err(err),
+                }
             }
         } else if let PotPlayInputType::Search(query) = input {
             Self::get_playlist(&format!("ytsearch1:{}", query)).await
@@ -185,45 +345,138 @@ impl SystemPlaylist {
 
         match playlist_result {
             Ok(mut playlist) => {
+                crate::playlist_formats::mark_streams(&mut playlist).await;
+                for item in playlist.iter_mut() {
+                    item.requester = Some(*requester.as_u64());
+                }
 
-                if self.guilds_playlists.contains_key(guild.as_u64()) { // Guild playlist already exist
-                    let guild_playlist = self.guilds_playlists.get_mut(guild.as_u64()).unwrap();
-
-                    if is_url {
-                        let playlist_size = playlist.len();
-                        guild_playlist.append(&mut playlist);
-                        Ok(playlist_size)
-                    } else {
-                        match playlist.get(0) {
-                            Some(item) => {
-                                guild_playlist.push(item.to_owned());
-                                Ok(1)
-                            },
-                            None => Ok(0),
-                        }
-                        
-                    }
-                } else { // Guild playlist not exist creating one
-                    let playlist_size = playlist.len();
-
-                    if is_url {
-                        self.guilds_playlists.insert(*guild.as_u64(), playlist);
-                        Ok(playlist_size)
-                    } else if playlist_size > 0 {
-                        self.guilds_playlists.insert(*guild.as_u64(), Vec::new());
-                        let guild_playlist = self.guilds_playlists.get_mut(guild.as_u64()).unwrap();
-                        guild_playlist.push(playlist.get(0).unwrap().to_owned());
-                        Ok(1)
-                    } else {
-                        Ok(0)
-                    }
-                    
+                // Searches only queue the first result
+                if !is_url {
+                    playlist.truncate(1);
                 }
+
+                Ok(playlist)
             },
             Err(err) => Err(err),
         }
     }
 
+    /// The YouTube API playlist items do not include durations, fetch them so the admission policy can use them
+    async fn with_video_details(api: &crate::yt::YoutubeAPI, mut items: Vec<PlaylistItem>) -> Vec<PlaylistItem> {
+        let ids: Vec<String> = items.iter().map(|item| item.id.to_owned()).collect();
+        let details = api.video_details(&ids).await;
+
+        for item in items.iter_mut() {
+            if let Some(details) = details.get(&item.id) {
+                item.duration = details.duration;
+                item.is_live = Some(details.is_live);
+            }
+        }
+
+        items
+    }
+
+    /// Add resolved items to the guild playlist, applying the guild admission policy and queue settings
+    pub fn add(&mut self, guild: GuildId, items: Vec<PlaylistItem>, settings: &GuildSettings) -> AddOutcome {
+        let guild_playlist = self.guilds_playlists.entry(*guild.as_u64()).or_default();
+        let mut outcome = AddOutcome::default();
+
+        for item in items {
+            if let Err(rejection) = settings.admission.check(&item) {
+                outcome.rejected.push((item, rejection));
+                continue;
+            }
+
+            if let Err(rejection) = check_user_limits(guild_playlist, &item, settings) {
+                outcome.rejected.push((item, rejection));
+                continue;
+            }
+
+            if settings.fair_queue {
+                fair_insert(guild_playlist, item);
+            } else {
+                guild_playlist.push(item);
+            }
+            outcome.added += 1;
+        }
+
+        outcome
+    }
+
+    /// Items waiting in the guild playlist
+    pub fn items(&self, guild: GuildId) -> &[PlaylistItem] {
+        match self.guilds_playlists.get(guild.as_u64()) {
+            Some(guild_playlist) => guild_playlist,
+            None => &[],
+        }
+    }
+
+    /// Remove the item at the index from the guild playlist
+    pub fn remove(&mut self, guild: GuildId, index: usize) -> Option<PlaylistItem> {
+        let guild_playlist = self.guilds_playlists.get_mut(guild.as_u64())?;
+
+        if index < guild_playlist.len() {
+            Some(guild_playlist.remove(index))
+        } else {
+            None
+        }
+    }
+
+    /// Move the item at the index to another position of the guild playlist, returns false if either index is out of the queue
+    pub fn move_item(&mut self, guild: GuildId, from: usize, to: usize) -> bool {
+        let guild_playlist = match self.guilds_playlists.get_mut(guild.as_u64()) {
+            Some(guild_playlist) => guild_playlist,
+            None => return false,
+        };
+
+        if from >= guild_playlist.len() || to >= guild_playlist.len() {
+            return false;
+        }
+
+        let item = guild_playlist.remove(from);
+        guild_playlist.insert(to, item);
+        true
+    }
+
+    /// Put an item back at the front of the guild playlist
+    pub fn push_front(&mut self, guild: GuildId, item: PlaylistItem) {
+        self.guilds_playlists.entry(*guild.as_u64()).or_default().insert(0, item);
+    }
+
+    /// Put an item at the end of the guild playlist
+    pub fn push_back(&mut self, guild: GuildId, item: PlaylistItem) {
+        self.guilds_playlists.entry(*guild.as_u64()).or_default().push(item);
+    }
+
+    /// Guilds with a queue or a player
+    pub fn guilds(&self) -> Vec<GuildId> {
+        let mut guilds: Vec<u64> = self.guilds_playlists.keys().chain(self.guilds_players.keys()).copied().collect();
+        guilds.sort_unstable();
+        guilds.dedup();
+        guilds.into_iter().map(GuildId).collect()
+    }
+
+    /// Number of items waiting in the guild playlist
+    pub fn len(&self, guild: GuildId) -> usize {
+        match self.guilds_playlists.get(guild.as_u64()) {
+            Some(guild_playlist) => guild_playlist.len(),
+            None => 0,
+        }
+    }
+
+    /// Shuffle the items waiting in the guild playlist, returns the number of items shuffled
+    pub fn shuffle(&mut self, guild: GuildId) -> usize {
+        use rand::seq::SliceRandom;
+
+        match self.guilds_playlists.get_mut(guild.as_u64()) {
+            Some(guild_playlist) => {
+                guild_playlist.shuffle(&mut rand::thread_rng());
+                guild_playlist.len()
+            },
+            None => 0,
+        }
+    }
+
     /// Remove all items from the playlist and returns true if the playlist is cleared of false if the guild has no playlist
     pub fn clear(&mut self, guild: GuildId) -> bool{
         if self.guilds_playlists.contains_key(guild.as_u64()) { // Guild playlist already exist
@@ -238,6 +491,8 @@ impl SystemPlaylist {
 
     /// Fetch playlist with yt-dlp and parse the result
     async fn get_playlist (url: &str) -> anyhow::Result<Vec<PlaylistItem>> {
+        dependencies::ensure(YOUTUBE_DL_COMMAND)?;
+
         let ytdl_args = [
             "-j",
             "-f",
@@ -252,12 +507,14 @@ impl SystemPlaylist {
             "-",
         ];
 
+        let _timer = metrics::process_timer(YOUTUBE_DL_COMMAND, "resolve");
         let mut ytdlp_child = Command::new(YOUTUBE_DL_COMMAND)
         .args(ytdl_args)
         .stdin(Stdio::null())
         .stderr(Stdio::piped())
         .stdout(Stdio::piped())
-        .spawn()?;
+        .spawn()
+        .inspect_err(|_| metrics::spawn_failed(YOUTUBE_DL_COMMAND))?;
 
         // This rigmarole is required due to the inner synchronous reading context.
         let stderr = ytdlp_child.stderr.take();
@@ -277,54 +534,105 @@ impl SystemPlaylist {
         let jsons: Vec<&str> = value.split('\n').collect();
 
         let items: Vec<PlaylistItem> = jsons.iter().filter_map(|json_str| {
-            match serde_json::from_str::<PlaylistItem>(json_str) {
-                Ok(item) => Some(item),
-                Err(_) => None,
-            }
+            serde_json::from_str::<PlaylistItem>(json_str).ok()
         }).collect();
 
         Ok(items)
     }
 
-    pub async fn get_media (&self, item: &PlaylistItem) -> Option<songbird::input::Restartable> {
-        let _ = helpers::graceful_mkdir("data/cache");
-        let fpath = format!("data/cache/media/{}/{}", item.extractor, item.id);
-        let path = Path::new(&fpath);
+    /// Videos of the YouTube mix of a video, only their ids and titles are read so it is quick
+    pub async fn youtube_mix(video_id: &str, length: usize) -> anyhow::Result<Vec<PlaylistItem>> {
+        dependencies::ensure(YOUTUBE_DL_COMMAND)?;
+        let url = format!("https://www.youtube.com/watch?v={0}&list=RD{0}", video_id);
+        let playlist_end = length.to_string();
+
+        let _timer = metrics::process_timer(YOUTUBE_DL_COMMAND, "mix");
+        let output = task::spawn_blocking(move || {
+            let ytdl_args = [
+                "-j",
+                "--flat-playlist",
+                "--playlist-end",
+                &playlist_end,
+                "--ignore-config",
+                "--no-warnings",
+                &url,
+            ];
+
+            Command::new(YOUTUBE_DL_COMMAND)
+                .args(ytdl_args)
+                .stdin(Stdio::null())
+                .stderr(Stdio::null())
+                .output()
+                .inspect_err(|_| metrics::spawn_failed(YOUTUBE_DL_COMMAND))
+        }).await??;
+
+        let items = String::from_utf8_lossy(&output.stdout).lines().filter_map(|line| {
+            let entry = serde_json::from_str::<FlatPlaylistEntry>(line).ok()?;
+            Some(PlaylistItem {
+                original_url: format!("https://www.youtube.com/watch?v={}", entry.id),
+                id: entry.id,
+                title: entry.title?,
+                extractor: "youtube".to_string(),
+                thumbnail: None,
+                duration: entry.duration,
+                playlist_id: None,
+                webpage_url: None,
+                is_live: entry.live_status.map(|status| status == "is_live"),
+                was_live: None,
+                requester: None,
+                autoplay: false,
+            })
+        }).collect();
 
-        let file_path = if Self::check_file(path) {
-            println!("Loaded from cache");
-            Some(path.to_str().unwrap().to_string())
-        } else {
-            println!("Loaded from ytdl");
-            let path_str = path.to_str().unwrap();
+        Ok(items)
+    }
 
-            Self::ytdlp_download(path_str, &item.original_url).await;
-    
-            if Self::check_file(path) {
-                Some(path_str.to_string())
-            } else {
-                None
+    /// Play the item through the filters, starting `start` seconds into the source
+    ///
+    /// Cached items and library files are read from disk, anything else is streamed, and cached in the background for the next time when the media cache is on
+    ///
+    /// Live streams are always streamed, they would never finish downloading
+    pub async fn get_media_stream(&self, item: &PlaylistItem, filter: &AudioFilter, start: f32) -> anyhow::Result<songbird::input::Input> {
+        if item.is_live() {
+            let ytdlp_child = Self::ytdlp_stream(&item.original_url).await?;
+            return Self::ffmpeg_to_input(ytdlp_child, filter, 0.0).await;
+        }
+
+        if let Some(path) = cache::cached_media(item) {
+            if item.extractor != LOCAL_EXTRACTOR {
+                metrics::CACHE_HITS.inc();
             }
-        };
+            let loudness = match cache::load_meta(item) {
+                Some(meta) => meta.loudness,
+                None => {
+                    cache::measure_in_background(item);
+                    None
+                },
+            };
+            return Self::ffmpeg_file_input(&path.to_string_lossy(), filter, loudness.as_ref(), start);
+        }
 
-        match file_path {
-            Some(file_path) => {
-                match songbird::input::Restartable::ffmpeg(file_path, false).await {
-                    Ok(source) => Some(source),
-                    Err(_why) => None,
-                }
-            },
-            None => None,
+        if item.extractor == LOCAL_EXTRACTOR {
+            return Err(anyhow!("{} is no longer in the library", item.original_url));
+        }
+
+        if cache::is_enabled() {
+            metrics::CACHE_MISSES.inc();
+            cache::store_in_background(item);
         }
-    }
 
-    pub async fn get_media_stream(&self, item: &PlaylistItem) -> anyhow::Result<songbird::input::Input> {
         let ytdlp_child = Self::ytdlp_stream(&item.original_url).await?;
-        let input = Self::ffmpeg_to_input(ytdlp_child).await?;
+        let input = Self::ffmpeg_to_input(ytdlp_child, filter, start).await?;
         Ok(input)
     }
 
-    pub async fn ytdlp_download(path_str: &str, item_original_url: &str) {
+    /// Download the item to `path`, it only shows up there once the download is complete
+    pub fn ytdlp_download(path: &Path, item_original_url: &str) -> anyhow::Result<()> {
+        dependencies::ensure(YOUTUBE_DL_COMMAND)?;
+
+        let temp = helpers::temp_path(path);
+        let temp_str = temp.to_string_lossy();
+
         let ytdl_args = [
             "--print-json",
             "-f",
@@ -336,21 +644,38 @@ impl SystemPlaylist {
             "--no-warnings",
             item_original_url,
             "-o",
-            path_str,
+            &temp_str,
         ];
 
-        let mut yt_dlp = Command::new(YOUTUBE_DL_COMMAND)
+        let _timer = metrics::process_timer(YOUTUBE_DL_COMMAND, "download");
+        let yt_dlp = Command::new(YOUTUBE_DL_COMMAND)
             .args(ytdl_args)
             .stdin(Stdio::null())
             .stderr(Stdio::null())
             .stdout(Stdio::null())
-            .spawn().expect("ytdlp failed to execute");
+            .spawn()
+            .inspect_err(|_| metrics::spawn_failed(YOUTUBE_DL_COMMAND))
+            .map_err(|err| anyhow!("{} could not be started: {}", YOUTUBE_DL_COMMAND, err))?;
+
+        let status = shutdown::track(yt_dlp).wait()?;
+        let moved = if status.success() {
+            helpers::move_file(&temp, path).map_err(anyhow::Error::from)
+        } else {
+            Err(anyhow!("{} exited with {}", YOUTUBE_DL_COMMAND, status))
+        };
 
-        let _ = yt_dlp.wait();
+        if moved.is_err() {
+            // yt-dlp writes to a .part file until the download finishes
+            let _ = fs::remove_file(&temp);
+            let _ = fs::remove_file(format!("{}.part", temp_str));
+        }
+        moved
     }
 
     // Calls yt-dlp and gets the file data from stdout
     pub async fn ytdlp_stream(item_original_url: &str) -> anyhow::Result<std::process::Child> {
+        dependencies::ensure(YOUTUBE_DL_COMMAND)?;
+
         let ytdl_args = [
             "--print-json",
             "-f",
@@ -367,16 +692,20 @@ impl SystemPlaylist {
 
         // let log = fs::File::create("debug.txt").expect("failed to open log");
 
+        // Time until yt-dlp printed the item and the audio starts coming
+        let _timer = metrics::process_timer(YOUTUBE_DL_COMMAND, "stream");
         let mut yt_dlp = Command::new(YOUTUBE_DL_COMMAND)
             .args(ytdl_args)
             .stdin(Stdio::null())
             .stderr(Stdio::piped())
             .stdout(Stdio::piped())
-            .spawn().expect("ytdlp failed to execute");
+            .spawn()
+            .inspect_err(|_| metrics::spawn_failed(YOUTUBE_DL_COMMAND))
+            .map_err(|err| anyhow!("{} could not be started: {}", YOUTUBE_DL_COMMAND, err))?;
 
         // This rigmarole is required due to the inner synchronous reading context.
         let stderr = yt_dlp.stderr.take();
-        let returned_stderr = task::spawn_blocking(move || {
+        let returned_stderr = match task::spawn_blocking(move || {
             let mut children_stderr = stderr.unwrap();
 
             let mut reader = BufReader::new(children_stderr.by_ref());
@@ -386,16 +715,62 @@ impl SystemPlaylist {
 
             children_stderr
         })
-        .await?;
+        .await {
+            Ok(returned_stderr) => returned_stderr,
+            Err(err) => {
+                // Do not leave the child running if we cannot read from it
+                let _ = yt_dlp.kill();
+                let _ = yt_dlp.wait();
+                return Err(err.into());
+            }
+        };
 
         yt_dlp.stderr = Some(returned_stderr);
 
         Ok(yt_dlp)
     }
 
-    pub async fn ffmpeg_to_input(mut input: std::process::Child) -> anyhow::Result<songbird::input::Input>{
+    pub async fn ffmpeg_to_input(mut input: std::process::Child, filter: &AudioFilter, start: f32) -> anyhow::Result<songbird::input::Input>{
         let taken_stdout = input.stdout.take().ok_or_else(|| anyhow!("Failed to take children stdout"))?;
 
+        // The input is a pipe, seeking is done by decoding and dropping the audio before the start
+        let mut input_args = vec!["-i".to_string(), "-".to_string()];
+        if start > 0.0 {
+            input_args.push("-ss".to_string());
+            input_args.push(format!("{:.3}", start));
+        }
+
+        Self::ffmpeg_input(input_args, Stdio::from(taken_stdout), vec![input], filter, None)
+    }
+
+    /// Play a cached file, with a loudness measurement the normalization applies the exact gain
+    pub fn ffmpeg_file_input(path: &str, filter: &AudioFilter, loudness: Option<&LoudnessMeasurement>, start: f32) -> anyhow::Result<songbird::input::Input> {
+        // Files can be seeked, -ss before -i jumps straight to the start
+        let input_args = vec!["-ss".to_string(), format!("{:.3}", start), "-i".to_string(), path.to_string()];
+
+        Self::ffmpeg_input(input_args, Stdio::null(), Vec::new(), filter, loudness)
+    }
+
+    fn ffmpeg_input(input_args: Vec<String>, stdin: Stdio, mut children: Vec<std::process::Child>, filter: &AudioFilter, loudness: Option<&LoudnessMeasurement>) -> anyhow::Result<songbird::input::Input> {
+        // Nothing would read the output of the children, do not leave them running
+        let stop_children = |children: &mut Vec<std::process::Child>| {
+            for child in children.iter_mut() {
+                let _ = child.kill();
+                let _ = child.wait();
+            }
+        };
+
+        if let Err(err) = dependencies::ensure(FFMPEG) {
+            stop_children(&mut children);
+            return Err(err);
+        }
+
+        let mut filter_args = Vec::new();
+        if let Some(graph) = filter.graph(loudness) {
+            filter_args.push("-af".to_string());
+            filter_args.push(graph);
+        }
+
         let ffmpeg_args = [
             "-f",
             "s16le",
@@ -408,51 +783,32 @@ impl SystemPlaylist {
             "-",
         ];
 
-        let ffmpeg = Command::new("ffmpeg")
-            .arg("-i")
-            .arg("-")
+        let ffmpeg = Command::new(FFMPEG)
+            .args(input_args)
+            .args(filter_args)
             .args(ffmpeg_args)
-            .stdin(taken_stdout)
+            .stdin(stdin)
             .stderr(Stdio::null())
             .stdout(Stdio::piped())
-            .spawn()?;
+            .spawn();
+        let ffmpeg = match ffmpeg {
+            Ok(ffmpeg) => ffmpeg,
+            Err(err) => {
+                metrics::spawn_failed(FFMPEG);
+                stop_children(&mut children);
+                return Err(anyhow!("{} could not be started: {}", FFMPEG, err));
+            },
+        };
+        children.push(ffmpeg);
 
         Ok(songbird::input::Input::new(
             true,
-            songbird::input::children_to_reader::<f32>(vec![input, ffmpeg]),
+            songbird::input::children_to_reader::<f32>(children),
             songbird::input::Codec::FloatPcm,
             songbird::input::Container::Raw,
             Default::default(),
         ))
     }
-
-    pub async fn save_stdout(input: ChildStdout) -> anyhow::Result<()>{
-        let tee_args = [
-            "debug.txt",
-        ];
-
-        let mut _tee = Command::new("tee")
-            .args(tee_args)
-            .stdin(input)
-            .stderr(Stdio::inherit())
-            .stdout(Stdio::inherit())
-            .spawn()?;
-        
-            // let _ = _tee.wait();
-
-        Ok(())
-    }
-
-    fn check_file (path: &Path) -> bool {
-        match fs::metadata(path) {
-            Ok(attributes) => {
-                !attributes.is_dir()
-            },
-            Err(_) => {
-                false
-            }
-        }
-    }
 }
 
 impl Default for SystemPlaylist {
@@ -461,8 +817,16 @@ impl Default for SystemPlaylist {
     }
 }
 
-    
-#[derive(Debug, Deserialize, Clone)]
+/// Entry of a playlist listed with --flat-playlist, the videos are not resolved
+#[derive(Deserialize)]
+struct FlatPlaylistEntry {
+    id: String,
+    title: Option<String>,
+    duration: Option<f32>,
+    live_status: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
 #[allow(dead_code)]
 pub struct PlaylistItem {
     pub id: String,
@@ -474,5 +838,122 @@ pub struct PlaylistItem {
     pub playlist_id: Option<String>,
     pub webpage_url: Option<String>,
     pub is_live: Option<bool>,
-    pub was_live: Option<bool>
+    pub was_live: Option<bool>,
+    /// Discord user id of who queued the item
+    #[serde(default)]
+    pub requester: Option<u64>,
+    /// Picked by autoplay when the queue ran out, nobody requested it
+    #[serde(default)]
+    pub autoplay: bool
+}
+
+impl PlaylistItem {
+    /// Live streams have no end, they are never cached and can play for as long as the guild allows
+    pub fn is_live(&self) -> bool {
+        self.is_live == Some(true)
+    }
+
+    /// extractor:id of the item, what /settings block stores
+    pub fn key(&self) -> String {
+        format!("{}:{}", self.extractor.to_lowercase(), self.id)
+    }
+
+    /// Page of the item that can be linked in Discord, local files have none
+    pub fn link(&self) -> Option<&str> {
+        let url = self.webpage_url.as_ref().unwrap_or(&self.original_url);
+        if url.starts_with("http://") || url.starts_with("https://") {
+            Some(url)
+        } else {
+            None
+        }
+    }
+
+    /// Item with only an extractor, an id and a title, for tests
+    #[cfg(test)]
+    pub fn for_test(extractor: &str, id: &str, title: &str) -> Self {
+        Self {
+            id: id.to_string(),
+            title: title.to_string(),
+            original_url: String::new(),
+            extractor: extractor.to_string(),
+            thumbnail: None,
+            duration: None,
+            playlist_id: None,
+            webpage_url: None,
+            is_live: None,
+            was_live: None,
+            requester: None,
+            autoplay: false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn item(title: &str, requester: u64, duration: Option<f32>) -> PlaylistItem {
+        PlaylistItem { requester: Some(requester), duration, ..PlaylistItem::for_test("youtube", title, title) }
+    }
+
+    fn titles(queue: &[PlaylistItem]) -> Vec<&str> {
+        queue.iter().map(|item| item.title.as_str()).collect()
+    }
+
+    #[test]
+    fn fair_insert_takes_turns() {
+        let mut queue = Vec::new();
+        for title in ["a1", "a2", "a3"] {
+            fair_insert(&mut queue, item(title, 1, None));
+        }
+        fair_insert(&mut queue, item("b1", 2, None));
+        fair_insert(&mut queue, item("b2", 2, None));
+        fair_insert(&mut queue, item("c1", 3, None));
+
+        assert_eq!(titles(&queue), ["a1", "b1", "c1", "a2", "b2", "a3"]);
+    }
+
+    #[test]
+    fn move_item_stays_inside_the_queue() {
+        let guild = GuildId(1);
+        let mut playlist = SystemPlaylist::new();
+        for title in ["a", "b", "c"] {
+            playlist.push_back(guild, item(title, 1, None));
+        }
+
+        assert!(playlist.move_item(guild, 2, 0));
+        assert_eq!(titles(playlist.items(guild)), ["c", "a", "b"]);
+        assert!(playlist.move_item(guild, 0, 2));
+        assert_eq!(titles(playlist.items(guild)), ["a", "b", "c"]);
+        assert!(!playlist.move_item(guild, 1, 3));
+        assert!(!playlist.move_item(GuildId(2), 0, 0));
+    }
+
+    #[test]
+    fn fair_insert_keeps_arrival_order_within_a_round() {
+        let mut queue = Vec::new();
+        fair_insert(&mut queue, item("a1", 1, None));
+        fair_insert(&mut queue, item("b1", 2, None));
+        fair_insert(&mut queue, item("a2", 1, None));
+        fair_insert(&mut queue, item("b2", 2, None));
+
+        assert_eq!(titles(&queue), ["a1", "b1", "a2", "b2"]);
+    }
+
+    #[test]
+    fn user_limits() {
+        let settings = GuildSettings {
+            max_user_items: Some(2),
+            max_user_duration: Some(600),
+            ..Default::default()
+        };
+        let queue = vec![item("a1", 1, Some(300.0)), item("b1", 2, None)];
+
+        assert_eq!(check_user_limits(&queue, &item("a2", 1, Some(200.0)), &settings), Ok(()));
+        assert_eq!(check_user_limits(&queue, &item("a2", 1, Some(400.0)), &settings), Err(Rejection::UserDurationLimit(600)));
+
+        let queue = vec![item("a1", 1, None), item("a2", 1, None)];
+        assert_eq!(check_user_limits(&queue, &item("a3", 1, None), &settings), Err(Rejection::UserItemLimit(2)));
+        assert_eq!(check_user_limits(&queue, &item("b1", 2, None), &settings), Ok(()));
+    }
 }
\ No newline at end of file
@@ -0,0 +1,193 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+
+use poise::serenity_prelude::GuildId;
+
+use crate::helpers;
+use crate::layout::{layout, DataLayout};
+use crate::pot::PlaylistItem;
+
+
+/// Longest name a saved playlist can have, Discord choices cannot be longer than 100
+const MAX_NAME_LENGTH: usize = 50;
+
+/// A named list of items saved by a member of the guild
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SavedPlaylist {
+    pub name: String,
+    /// User that created the playlist, only they or a server manager can change it
+    pub owner: u64,
+    pub items: Vec<PlaylistItem>,
+}
+
+impl SavedPlaylist {
+    pub fn can_edit(&self, user_id: u64, is_manager: bool) -> bool {
+        is_manager || self.owner == user_id
+    }
+}
+
+#[derive(Debug)]
+pub enum SavedPlaylistError {
+    InvalidName,
+    NotFound(String),
+    /// The playlist belongs to another member
+    NotOwner(u64),
+    Io(std::io::Error),
+}
+
+impl std::fmt::Display for SavedPlaylistError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            SavedPlaylistError::InvalidName => write!(f, "Playlist names must have between 1 and {} characters", MAX_NAME_LENGTH),
+            SavedPlaylistError::NotFound(name) => write!(f, "There is no playlist named {}", name),
+            SavedPlaylistError::NotOwner(owner) => write!(f, "This playlist belongs to <@{}>", owner),
+            SavedPlaylistError::Io(err) => write!(f, "Cannot save the playlists: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for SavedPlaylistError {}
+
+impl From<std::io::Error> for SavedPlaylistError {
+    fn from(err: std::io::Error) -> Self {
+        SavedPlaylistError::Io(err)
+    }
+}
+
+/// Trim the name and check its length
+pub fn validate_name(name: &str) -> Result<String, SavedPlaylistError> {
+    let name = name.trim();
+    if name.is_empty() || name.chars().count() > MAX_NAME_LENGTH {
+        return Err(SavedPlaylistError::InvalidName);
+    }
+    Ok(name.to_string())
+}
+
+/// Saved playlists of every guild, stored as playlists/{guild_id}.json in the data directory
+pub struct SavedPlaylistStore {
+    guilds: HashMap<u64, Vec<SavedPlaylist>>
+}
+
+impl SavedPlaylistStore {
+    pub fn new() -> Self {
+        Self {
+            guilds: HashMap::new()
+        }
+    }
+
+    fn path(guild: GuildId) -> PathBuf {
+        DataLayout::guild_file(&layout().playlists(), guild.0)
+    }
+
+    fn guild_playlists(&mut self, guild: GuildId) -> &mut Vec<SavedPlaylist> {
+        self.guilds.entry(*guild.as_u64()).or_insert_with(|| {
+            match fs::read_to_string(Self::path(guild)) {
+                Ok(json) => serde_json::from_str(&json).unwrap_or_else(|err| {
+                    tracing::error!(guild_id = guild.0, "Cannot parse playlists: {}", err);
+                    Vec::new()
+                }),
+                Err(_) => Vec::new(),
+            }
+        })
+    }
+
+    /// Save the playlists of the guild, they only replace the ones in memory once they are written
+    fn persist(&mut self, guild: GuildId, playlists: Vec<SavedPlaylist>) -> Result<(), SavedPlaylistError> {
+        let json = serde_json::to_string_pretty(&playlists).map_err(std::io::Error::from)?;
+        helpers::write_json(Self::path(guild), json)?;
+        self.guilds.insert(guild.0, playlists);
+        Ok(())
+    }
+
+    /// Every playlist of the guild, loading them from disk the first time
+    pub fn list(&mut self, guild: GuildId) -> &[SavedPlaylist] {
+        self.guild_playlists(guild)
+    }
+
+    /// Find a playlist by name, names are case insensitive
+    pub fn get(&mut self, guild: GuildId, name: &str) -> Option<&SavedPlaylist> {
+        let name = name.trim().to_lowercase();
+        self.guild_playlists(guild).iter().find(|playlist| playlist.name.to_lowercase() == name)
+    }
+
+    /// Replace the items of the playlist, creating it if it does not exist
+    pub fn save(&mut self, guild: GuildId, name: &str, user_id: u64, is_manager: bool, items: Vec<PlaylistItem>) -> Result<usize, SavedPlaylistError> {
+        self.change(guild, name, user_id, is_manager, |playlist| playlist.items = items)
+    }
+
+    /// Add items at the end of the playlist, creating it if it does not exist
+    pub fn append(&mut self, guild: GuildId, name: &str, user_id: u64, is_manager: bool, mut items: Vec<PlaylistItem>) -> Result<usize, SavedPlaylistError> {
+        self.change(guild, name, user_id, is_manager, |playlist| playlist.items.append(&mut items))
+    }
+
+    /// Apply the change to the playlist and save the guild playlists, returns the number of items of the playlist
+    fn change<F>(&mut self, guild: GuildId, name: &str, user_id: u64, is_manager: bool, f: F) -> Result<usize, SavedPlaylistError>
+    where
+        F: FnOnce(&mut SavedPlaylist),
+    {
+        let name = validate_name(name)?;
+        let key = name.to_lowercase();
+        let mut playlists = self.guild_playlists(guild).clone();
+
+        let index = match playlists.iter().position(|playlist| playlist.name.to_lowercase() == key) {
+            Some(index) => index,
+            None => {
+                playlists.push(SavedPlaylist { name, owner: user_id, items: Vec::new() });
+                playlists.len() - 1
+            },
+        };
+
+        let playlist = &mut playlists[index];
+        if !playlist.can_edit(user_id, is_manager) {
+            return Err(SavedPlaylistError::NotOwner(playlist.owner));
+        }
+        f(playlist);
+        let len = playlist.items.len();
+
+        self.persist(guild, playlists)?;
+        Ok(len)
+    }
+
+    pub fn delete(&mut self, guild: GuildId, name: &str, user_id: u64, is_manager: bool) -> Result<SavedPlaylist, SavedPlaylistError> {
+        let key = name.trim().to_lowercase();
+        let mut playlists = self.guild_playlists(guild).clone();
+
+        let index = playlists.iter().position(|playlist| playlist.name.to_lowercase() == key)
+            .ok_or_else(|| SavedPlaylistError::NotFound(name.trim().to_string()))?;
+        if !playlists[index].can_edit(user_id, is_manager) {
+            return Err(SavedPlaylistError::NotOwner(playlists[index].owner));
+        }
+
+        let playlist = playlists.remove(index);
+        self.persist(guild, playlists)?;
+        Ok(playlist)
+    }
+}
+
+impl Default for SavedPlaylistStore {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_are_trimmed_and_limited() {
+        assert_eq!(validate_name("  chill ").unwrap(), "chill");
+        assert!(matches!(validate_name("   "), Err(SavedPlaylistError::InvalidName)));
+        assert!(matches!(validate_name(&"a".repeat(MAX_NAME_LENGTH + 1)), Err(SavedPlaylistError::InvalidName)));
+    }
+
+    #[test]
+    fn only_owner_or_manager_can_edit() {
+        let playlist = SavedPlaylist { name: "chill".to_string(), owner: 1, items: Vec::new() };
+        assert!(playlist.can_edit(1, false));
+        assert!(playlist.can_edit(2, true));
+        assert!(!playlist.can_edit(2, false));
+    }
+}
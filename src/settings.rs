@@ -0,0 +1,216 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+
+use poise::serenity_prelude::GuildId;
+
+use crate::helpers;
+use crate::layout::{layout, DataLayout};
+use crate::pot::{PlaylistItem, Rejection};
+
+
+/// Longest crossfade in seconds
+pub const MAX_CROSSFADE: u32 = 12;
+
+/// Per guild configuration, stored as settings/{guild_id}.json in the data directory
+/// Missing fields take their default value so older files keep loading
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct GuildSettings {
+    /// Members with this role have full control of the player, when unset every listener does
+    pub dj_role: Option<u64>,
+    /// Skips from members that cannot skip count as votes
+    pub vote_skip: bool,
+    /// Share of the listeners that must vote to skip a track
+    pub vote_skip_ratio: f32,
+    /// Take items from each requester in turn instead of in the order they were added
+    pub fair_queue: bool,
+    /// Maximum number of items a member can have queued
+    pub max_user_items: Option<usize>,
+    /// Maximum seconds of items a member can have queued
+    pub max_user_duration: Option<u32>,
+    /// Rules every item must pass before it is queued
+    pub admission: AdmissionPolicy,
+    /// Seconds the next track fades in while the current one fades out, 0 disables crossfade
+    pub crossfade: u32,
+    /// Seconds a live stream plays before the queue moves on, None lets it play until it is skipped
+    pub max_live_duration: Option<u32>,
+    /// Keep playing related songs when the queue runs out
+    pub autoplay: bool,
+}
+
+impl Default for GuildSettings {
+    fn default() -> Self {
+        Self {
+            dj_role: None,
+            vote_skip: false,
+            vote_skip_ratio: 0.5,
+            fair_queue: false,
+            max_user_items: None,
+            max_user_duration: None,
+            admission: AdmissionPolicy::default(),
+            crossfade: 0,
+            max_live_duration: None,
+            autoplay: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AdmissionPolicy {
+    /// Maximum seconds of a single item, items with unknown duration are allowed
+    pub max_duration: Option<u32>,
+    /// yt-dlp extractors items can come from, empty allows every extractor
+    pub allowed_extractors: Vec<String>,
+    pub allow_live: bool,
+    /// Items with any of these words in the title are rejected, case insensitive
+    pub blocked_keywords: Vec<String>,
+    /// Rejected items, as the item id or extractor:id
+    pub blocked_tracks: Vec<String>,
+}
+
+impl Default for AdmissionPolicy {
+    fn default() -> Self {
+        Self {
+            max_duration: None,
+            allowed_extractors: Vec::new(),
+            allow_live: true,
+            blocked_keywords: Vec::new(),
+            blocked_tracks: Vec::new(),
+        }
+    }
+}
+
+impl AdmissionPolicy {
+    /// Check the item against every rule of the policy
+    pub fn check(&self, item: &PlaylistItem) -> Result<(), Rejection> {
+        let extractor = item.extractor.to_lowercase();
+
+        let key = item.key();
+        let blocked_track = self.blocked_tracks.iter().any(|blocked| *blocked == item.id || *blocked == key);
+        if blocked_track {
+            return Err(Rejection::BlockedTrack);
+        }
+
+        if !self.allowed_extractors.is_empty() && !self.allowed_extractors.iter().any(|allowed| allowed.to_lowercase() == extractor) {
+            return Err(Rejection::ExtractorNotAllowed(item.extractor.to_owned()));
+        }
+
+        if !self.allow_live && item.is_live() {
+            return Err(Rejection::LiveNotAllowed);
+        }
+
+        if let (Some(max_duration), Some(duration)) = (self.max_duration, item.duration) {
+            if duration > max_duration as f32 {
+                return Err(Rejection::TooLong(max_duration));
+            }
+        }
+
+        let title = item.title.to_lowercase();
+        if let Some(keyword) = self.blocked_keywords.iter().find(|keyword| title.contains(&keyword.to_lowercase())) {
+            return Err(Rejection::BlockedKeyword(keyword.to_owned()));
+        }
+
+        Ok(())
+    }
+}
+
+pub struct GuildSettingsStore {
+    guilds: HashMap<u64, GuildSettings>
+}
+
+impl GuildSettingsStore {
+    /// Load the settings of every guild from disk
+    pub fn load() -> Self {
+        let mut guilds = HashMap::new();
+
+        if let Ok(entries) = fs::read_dir(layout().settings()) {
+            for entry in entries.flatten() {
+                let path = entry.path();
+                let guild_id = match path.file_stem().and_then(|stem| stem.to_str()).and_then(|stem| stem.parse::<u64>().ok()) {
+                    Some(guild_id) => guild_id,
+                    None => continue,
+                };
+
+                match fs::read_to_string(&path).map(|json| serde_json::from_str::<GuildSettings>(&json)) {
+                    Ok(Ok(settings)) => { guilds.insert(guild_id, settings); },
+                    Ok(Err(err)) => tracing::error!(path = %path.display(), "Cannot parse the settings: {}", err),
+                    Err(err) => tracing::error!(path = %path.display(), "Cannot read the settings: {}", err),
+                }
+            }
+        }
+
+        Self {
+            guilds
+        }
+    }
+
+    /// Get the settings of the guild, guilds without settings get the defaults
+    pub fn get(&self, guild: GuildId) -> GuildSettings {
+        self.guilds.get(guild.as_u64()).cloned().unwrap_or_default()
+    }
+
+    /// Change the settings of the guild and save them to disk
+    pub fn update<F>(&mut self, guild: GuildId, f: F) -> std::io::Result<GuildSettings>
+    where
+        F: FnOnce(&mut GuildSettings),
+    {
+        // Changed on a copy, a failed write leaves the settings as they were
+        let mut settings = self.get(guild);
+        f(&mut settings);
+
+        let json = serde_json::to_string_pretty(&settings)?;
+        helpers::write_json(DataLayout::guild_file(&layout().settings(), guild.0), json)?;
+
+        self.guilds.insert(guild.0, settings.clone());
+        Ok(settings)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn item(title: &str, extractor: &str, duration: Option<f32>, is_live: Option<bool>) -> PlaylistItem {
+        PlaylistItem { duration, is_live, ..PlaylistItem::for_test(extractor, "abc", title) }
+    }
+
+    #[test]
+    fn default_policy_allows_everything() {
+        let policy = AdmissionPolicy::default();
+        assert_eq!(policy.check(&item("song", "soundcloud", Some(36000.0), Some(true))), Ok(()));
+    }
+
+    #[test]
+    fn admission_rules() {
+        let policy = AdmissionPolicy {
+            max_duration: Some(600),
+            allowed_extractors: vec!["youtube".to_string()],
+            allow_live: false,
+            blocked_keywords: vec!["Earrape".to_string()],
+            blocked_tracks: vec!["youtube:abc".to_string()],
+        };
+
+        assert_eq!(policy.check(&item("song", "Youtube", Some(200.0), None)), Err(Rejection::BlockedTrack));
+
+        let policy = AdmissionPolicy { blocked_tracks: Vec::new(), ..policy };
+        assert_eq!(policy.check(&item("song", "Youtube", Some(200.0), None)), Ok(()));
+        assert_eq!(policy.check(&item("song", "youtube", None, None)), Ok(()));
+        assert_eq!(policy.check(&item("song", "youtube", Some(601.0), None)), Err(Rejection::TooLong(600)));
+        assert_eq!(policy.check(&item("song", "soundcloud", None, None)), Err(Rejection::ExtractorNotAllowed("soundcloud".to_string())));
+        assert_eq!(policy.check(&item("song", "youtube", None, Some(true))), Err(Rejection::LiveNotAllowed));
+        assert_eq!(policy.check(&item("song EARRAPE", "youtube", None, None)), Err(Rejection::BlockedKeyword("Earrape".to_string())));
+    }
+
+    #[test]
+    fn blocks_other_extractors_by_key() {
+        let policy = AdmissionPolicy {
+            blocked_tracks: vec!["soundcloud:abc".to_string()],
+            ..AdmissionPolicy::default()
+        };
+
+        assert_eq!(policy.check(&item("song", "Soundcloud", None, None)), Err(Rejection::BlockedTrack));
+        assert_eq!(policy.check(&item("song", "bandcamp", None, None)), Ok(()));
+    }
+}
@@ -0,0 +1,265 @@
+use std::fs;
+use std::io;
+use std::process::{Child, ExitStatus};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use poise::serenity_prelude::{GuildId, Http, ShardManager};
+use serde::{Deserialize, Serialize};
+use tokio::signal::unix::{signal, SignalKind};
+
+use crate::helpers;
+use crate::layout::{layout, DataLayout};
+use crate::now_playing;
+use crate::player::PlayerEvent;
+use crate::pot::{PlaylistItem, SystemPlaylist};
+use crate::Data;
+
+/// Time the sessions get to save and leave before the remaining work is abandoned
+const SESSIONS_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The process exits after this long even if something is stuck
+const EXIT_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// How often a tracked child is checked while waiting for it
+const WAIT_INTERVAL: Duration = Duration::from_millis(100);
+
+const RESTART_NOTICE: &str = "The bot is restarting, the queue was saved and continues with the next song queued";
+
+static SHUTTING_DOWN: AtomicBool = AtomicBool::new(false);
+
+/// Processes that run outside of a voice session, like downloads and loudness measurements
+static CHILDREN: Mutex<Vec<(u64, Arc<Mutex<Child>>)>> = Mutex::new(Vec::new());
+static NEXT_CHILD: AtomicU64 = AtomicU64::new(0);
+
+/// Queue of a guild when the bot was stopped, stored as queues/{guild_id}.json in the data directory
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SavedQueue {
+    pub current: Option<PlaylistItem>,
+    /// Seconds of the current item that were played
+    pub position: f32,
+    pub items: Vec<PlaylistItem>,
+}
+
+impl SavedQueue {
+    /// Put the queue back in front of the guild playlist, the current item continues where it stopped
+    pub fn restore(self, playlist: &mut SystemPlaylist, guild_id: GuildId) {
+        for item in self.items.into_iter().rev() {
+            playlist.push_front(guild_id, item);
+        }
+
+        if let Some(current) = self.current {
+            if !current.is_live() && self.position > 0.0 {
+                playlist.player(guild_id).resume_at = Some((current.key(), self.position));
+            }
+            playlist.push_front(guild_id, current);
+        }
+    }
+}
+
+/// Load the queues saved at the last shutdown, they play once a song is queued in the guild again
+///
+/// The files are removed once loaded, the next shutdown saves the queues again
+pub fn restore_queues(playlist: &mut SystemPlaylist) {
+    let entries = match fs::read_dir(layout().queues()) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+
+    for entry in entries.flatten() {
+        let path = entry.path();
+        let guild_id = match path.file_stem().and_then(|stem| stem.to_str()).and_then(|stem| stem.parse::<u64>().ok()) {
+            Some(guild_id) => GuildId(guild_id),
+            None => continue,
+        };
+
+        match fs::read_to_string(&path).map(|json| serde_json::from_str::<SavedQueue>(&json)) {
+            Ok(Ok(queue)) => {
+                queue.restore(playlist, guild_id);
+                tracing::info!(guild_id = guild_id.0, songs = playlist.len(guild_id), "Queue restored");
+                if let Err(err) = fs::remove_file(&path) {
+                    tracing::warn!(path = %path.display(), "Cannot remove the restored queue: {}", err);
+                }
+            },
+            Ok(Err(err)) => tracing::error!(path = %path.display(), "Cannot parse the saved queue: {}", err),
+            Err(err) => tracing::error!(path = %path.display(), "Cannot read the saved queue: {}", err),
+        }
+    }
+}
+
+pub fn is_shutting_down() -> bool {
+    SHUTTING_DOWN.load(Ordering::SeqCst)
+}
+
+/// A child process that is killed when the bot shuts down before it finishes
+pub struct TrackedChild {
+    id: u64,
+    child: Arc<Mutex<Child>>,
+}
+
+/// Kill the child on shutdown, children started while shutting down are killed right away
+pub fn track(child: Child) -> TrackedChild {
+    let id = NEXT_CHILD.fetch_add(1, Ordering::Relaxed);
+    let child = Arc::new(Mutex::new(child));
+    CHILDREN.lock().unwrap_or_else(|err| err.into_inner()).push((id, child.clone()));
+
+    // Checked after registering, so a shutdown starting in between still sees it
+    if is_shutting_down() {
+        let _ = child.lock().unwrap_or_else(|err| err.into_inner()).kill();
+    }
+
+    TrackedChild { id, child }
+}
+
+impl TrackedChild {
+    /// Wait for the child to exit without holding it, so the shutdown can kill it meanwhile
+    pub fn wait(&self) -> io::Result<ExitStatus> {
+        loop {
+            if let Some(status) = self.child.lock().unwrap_or_else(|err| err.into_inner()).try_wait()? {
+                return Ok(status);
+            }
+            thread::sleep(WAIT_INTERVAL);
+        }
+    }
+}
+
+impl Drop for TrackedChild {
+    fn drop(&mut self) {
+        CHILDREN.lock().unwrap_or_else(|err| err.into_inner()).retain(|(id, _)| *id != self.id);
+    }
+}
+
+fn kill_children() {
+    for (_, child) in CHILDREN.lock().unwrap_or_else(|err| err.into_inner()).iter() {
+        let _ = child.lock().unwrap_or_else(|err| err.into_inner()).kill();
+    }
+}
+
+/// Reply to commands received while shutting down instead of running them
+pub async fn command_check(ctx: crate::Context<'_>) -> Result<bool, crate::Error> {
+    if !is_shutting_down() {
+        return Ok(true);
+    }
+
+    ctx.send(|r| r.content("The bot is restarting, try again in a moment").ephemeral(true)).await?;
+    Ok(false)
+}
+
+async fn terminated() -> io::Result<()> {
+    let mut terminate = signal(SignalKind::terminate())?;
+
+    tokio::select! {
+        _ = terminate.recv() => tracing::info!("Received SIGTERM"),
+        result = tokio::signal::ctrl_c() => {
+            result?;
+            tracing::info!("Received SIGINT");
+        },
+    }
+    Ok(())
+}
+
+/// Save the queue and the position of the current item of the guild
+async fn save_queue(playlist: &mut SystemPlaylist, guild_id: GuildId) -> io::Result<()> {
+    let player = playlist.player(guild_id);
+    let played = match player.track.clone() {
+        Some(track) => track.get_info().await.map(|info| info.position.as_secs_f32()).unwrap_or(0.0),
+        None => 0.0,
+    };
+
+    let mut items = playlist.items(guild_id).to_vec();
+    let player = playlist.player(guild_id);
+    let (current, position) = match (player.current.clone(), player.resume_at.clone()) {
+        (Some(current), _) => (Some(current), player.source_position(played)),
+        // A restored queue that did not play yet keeps the position it was saved with
+        (None, Some((key, position))) if items.first().is_some_and(|item| item.key() == key) => (Some(items.remove(0)), position),
+        _ => (None, 0.0),
+    };
+    let queue = SavedQueue { current, position, items };
+
+    let json = serde_json::to_string_pretty(&queue)?;
+    helpers::write_json(DataLayout::guild_file(&layout().queues(), guild_id.0), json)
+}
+
+/// Save every active session, tell its channel and leave the voice channel
+async fn stop_sessions(data: &Data, http: &Http) {
+    let mut playlist = data.system_playlist.write().await;
+
+    for guild_id in playlist.guilds() {
+        let active = playlist.state(guild_id).is_active() || data.songbird.get(guild_id).is_some();
+        // Queues restored at startup are saved again even if they did not play
+        if !active && playlist.len(guild_id) == 0 {
+            continue;
+        }
+
+        match save_queue(&mut playlist, guild_id).await {
+            Ok(_) => tracing::info!(guild_id = guild_id.0, "Queue saved"),
+            Err(err) => tracing::error!(guild_id = guild_id.0, "Cannot save the queue: {}", err),
+        }
+
+        if !active {
+            continue;
+        }
+
+        let text_channel = playlist.player(guild_id).text_channel;
+        if !now_playing::finish(http, &mut playlist, guild_id, RESTART_NOTICE).await {
+            if let Some(channel_id) = text_channel {
+                let _ = channel_id.say(http, RESTART_NOTICE).await;
+            }
+        }
+        playlist.transition(guild_id, PlayerEvent::Stop);
+
+        // Dropping the call stops its tracks, which kills their yt-dlp and ffmpeg
+        if let Err(err) = data.songbird.remove(guild_id).await {
+            tracing::warn!(guild_id = guild_id.0, "Cannot leave the voice channel: {:?}", err);
+        }
+    }
+}
+
+/// Wait for SIGTERM or SIGINT, then stop taking commands, save and leave every session and disconnect
+pub async fn on_signal(data: Data, http: Arc<Http>, shard_manager: Arc<tokio::sync::Mutex<ShardManager>>) {
+    if let Err(err) = terminated().await {
+        tracing::error!("Cannot listen for signals, the bot will not shut down gracefully: {}", err);
+        return;
+    }
+
+    SHUTTING_DOWN.store(true, Ordering::SeqCst);
+    tracing::info!("Shutting down");
+
+    // Bound the whole shutdown, a stuck Discord request or lock must not keep the process alive
+    thread::spawn(|| {
+        thread::sleep(EXIT_TIMEOUT);
+        tracing::error!("Shutdown timed out");
+        std::process::exit(1);
+    });
+
+    if tokio::time::timeout(SESSIONS_TIMEOUT, stop_sessions(&data, &http)).await.is_err() {
+        tracing::warn!("Timed out stopping the sessions");
+    }
+    kill_children();
+
+    shard_manager.lock().await.shutdown_all().await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn item(id: &str) -> PlaylistItem {
+        PlaylistItem::for_test("youtube", id, id)
+    }
+
+    #[test]
+    fn restored_queue_resumes_the_current_item() {
+        let mut playlist = SystemPlaylist::new();
+        let guild_id = GuildId(1);
+        playlist.push_back(guild_id, item("queued"));
+
+        SavedQueue { current: Some(item("current")), position: 42.0, items: vec![item("next")] }.restore(&mut playlist, guild_id);
+
+        let ids: Vec<&str> = playlist.items(guild_id).iter().map(|item| item.id.as_str()).collect();
+        assert_eq!(ids, ["current", "next", "queued"]);
+        assert_eq!(playlist.player(guild_id).resume_at, Some(("youtube:current".to_string(), 42.0)));
+    }
+}
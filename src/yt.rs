@@ -1,5 +1,34 @@
 use serde::Deserialize;
 use async_recursion::async_recursion;
+use std::collections::HashMap;
+
+use crate::metrics;
+
+/// Quota units a request costs, searches are much more expensive than the other endpoints
+fn quota_cost (endpoint: &str) -> u64 {
+    match endpoint {
+        "search" => 100,
+        _ => 1,
+    }
+}
+
+/// Request the YouTube Data API and record it in the metrics
+async fn get (endpoint: &'static str, url: String) -> reqwest::Result<reqwest::Response> {
+    metrics::YOUTUBE_API_CALLS.with_label_values(&[endpoint]).inc();
+    metrics::YOUTUBE_API_QUOTA.with_label_values(&[endpoint]).inc_by(quota_cost(endpoint));
+
+    let timer = metrics::YOUTUBE_API_DURATION.start_timer();
+    let result = reqwest::get(url).await;
+    timer.observe_duration();
+
+    match &result {
+        Ok(response) if !response.status().is_success() => metrics::YOUTUBE_API_ERRORS.with_label_values(&[endpoint]).inc(),
+        Err(_) => metrics::YOUTUBE_API_ERRORS.with_label_values(&[endpoint]).inc(),
+        _ => (),
+    }
+
+    result
+}
 
 pub struct YoutubeAPI {
     key: String
@@ -14,7 +43,7 @@ impl YoutubeAPI {
 
     pub async fn video (&self, id: &str) -> YoutubeResult {
         let search_url = format!("https://www.googleapis.com/youtube/v3/videos?key={}&part=snippet&maxResults=1&id={}", &self.key, id);
-        let result = reqwest::get(search_url).await;
+        let result = get("videos", search_url).await;
 
         match result {
             Ok(response) => {
@@ -51,7 +80,7 @@ impl YoutubeAPI {
 
     pub async fn _search (&self, query: &str) -> YoutubeResult {
         let search_url = format!("https://www.googleapis.com/youtube/v3/search?key={}&part=snippet&maxResults=1&type=video&q={}", &self.key, query);
-        let result = reqwest::get(search_url).await;
+        let result = get("search", search_url).await;
 
         match result {
             Ok(response) => {
@@ -80,7 +109,7 @@ impl YoutubeAPI {
 
     pub async fn playlist (&self, playlist: &str) -> YoutubeResult {
         let search_url = format!("https://www.googleapis.com/youtube/v3/playlistItems?key={}&part=snippet&maxResults=50&playlistId={}", &self.key, playlist);
-        let result = reqwest::get(search_url).await;
+        let result = get("playlistItems", search_url).await;
 
         match result {
             Ok(response) => {
@@ -114,6 +143,34 @@ impl YoutubeAPI {
         }
     }
 
+    /// Get duration and live status of videos, ids are requested in pages of 50
+    pub async fn video_details (&self, ids: &[String]) -> HashMap<String, YoutubeVideoDetails> {
+        let mut details = HashMap::new();
+
+        for page in ids.chunks(50) {
+            let search_url = format!("https://www.googleapis.com/youtube/v3/videos?key={}&part=snippet,contentDetails&maxResults=50&id={}", &self.key, page.join(","));
+
+            let text = match get("videos", search_url).await {
+                Ok(response) => match response.text().await {
+                    Ok(text) => text,
+                    Err(_) => continue,
+                },
+                Err(_) => continue,
+            };
+
+            if let Ok(result) = serde_json::from_str::<YoutubeVideosResponse>(&text) {
+                for item in result.items {
+                    details.insert(item.id, YoutubeVideoDetails {
+                        duration: item.contentDetails.duration.as_deref().and_then(parse_iso8601_duration),
+                        is_live: item.snippet.liveBroadcastContent.as_deref() == Some("live"),
+                    });
+                }
+            }
+        }
+
+        details
+    }
+
     #[async_recursion]
     async fn playlist_get_items (&self, playlist: &str, page_token: Option<&'async_recursion str>) -> Vec<YoutubePlaylistItemsResult> {
         let search_url = if let Some(page_token_str) = &page_token {
@@ -122,7 +179,7 @@ impl YoutubeAPI {
             format!("https://www.googleapis.com/youtube/v3/playlistItems?key={}&part=snippet&maxResults=50&playlistId={}", &self.key, playlist)
         };
 
-        let result = reqwest::get(search_url).await;
+        let result = get("playlistItems", search_url).await;
 
         match result {
             Ok(response) => {
@@ -157,6 +214,42 @@ impl YoutubeAPI {
     }
 }
 
+/// Parse durations like PT1H2M3S, the format used by the YouTube API, into seconds
+pub fn parse_iso8601_duration (duration: &str) -> Option<f32> {
+    // Videos longer than a day have a day part, like P1DT2H
+    let duration = duration.strip_prefix('P')?;
+    let (days, time) = duration.split_once('T').unwrap_or((duration, ""));
+    let mut seconds = match days {
+        "" => 0.0,
+        days => days.strip_suffix('D')?.parse::<f32>().ok()? * 86400.0,
+    };
+    let mut number = String::new();
+
+    for c in time.chars() {
+        if c.is_ascii_digit() || c == '.' {
+            number.push(c);
+            continue;
+        }
+
+        let value: f32 = number.parse().ok()?;
+        number.clear();
+
+        seconds += match c {
+            'H' => value * 3600.0,
+            'M' => value * 60.0,
+            'S' => value,
+            _ => return None,
+        };
+    }
+
+    if number.is_empty() {
+        Some(seconds)
+    } else {
+        None
+    }
+}
+
+#[allow(dead_code)]
 #[derive(Debug)]
 pub enum YoutubeResult {
     Ok(YoutubePlaylistItemsResponse),
@@ -182,6 +275,7 @@ pub struct YoutubeError {
 
 // Search
 
+#[allow(dead_code)]
 #[derive(Deserialize, Debug)]
 pub struct YoutubeSearchResponse {
     pub kind: String,
@@ -208,6 +302,7 @@ impl YoutubeSearchResponse {
     }
 }
 
+#[allow(dead_code)]
 #[derive(Deserialize, Debug)]
 pub struct YoutubeSearchResult {
     pub kind: String,
@@ -215,7 +310,7 @@ pub struct YoutubeSearchResult {
     pub snippet: YoutubeItemSnippet
 }
 
-#[allow(non_snake_case)]
+#[allow(non_snake_case, dead_code)]
 #[derive(Deserialize, Debug)]
 pub struct YoutubeItemID {
     pub kind: String,
@@ -231,7 +326,7 @@ pub struct YoutubeItemSnippet {
 
 // Playlist items
 
-#[allow(non_snake_case)]
+#[allow(non_snake_case, dead_code)]
 #[derive(Deserialize, Debug)]
 pub struct YoutubePlaylistItemsResponse {
     pub kind: String,
@@ -244,4 +339,53 @@ pub struct YoutubePlaylistItemsResult {
     pub kind: String,
     pub id: String,
     pub snippet: YoutubeItemSnippet
+}
+
+// Videos
+
+#[derive(Debug, Clone, Copy)]
+pub struct YoutubeVideoDetails {
+    pub duration: Option<f32>,
+    pub is_live: bool
+}
+
+#[derive(Deserialize, Debug)]
+struct YoutubeVideosResponse {
+    items: Vec<YoutubeVideosResult>
+}
+
+#[allow(non_snake_case)]
+#[derive(Deserialize, Debug)]
+struct YoutubeVideosResult {
+    id: String,
+    snippet: YoutubeVideoSnippet,
+    contentDetails: YoutubeContentDetails
+}
+
+#[allow(non_snake_case)]
+#[derive(Deserialize, Debug)]
+struct YoutubeVideoSnippet {
+    liveBroadcastContent: Option<String>
+}
+
+#[derive(Deserialize, Debug)]
+struct YoutubeContentDetails {
+    duration: Option<String>
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_youtube_durations() {
+        assert_eq!(parse_iso8601_duration("PT4M13S"), Some(253.0));
+        assert_eq!(parse_iso8601_duration("PT1H2M3S"), Some(3723.0));
+        assert_eq!(parse_iso8601_duration("PT45S"), Some(45.0));
+        assert_eq!(parse_iso8601_duration("PT0S"), Some(0.0));
+        assert_eq!(parse_iso8601_duration("P1D"), Some(86400.0));
+        assert_eq!(parse_iso8601_duration("P1DT2H3S"), Some(93603.0));
+        assert_eq!(parse_iso8601_duration("P1W"), None);
+        assert_eq!(parse_iso8601_duration("PT4"), None);
+    }
 }
\ No newline at end of file